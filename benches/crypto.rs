@@ -0,0 +1,31 @@
+#[macro_use]
+extern crate criterion;
+extern crate whatsappweb;
+
+use criterion::{Criterion, Benchmark, Throughput};
+
+use whatsappweb::crypto;
+
+const ENC_KEY: [u8; 32] = [7; 32];
+const MAC_KEY: [u8; 32] = [42; 32];
+
+fn sign_and_encrypt(c: &mut Criterion) {
+    for &size in &[64usize, 4 * 1024, 256 * 1024] {
+        let message = vec![0x5Au8; size];
+        c.bench("sign_and_encrypt_message", Benchmark::new(size.to_string(), move |b| {
+            b.iter(|| crypto::sign_and_encrypt_message(&ENC_KEY, &MAC_KEY, &message))
+        }).throughput(Throughput::Bytes(size as u32)));
+    }
+}
+
+fn verify_and_decrypt(c: &mut Criterion) {
+    for &size in &[64usize, 4 * 1024, 256 * 1024] {
+        let message_encrypted = crypto::sign_and_encrypt_message(&ENC_KEY, &MAC_KEY, &vec![0x5Au8; size]);
+        c.bench("verify_and_decrypt_message", Benchmark::new(size.to_string(), move |b| {
+            b.iter(|| crypto::verify_and_decrypt_message(&ENC_KEY, &MAC_KEY, &message_encrypted).unwrap())
+        }).throughput(Throughput::Bytes(size as u32)));
+    }
+}
+
+criterion_group!(benches, sign_and_encrypt, verify_and_decrypt);
+criterion_main!(benches);
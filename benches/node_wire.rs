@@ -0,0 +1,64 @@
+#[macro_use]
+extern crate criterion;
+extern crate whatsappweb;
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use criterion::Criterion;
+
+use whatsappweb::Jid;
+use whatsappweb::node_wire::{Node, NodeContent, IntoCow};
+
+/// Shaped like the contacts dump sent after login
+fn contacts_node(count: u64) -> Node<'static> {
+    let users = (0..count).map(|i| {
+        let mut attributes = HashMap::new();
+        attributes.insert("jid".cow(), NodeContent::Jid(Jid::from_str(&format!("49{:010}@c.us", i)).unwrap()));
+        attributes.insert("name".cow(), NodeContent::String(format!("Contact {}", i).cow()));
+        attributes.insert("notify".cow(), NodeContent::String(format!("Notify {}", i).cow()));
+        attributes.insert("short".cow(), NodeContent::String("Contact".cow()));
+        Node::new("user", attributes, NodeContent::None)
+    }).collect();
+
+    let mut attributes = HashMap::new();
+    attributes.insert("type".cow(), NodeContent::Token("contacts"));
+    attributes.insert("duplicate".cow(), NodeContent::Token("false"));
+    Node::new("response", attributes, NodeContent::List(users))
+}
+
+/// Shaped like the chats dump sent after login
+fn chats_node(count: u64) -> Node<'static> {
+    let chats = (0..count).map(|i| {
+        let mut attributes = HashMap::new();
+        attributes.insert("jid".cow(), NodeContent::Jid(Jid::from_str(&format!("49{:010}-{}@g.us", i, 1_500_000_000 + i)).unwrap()));
+        attributes.insert("name".cow(), NodeContent::String(format!("Group {}", i).cow()));
+        attributes.insert("t".cow(), NodeContent::Nibble((1_500_000_000 + i).to_string().cow()));
+        attributes.insert("count".cow(), NodeContent::Nibble((i % 20).to_string().cow()));
+        attributes.insert("spam".cow(), NodeContent::Token("false"));
+        Node::new("chat", attributes, NodeContent::None)
+    }).collect();
+
+    let mut attributes = HashMap::new();
+    attributes.insert("type".cow(), NodeContent::Token("chat"));
+    Node::new("response", attributes, NodeContent::List(chats))
+}
+
+fn serialize(c: &mut Criterion) {
+    let contacts = contacts_node(500);
+    c.bench_function("serialize contacts", move |b| b.iter(|| contacts.clone().serialize()));
+
+    let chats = chats_node(200);
+    c.bench_function("serialize chats", move |b| b.iter(|| chats.clone().serialize()));
+}
+
+fn deserialize(c: &mut Criterion) {
+    let contacts = contacts_node(500).serialize();
+    c.bench_function("deserialize contacts", move |b| b.iter(|| Node::deserialize(&contacts).unwrap()));
+
+    let chats = chats_node(200).serialize();
+    c.bench_function("deserialize chats", move |b| b.iter(|| Node::deserialize(&chats).unwrap()));
+}
+
+criterion_group!(benches, serialize, deserialize);
+criterion_main!(benches);
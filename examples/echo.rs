@@ -3,12 +3,12 @@ extern crate simple_logger;
 extern crate log;
 extern crate qrcode;
 extern crate image;
-extern crate bincode;
 extern crate whatsappweb;
 extern crate reqwest;
 extern crate base64;
 
-use std::fs::{File, OpenOptions, remove_file};
+use std::fs::remove_file;
+use std::env;
 use std::io::{Read, Write, Cursor};
 use std::sync::{RwLock, Arc};
 use std::str::FromStr;
@@ -18,13 +18,17 @@ use image::Luma;
 
 use whatsappweb::connection::*;
 use whatsappweb::{Jid, Contact, PresenceStatus, GroupParticipantsChange, ChatAction, MediaType};
-use whatsappweb::message::{MessageAck, MessageAckSide, MessageAckLevel, Direction, Peer, ChatMessageContent, ChatMessage};
+use whatsappweb::message::{MessageAck, MessageAckSide, MessageAckLevel, Direction, ChatMessageContent, ChatMessage};
 use whatsappweb::crypto;
 use whatsappweb::media;
 
 
 const SESSION_FILENAME: &str = "session.bin";
 
+fn session_passphrase() -> String {
+    env::var("SESSION_PASSPHRASE").expect("SESSION_PASSPHRASE has to be set to encrypt the stored session")
+}
+
 struct Handler {}
 
 impl WhatsappWebHandler for Handler {
@@ -33,7 +37,7 @@ impl WhatsappWebHandler for Handler {
     }
 
     fn on_persistent_session_data_changed(&self, persistent_session: PersistentSession) {
-        bincode::serialize_into(OpenOptions::new().create(true).write(true).open(SESSION_FILENAME).unwrap(), &persistent_session).unwrap();
+        persistent_session.save_encrypted(SESSION_FILENAME, &session_passphrase()).unwrap();
     }
     fn on_user_data_changed(&self, connection: &WhatsappWebConnection<Handler>, user_data: UserData) {
         info!("userdata changed: {:?}", user_data);
@@ -41,7 +45,7 @@ impl WhatsappWebHandler for Handler {
     fn on_disconnect(&self, reason: whatsappweb::connection::DisconnectReason) {
         info!("disconnected");
         match reason {
-            whatsappweb::connection::DisconnectReason::Removed => {
+            whatsappweb::connection::DisconnectReason::LoggedOutFromPhone => {
                 remove_file(SESSION_FILENAME).unwrap();
             }
             _ => {}
@@ -61,9 +65,8 @@ impl WhatsappWebHandler for Handler {
             _ => return
         };
 
-        match &peer {
-            &Peer::Individual(ref jid) => if jid != &accepted_jid { return; }
-            _ => return
+        if peer.chat_jid() != &accepted_jid {
+            return;
         }
 
         connection.send_message_read(message.id.clone(), peer.clone());
@@ -71,7 +74,7 @@ impl WhatsappWebHandler for Handler {
 
         match message.content {
             ChatMessageContent::Text(text) => {
-                connection.send_message(ChatMessageContent::Text(text), accepted_jid);
+                connection.send_message(ChatMessageContent::Text(text), peer.chat_jid().clone());
             }
             _ => {}
         }
@@ -81,8 +84,8 @@ impl WhatsappWebHandler for Handler {
 fn main() {
     let handler = Handler {};
 
-    if let Ok(file) = File::open(SESSION_FILENAME) {
-        let (_, join_handle) = whatsappweb::connection::with_persistent_session(bincode::deserialize_from(file).unwrap(), handler);
+    if let Ok(persistent_session) = PersistentSession::load_encrypted(SESSION_FILENAME, &session_passphrase()) {
+        let (_, join_handle) = whatsappweb::connection::with_persistent_session(persistent_session, handler);
         join_handle.join().unwrap();
     } else {
         let (_, join_handle) = whatsappweb::connection::new(|qr| { qr.render::<Luma<u8>>().module_dimensions(10, 10).build().save("login_qr.png").unwrap(); }, handler);
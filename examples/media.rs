@@ -3,12 +3,12 @@ extern crate simple_logger;
 extern crate log;
 extern crate qrcode;
 extern crate image;
-extern crate bincode;
 extern crate whatsappweb;
 extern crate reqwest;
 extern crate base64;
 
-use std::fs::{File, OpenOptions, remove_file};
+use std::fs::{File, remove_file};
+use std::env;
 use std::io::Read;
 use std::sync::Arc;
 
@@ -23,6 +23,10 @@ use whatsappweb::{Jid, MediaType};
 
 const SESSION_FILENAME: &str = "session.bin";
 
+fn session_passphrase() -> String {
+    env::var("SESSION_PASSPHRASE").expect("SESSION_PASSPHRASE has to be set to encrypt the stored session")
+}
+
 struct Handler {}
 
 impl WhatsappWebHandler for Handler {
@@ -39,20 +43,20 @@ impl WhatsappWebHandler for Handler {
             media::upload_file(&file, MediaType::Image, &connection, Box::new(move |file_info| {
                 let jid = Jid::from_phone_number("+49123456789".to_string()).unwrap();
 
-                connection0.send_message(ChatMessageContent::Image(file_info.unwrap(), size, thumbnail.to_vec()), jid);
+                connection0.send_message(ChatMessageContent::Image(file_info.unwrap(), size, thumbnail.to_vec(), None), jid);
             }));
         }
     }
 
     fn on_persistent_session_data_changed(&self, persistent_session: PersistentSession) {
-        bincode::serialize_into(OpenOptions::new().create(true).write(true).open(SESSION_FILENAME).unwrap(), &persistent_session).unwrap();
+        persistent_session.save_encrypted(SESSION_FILENAME, &session_passphrase()).unwrap();
     }
     fn on_user_data_changed(&self, _: &WhatsappWebConnection<Handler>, _: UserData) {}
     fn on_disconnect(&self, reason: DisconnectReason) {
         info!("disconnected");
 
         match reason {
-            DisconnectReason::Removed => {
+            DisconnectReason::LoggedOutFromPhone => {
                 remove_file(SESSION_FILENAME).unwrap();
             }
             _ => {}
@@ -65,8 +69,8 @@ fn main() {
     simple_logger::init_with_level(log::Level::Debug).unwrap();
     let handler = Handler {};
 
-    if let Ok(file) = File::open(SESSION_FILENAME) {
-        let (_, join_handle) = connection::with_persistent_session(bincode::deserialize_from(file).unwrap(), handler);
+    if let Ok(persistent_session) = PersistentSession::load_encrypted(SESSION_FILENAME, &session_passphrase()) {
+        let (_, join_handle) = connection::with_persistent_session(persistent_session, handler);
         join_handle.join().unwrap();
     } else {
         let (_, join_handle) = connection::new(|qr| { qr.render::<Luma<u8>>().module_dimensions(10, 10).build().save("login_qr.png").unwrap(); }, handler);
@@ -3,12 +3,11 @@ extern crate simple_logger;
 extern crate log;
 extern crate qrcode;
 extern crate image;
-extern crate bincode;
 extern crate whatsappweb;
 extern crate reqwest;
 extern crate base64;
 
-use std::fs::{File, OpenOptions};
+use std::env;
 
 use image::Luma;
 
@@ -20,6 +19,10 @@ use whatsappweb::Jid;
 
 const SESSION_FILENAME: &str = "session.bin";
 
+fn session_passphrase() -> String {
+    env::var("SESSION_PASSPHRASE").expect("SESSION_PASSPHRASE has to be set to encrypt the stored session")
+}
+
 struct Handler {
     subscribed_jid: Jid
 }
@@ -34,7 +37,7 @@ impl WhatsappWebHandler for Handler {
     }
 
     fn on_persistent_session_data_changed(&self, persistent_session: PersistentSession) {
-        bincode::serialize_into(OpenOptions::new().create(true).write(true).open(SESSION_FILENAME).unwrap(), &persistent_session).unwrap();
+        persistent_session.save_encrypted(SESSION_FILENAME, &session_passphrase()).unwrap();
     }
     fn on_user_data_changed(&self, _: &WhatsappWebConnection<Handler>, user_data: UserData) {
         if let UserData::PresenceChange(jid, status, _) = user_data {
@@ -54,8 +57,8 @@ fn main() {
     simple_logger::init_with_level(log::Level::Info).unwrap();
     let handler = Handler {subscribed_jid: Jid::from_phone_number("+49123456789".to_string()).unwrap()};
 
-    if let Ok(file) = File::open(SESSION_FILENAME) {
-        let (_, join_handle) = connection::with_persistent_session(bincode::deserialize_from(file).unwrap(), handler);
+    if let Ok(persistent_session) = PersistentSession::load_encrypted(SESSION_FILENAME, &session_passphrase()) {
+        let (_, join_handle) = connection::with_persistent_session(persistent_session, handler);
         join_handle.join().unwrap();
     } else {
         let (_, join_handle) = connection::new(|qr| { qr.render::<Luma<u8>>().module_dimensions(10, 10).build().save("login_qr.png").unwrap(); }, handler);
@@ -0,0 +1,10 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate whatsappweb;
+
+use whatsappweb::message::ChatMessage;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = ChatMessage::from_proto_binary(data);
+});
@@ -0,0 +1,10 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate whatsappweb;
+
+use whatsappweb::node_wire::Node;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Node::deserialize(data);
+});
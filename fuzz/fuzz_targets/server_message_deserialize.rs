@@ -0,0 +1,15 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate whatsappweb;
+extern crate json;
+
+use std::str;
+
+use whatsappweb::json_protocol::ServerMessage;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(json) = str::from_utf8(data).map_err(|_| ()).and_then(|text| json::parse(text).map_err(|_| ())) {
+        let _ = ServerMessage::deserialize(&json);
+    }
+});
@@ -0,0 +1,17 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate whatsappweb;
+extern crate ws;
+
+use std::str;
+
+use ws::Message;
+use whatsappweb::websocket_protocol::WebsocketMessage;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = WebsocketMessage::deserialize(&Message::Binary(data.to_vec()));
+    if let Ok(text) = str::from_utf8(data) {
+        let _ = WebsocketMessage::deserialize(&Message::Text(text.to_string()));
+    }
+});
@@ -0,0 +1,80 @@
+//! Every handler event as one serializable ```Event```, e.g. to forward the event stream of a connection
+//! to a bridge daemon over IPC
+use serde_json;
+
+use connection::{WhatsappWebConnection, WhatsappWebHandler, State, UserData, PersistentSession, DisconnectReason};
+use message::ChatMessage;
+use errors::*;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum Event {
+    StateChanged(State),
+    UserDataChanged(UserData),
+    /// The session isn't included as it contains the keys of the account, see ```EventHandler::with_session_callback```
+    PersistentSessionChanged,
+    Disconnected(DisconnectReason),
+    Message { new: bool, message: ChatMessage },
+    /// Description of an error which occurred in the background
+    Error(String),
+    /// Description of an error which occurred while logging in with a new session
+    LoginError(String),
+}
+
+impl Event {
+    /// Json representation like ```{"type": "Message", "data": {...}}```
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+/// Handler delivering every event to ```callback```
+pub struct EventHandler<F: Fn(Event) + Send + Sync + 'static> {
+    callback: F,
+    session_callback: Option<Box<Fn(PersistentSession) + Send + Sync>>
+}
+
+impl<F: Fn(Event) + Send + Sync + 'static> EventHandler<F> {
+    pub fn new(callback: F) -> EventHandler<F> {
+        EventHandler { callback, session_callback: None }
+    }
+
+    /// Hand the changed session to ```callback``` to be stored, the event stream only signals the change
+    pub fn with_session_callback(mut self, callback: Box<Fn(PersistentSession) + Send + Sync>) -> EventHandler<F> {
+        self.session_callback = Some(callback);
+        self
+    }
+}
+
+impl<F: Fn(Event) + Send + Sync + 'static> WhatsappWebHandler<EventHandler<F>> for EventHandler<F> {
+    fn on_state_changed(&self, _: &WhatsappWebConnection<EventHandler<F>>, state: State) {
+        (self.callback)(Event::StateChanged(state));
+    }
+
+    fn on_user_data_changed(&self, _: &WhatsappWebConnection<EventHandler<F>>, user_data: UserData) {
+        (self.callback)(Event::UserDataChanged(user_data));
+    }
+
+    fn on_persistent_session_data_changed(&self, persistent_session: PersistentSession) {
+        if let Some(ref session_callback) = self.session_callback {
+            session_callback(persistent_session);
+        }
+        (self.callback)(Event::PersistentSessionChanged);
+    }
+
+    fn on_disconnect(&self, reason: DisconnectReason) {
+        (self.callback)(Event::Disconnected(reason));
+    }
+
+    fn on_message(&self, _: &WhatsappWebConnection<EventHandler<F>>, message_new: bool, message: Box<ChatMessage>) {
+        (self.callback)(Event::Message { new: message_new, message: *message });
+    }
+
+    fn on_error(&self, _: &WhatsappWebConnection<EventHandler<F>>, error: Error) {
+        (self.callback)(Event::Error(error.to_string()));
+    }
+
+    fn on_login_error(&self, _: &WhatsappWebConnection<EventHandler<F>>, error: Error) {
+        (self.callback)(Event::LoginError(error.to_string()));
+    }
+}
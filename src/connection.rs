@@ -1,62 +1,136 @@
-use std::sync::Mutex;
-use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::thread;
 use std::thread::JoinHandle;
 use std::marker::Send;
 use std::sync::Arc;
 use std::clone::Clone;
 use std::ops::Deref;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
 
 use ws;
 use ws::{CloseCode, Handler, Request, Sender, Message};
 use ring::agreement;
 use ring::rand::{SystemRandom, SecureRandom};
 use url::Url;
-use qrcode::QrCode;
+#[cfg(feature = "qr")]
+use qrcode::{QrCode, EcLevel, Version};
 use base64;
+use bincode;
 use json::JsonValue;
 use ws::util::{Token, Timeout};
-use std::time::{SystemTime, Duration};
+use std::time::{Duration, Instant, SystemTime};
 use chrono::{NaiveDateTime, Utc};
+#[cfg(feature = "media")]
+use reqwest;
 
 use crypto;
-use message::{ChatMessage as WhatsappMessage, MessageAck, ChatMessageContent, Peer, Direction, MessageId};
+use message::{ChatMessage as WhatsappMessage, MessageAck, MessageAckSide, MessageAckLevel, PeerAck, ChatMessageContent, Peer, Direction, MessageId, SentMessage, ProtocolMessageKind, GroupInvite, MessageKey, MediaLimits};
 use timeout;
+#[cfg(feature = "media")]
+use media;
 use json_protocol;
 use json_protocol::ServerMessage;
-use websocket_protocol::{WebsocketMessage, WebsocketMessagePayload, WebsocketMessageMetric};
+use websocket_protocol;
+use websocket_protocol::{WebsocketMessage, WebsocketMessagePayload};
+pub use websocket_protocol::WebsocketMessageMetric;
 use node_protocol;
-use node_protocol::{AppMessage, MessageEventType, AppEvent, Query, GroupCommand};
+use node_protocol::{AppMessage, MessageEventType, AppEvent, Query, GroupCommand, BroadcastCommand, Roster};
 use node_wire::Node;
-use super::{Jid, PresenceStatus, Contact, Chat, GroupMetadata, GroupParticipantsChange, ChatAction, MediaType};
+use super::{Jid, PresenceStatus, Contact, Chat, Muted, GroupMetadata, GroupParticipantsChange, ChatAction, MediaType, UrlPreview, ProfilePicture, Identity, ParticipantChangeStatus, MediaConn};
+use worker_pool::{WorkerPool, PendingJobs};
 use errors::*;
 
 pub struct WhatsappWebConnection<H: WhatsappWebHandler + Send + Sync + 'static> {
     inner: Arc<Mutex<WhatsappWebConnectionInner<H>>>,
     //Todo
-    handler: Arc<H>
+    handler: Arc<H>,
+    media_workers: Arc<WorkerPool>,
+    /// Media transfers of this connection, the pool may be shared with other connections
+    media_jobs: Arc<PendingJobs>
 }
 
 impl<H: WhatsappWebHandler + Send + Sync + 'static> Clone for WhatsappWebConnection<H> {
     fn clone(&self) -> Self {
-        WhatsappWebConnection { handler: self.handler.clone(), inner: self.inner.clone() }
+        WhatsappWebConnection {
+            handler: self.handler.clone(),
+            inner: self.inner.clone(),
+            media_workers: self.media_workers.clone(),
+            media_jobs: self.media_jobs.clone()
+        }
     }
 }
 
+/// Number of worker threads used for media transfers unless set with ```set_media_workers```
+pub const DEFAULT_MEDIA_WORKERS: usize = 4;
+
 #[derive(Eq, PartialEq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub enum State {
     Uninitialized = 0,
     Connected = 1,
     Disconnecting = 2,
-    Reconnecting = 3
+    Reconnecting = 3,
+    /// A panic left the connection in a possibly inconsistent state, see ```WhatsappWebConnection::is_broken```
+    Broken = 4
+}
+
+/// Query answered by the server with a json object, see ```WhatsappWebConnection::query_json```
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonQuery {
+    ProfilePicThumb(Jid),
+    /// Full-size profile picture
+    ProfilePic(Jid),
+    Status(Jid),
+    GroupMetadata(Jid),
+    /// Whether the jid is registered, answered with status 200 if so
+    Exists(Jid),
+    /// Auth token and hosts for media transfers
+    MediaConn,
+    /// Query not modeled by this crate, e.g. ```["query", "Kind", ...]```
+    Other(JsonValue),
+}
+
+/// Information about the phone the session belongs to
+#[derive(Debug, Clone, Default)]
+pub struct PhoneInfo {
+    pub platform: Option<String>,
+    pub wa_version: Option<String>,
+    pub os_version: Option<String>,
+    pub device_manufacturer: Option<String>,
+    pub device_model: Option<String>
+}
+
+/// Snapshot of the connection
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub state: State,
+    pub user_jid: Option<Jid>,
+    pub push_name: Option<String>,
+    pub client_id: Option<[u8; 8]>,
+    /// Batterylevel last submitted by the app
+    pub battery: Option<u8>,
+    pub phone: PhoneInfo
 }
 
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub enum DisconnectReason {
+    /// Session was taken over by another client
     Replaced,
-    Removed
+    /// Session was removed from the phone and can't be restored anymore
+    LoggedOutFromPhone,
+    Banned,
+    Timeout,
+    Unknown(String)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub enum UserData {
     /// Contacts are initial send by the app
     ContactsInitial(Vec<Contact>),
@@ -66,17 +140,74 @@ pub enum UserData {
     ContactDelete(Jid),
     /// Chats are initial send by the app
     Chats(Vec<Chat>),
+    /// Part of the initial contacts if chunking is enabled with ```set_roster_chunk_size```,
+    /// ```last``` is set on the final chunk
+    ContactsChunk { contacts: Vec<Contact>, last: bool },
+    /// Part of the initial chats if chunking is enabled with ```set_roster_chunk_size```
+    ChatsChunk { chats: Vec<Chat>, last: bool },
     ChatAction(Jid, ChatAction),
+    /// Display name of the chat changed
+    ChatNameChange(Jid, String),
+    /// Notifications of the chat were muted until the given time, forever or unmuted on the phone.
+    /// ```tone``` is the notification tone of the chat if it was sent along.
+    /// Mute notifications are only delivered as this event, not as ```ChatAction```
+    ChatMuteChanged { chat: Jid, muted: Muted, tone: Option<String> },
+    /// Identity key of a contact differs from the one received by a previous ```get_identity```,
+    /// so the security code of the chat changed
+    IdentityChanged(Identity),
+    /// Message was starred or unstarred on the phone
+    MessageStarChanged { chat: Jid, id: MessageId, starred: bool },
+    /// Chat with all changes received since ```Chats``` applied
+    ChatUpdated(Chat),
+    /// Contacts, chats and the last messages of the chats were received after login,
+    /// so the roster can be trusted and messages can be sent
+    SyncComplete,
     /// Jid of the own user
     UserJid(Jid),
     PresenceChange(Jid, PresenceStatus, Option<NaiveDateTime>),
+    /// Result of ```subscribe_presence```, false if the subscription was denied e.g. due to the privacy settings of the contact
+    PresenceSubscription(Jid, bool),
+    /// Presence of a participant within a group e.g. typing
+    GroupPresenceChange { group: Jid, participant: Jid, status: PresenceStatus, time: Option<NaiveDateTime> },
     MessageAck(MessageAck),
+    /// Acknowledge of a message sent by this connection, together with the original message
+    SentMessageAck(MessageAck, SentMessage),
+    /// Recipient couldn't decrypt the message and requests it again
+    RetryRequested(MessageId),
+    /// Message wasn't acknowledged by the server after all resends enabled by ```set_ack_resend```
+    MessageSendFailed(SentMessage),
     GroupIntroduce { newly_created: bool, inducer: Jid, meta: GroupMetadata },
     GroupParticipantsChange { group: Jid, change: GroupParticipantsChange, inducer: Option<Jid>, participants: Vec<Jid> },
+    GroupSubjectChange { group: Jid, subject: String, subject_time: i64, subject_owner: Jid },
     /// Batterylevel which is submitted by the app
-    Battery(u8)
+    Battery(u8),
+    /// Phone became unreachable or reachable again, messages are only relayed if it is reachable
+    PhoneConnectivity(bool),
+    /// Message was deleted for everyone by ```by```
+    MessageRevoked { chat: Jid, id: MessageId, by: Jid },
+    /// Content of a message was replaced by its sender ```by```, edits of messages of other senders are dropped
+    MessageEdited { chat: Jid, id: MessageId, by: Jid, new_content: ChatMessageContent },
+    /// Disappearing messages of the chat were enabled with the given retention or disabled
+    EphemeralSettingChanged { chat: Jid, duration: Option<Duration> },
+    /// Json frame which isn't handled otherwise, e.g. ```Blocklist``` or ```Props``` sent after login.
+    /// The payload is kept as serialized json
+    ServerNotice { opcode: String, payload: String }
+}
+
+/// Direction of a frame passed to a ```FrameObserver```
+#[cfg(feature = "raw-nodes")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameDirection {
+    Inbound,
+    Outbound
 }
 
+/// Tap receiving every decrypted inbound node and every outbound node before its encryption,
+/// together with the time it was received or sent.
+/// It is called while the connection is locked, so it must not call back into the connection
+#[cfg(feature = "raw-nodes")]
+pub type FrameObserver = Box<Fn(FrameDirection, SystemTime, &Node) + Send + Sync>;
+
 pub trait WhatsappWebHandler<H = Self> where H: WhatsappWebHandler<H> + Send + Sync + 'static {
     fn on_state_changed(&self, connection: &WhatsappWebConnection<H>, state: State);
 
@@ -87,12 +218,18 @@ pub trait WhatsappWebHandler<H = Self> where H: WhatsappWebHandler<H> + Send + S
     fn on_disconnect(&self, reason: DisconnectReason);
 
     fn on_message(&self, connection: &WhatsappWebConnection<H>, message_new: bool, message: Box<WhatsappMessage>);
+
+    /// Error which occurred in the background, e.g. while sending a message
+    fn on_error(&self, _connection: &WhatsappWebConnection<H>, _error: Error) {}
+
+    /// Error which occurred while logging in with a new session, e.g. ```ErrorKind::QrCode``` if the qr-code couldn't be generated
+    fn on_login_error(&self, _connection: &WhatsappWebConnection<H>, _error: Error) {}
 }
 
 enum SessionState {
-    PendingNew { private_key: Option<agreement::EphemeralPrivateKey>, public_key: Vec<u8>, client_id: [u8; 8], qr_callback: Box<Fn(QrCode) + Send> },
+    PendingNew { private_key: Option<agreement::EphemeralPrivateKey>, public_key: Vec<u8>, client_id: [u8; 8], qr_callback: Box<Fn(String) -> Result<()> + Send> },
     PendingPersistent { persistent_session: PersistentSession },
-    Established { persistent_session: PersistentSession },
+    Established { persistent_session: PersistentSession, keys: crypto::SessionKeys },
     Teardown
 }
 
@@ -101,88 +238,296 @@ enum WebsocketState {
     Connected(Sender, timeout::TimeoutManager)
 }
 
-enum WebsocketResponse {
+enum WebsocketResponse<'a> {
     Json(JsonValue),
-    Node(Node)
+    Node(Node<'a>)
+}
+
+/// Client side state of a chat used to page through its history
+#[derive(Default)]
+struct ChatPage {
+    last_read: Option<MessageId>,
+    oldest_fetched: Option<(MessageId, NaiveDateTime)>
+}
+
+/// Parts of the initial sync after login which were received
+#[derive(Default)]
+struct InitialSync {
+    contacts: bool,
+    chats: bool,
+    messages: bool,
+    complete: bool
+}
+
+impl InitialSync {
+    /// Returns true only the first time all parts were received
+    fn check_complete(&mut self) -> bool {
+        if !self.complete && self.contacts && self.chats && self.messages {
+            self.complete = true;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Messages sent by this connection which weren't acknowledged by the server yet
+struct AckResend {
+    window: Duration,
+    max_attempts: u32,
+    /// Deadline of the ack and the number of resends, by message id
+    pending: HashMap<String, (Instant, u32)>,
+    /// Whether an ```ACK_RESEND_TOKEN``` timeout is scheduled, only one is kept at a time
+    timer_armed: bool
 }
 
 struct WhatsappWebConnectionInner<H: WhatsappWebHandler<H> + Send + Sync + 'static> {
     pub user_jid: Option<Jid>,
-    requests: HashMap<String, Box<Fn(WebsocketResponse, &WhatsappWebConnection<H>) + Send>>,
+    options: ConnectionOptions,
+    requests: HashMap<String, (Instant, Box<FnOnce(Result<WebsocketResponse>, &WhatsappWebConnection<H>) + Send>)>,
+    /// Callbacks of requests which were dropped because of ```MAX_PENDING_REQUESTS``` or couldn't be sent,
+    /// together with the reason they are failed with once the state is unlocked
+    failed_requests: Vec<(Box<FnOnce(Result<WebsocketResponse>, &WhatsappWebConnection<H>) + Send>, &'static str)>,
+    sent_messages: HashMap<String, SentMessage>,
+    /// Ids of ```sent_messages``` in insertion order, used to evict the oldest ones
+    sent_order: VecDeque<String>,
+    retried_messages: HashSet<String>,
+    resend_on_retry: bool,
+    ack_resend: Option<AckResend>,
+    chats: HashMap<String, Chat>,
+    roster_chunk_size: Option<usize>,
+    group_metadata_cache: Option<HashMap<Jid, GroupMetadata>>,
+    presence_subscriptions: HashSet<Jid>,
+    /// Subscriptions waiting to be sent, at most ```PRESENCE_SUBSCRIBE_BATCH``` are sent per interval
+    presence_subscribe_queue: VecDeque<Jid>,
+    presence_subscribe_armed: bool,
+    invite_on_add_restriction: bool,
+    chat_pages: HashMap<Jid, ChatPage>,
+    identities: HashMap<Jid, Vec<u8>>,
+    ack_levels: Option<(HashMap<(MessageId, MessageAckSide), MessageAckLevel>, VecDeque<(MessageId, MessageAckSide)>)>,
+    push_name: Option<String>,
+    phone_info: PhoneInfo,
+    battery: Option<u8>,
+    phone_connected: bool,
+    presence_keepalive: bool,
+    presence_keepalive_timeout: Option<Timeout>,
+    /// Expiry of the typing or recording state shown to a chat
+    chat_states: HashMap<Jid, Instant>,
+    /// Media connection info together with the time it was queried
+    media_conn: Option<(Instant, MediaConn)>,
+    media_limits: MediaLimits,
+    initial_sync: InitialSync,
+    #[cfg(feature = "raw-nodes")]
+    frame_observer: Option<FrameObserver>,
     messages_tag_counter: u32,
     session_state: SessionState,
     websocket_state: WebsocketState,
-    epoch: u32
+    epoch: u32,
+    /// Counters stored in the ```PersistentSession```, they are reserved ahead of the used ones
+    reserved_counters: (u32, u32),
+    /// A new block of counters was reserved and the session has to be handed to the handler
+    counters_reserved: bool,
+    node_buffer: Vec<u8>
 }
 
 impl<H: WhatsappWebHandler<H> + Send + Sync + 'static> WhatsappWebConnectionInner<H> {
+    fn state(&self) -> State {
+        match self.session_state {
+            SessionState::PendingNew { .. } => State::Uninitialized,
+            SessionState::PendingPersistent { .. } => State::Reconnecting,
+            SessionState::Established { .. } => State::Connected,
+            SessionState::Teardown => State::Disconnecting
+        }
+    }
+
+    fn client_id(&self) -> Option<[u8; 8]> {
+        match self.session_state {
+            SessionState::PendingNew { client_id, .. } => Some(client_id),
+            SessionState::PendingPersistent { ref persistent_session } |
+            SessionState::Established { ref persistent_session, .. } => Some(persistent_session.client_id),
+            SessionState::Teardown => None
+        }
+    }
     
-    fn send_json_message(&mut self, message: JsonValue, cb: Box<Fn(JsonValue, &WhatsappWebConnection<H>) + Send>) {
+    fn send_json_message(&mut self, message: JsonValue, cb: Box<FnOnce(Result<JsonValue>, &WhatsappWebConnection<H>) + Send>) -> Result<()> {
         debug!("sending json {:?}", &message);
         let tag = self.alloc_message_tag();
         self.ws_send_message(WebsocketMessage {
             tag: tag.into(),
             payload: WebsocketMessagePayload::Json(message)
         }, Box::new(move |payload, conn| {
-            if let WebsocketResponse::Json(json) = payload { 
-                cb(json, conn) 
+            match payload {
+                Ok(WebsocketResponse::Json(json)) => cb(Ok(json), conn),
+                Ok(WebsocketResponse::Node(_)) => cb(Err("unexpected binary response".into()), conn),
+                Err(err) => cb(Err(err), conn)
             }
-        }));
+        }))
+    }
+
+    fn send_group_command(&mut self, command: GroupCommand, participants: Vec<Jid>, cb: Box<FnOnce(Result<WebsocketResponse>, &WhatsappWebConnection<H>) + Send>) -> Result<()> {
+        let inducer = self.user_jid.clone().ok_or("not logged in")?;
+        let tag = self.alloc_message_tag();
+
+        let app_event = AppEvent::GroupCommand { inducer, participants, id: tag.clone(), command };
+
+        self.send_app_message(Some(tag), WebsocketMessageMetric::Group, AppMessage::MessagesEvents(Some(MessageEventType::Set), vec![app_event]), cb)
     }
 
-    fn send_group_command(&mut self, command: GroupCommand, participants: Vec<Jid>) {
+
+    fn send_broadcast_command(&mut self, command: BroadcastCommand, recipients: Vec<Jid>, cb: Box<FnOnce(Result<WebsocketResponse>, &WhatsappWebConnection<H>) + Send>) -> Result<()> {
         let tag = self.alloc_message_tag();
 
-        let app_event = AppEvent::GroupCommand { inducer: self.user_jid.clone().unwrap(), participants, id: tag.clone(), command };
+        let app_event = AppEvent::BroadcastCommand { id: tag.clone(), recipients, command };
 
-        self.send_app_message(Some(tag), WebsocketMessageMetric::Group, AppMessage::MessagesEvents(Some(MessageEventType::Set), vec![app_event]),
-                              Box::new(|_, _| {}));
+        self.send_app_message(Some(tag), WebsocketMessageMetric::Group, AppMessage::MessagesEvents(Some(MessageEventType::Set), vec![app_event]), cb)
     }
 
+    fn send_chat_message(&mut self, message_id: MessageId, jid: Jid, message_content: ChatMessageContent) -> Result<()> {
+        message_content.validate()?;
+        self.media_limits.check(&message_content)?;
+        if !self.sent_messages.contains_key(&message_id.0) {
+            if self.sent_order.len() >= MAX_SENT_MESSAGES {
+                if let Some(oldest) = self.sent_order.pop_front() {
+                    self.sent_messages.remove(&oldest);
+                    self.retried_messages.remove(&oldest);
+                }
+            }
+            self.sent_order.push_back(message_id.0.clone());
+        }
+        self.sent_messages.insert(message_id.0.clone(), SentMessage {
+            id: message_id.clone(),
+            jid: jid.clone(),
+            content: message_content.clone()
+        });
+
+        if let Some(ref mut ack_resend) = self.ack_resend {
+            if !ack_resend.pending.contains_key(&message_id.0) {
+                ack_resend.pending.insert(message_id.0.clone(), (Instant::now() + ack_resend.window, 0));
+                if let WebsocketState::Connected(ref sender, _) = self.websocket_state {
+                    if !ack_resend.timer_armed {
+                        ack_resend.timer_armed = sender.timeout(duration_millis(ack_resend.window), timeout::ACK_RESEND_TOKEN).is_ok();
+                    }
+                }
+            }
+        }
+
+        let msg = AppMessage::MessagesEvents(Some(MessageEventType::Relay), vec![AppEvent::Message(Box::new(WhatsappMessage {
+            content: message_content,
+            time: Utc::now().naive_utc(),
+            direction: Direction::Sending(jid),
+            id: message_id.clone(),
+            #[cfg(feature = "raw-proto")]
+            raw: None
+        }))]);
+        self.send_app_message(Some(message_id.0), WebsocketMessageMetric::Message, msg, Box::new(|_, _| {}))
+    }
 
-    fn send_app_message(&mut self, tag: Option<String>, metric: WebsocketMessageMetric, app_message: AppMessage, cb: Box<Fn(WebsocketResponse, &WhatsappWebConnection<H>) + Send>) {
+    fn send_app_message(&mut self, tag: Option<String>, metric: WebsocketMessageMetric, app_message: AppMessage, cb: Box<FnOnce(Result<WebsocketResponse>, &WhatsappWebConnection<H>) + Send>) -> Result<()> {
         self.epoch += 1;
         let epoch = self.epoch;
-        self.send_node_message(tag, metric, app_message.serialize(epoch), cb);
+        self.reserve_counters();
+        self.send_node_message(tag, metric, app_message.serialize(epoch), cb)
+    }
+
+    /// Reserve the next block of counters once the used ones reach the stored ones,
+    /// so a session restored after a crash never reuses a counter
+    fn reserve_counters(&mut self) {
+        if self.epoch > self.reserved_counters.0 || self.messages_tag_counter > self.reserved_counters.1 {
+            self.reserved_counters = (self.epoch + COUNTER_RESERVATION, self.messages_tag_counter + COUNTER_RESERVATION);
+            self.counters_reserved = true;
+        }
     }
 
     #[inline]
-    fn send_node_message(&mut self, tag: Option<String>, metric: WebsocketMessageMetric, node: Node, cb: Box<Fn(WebsocketResponse, &WhatsappWebConnection<H>) + Send>) {
+    fn send_node_message(&mut self, tag: Option<String>, metric: WebsocketMessageMetric, node: Node, cb: Box<FnOnce(Result<WebsocketResponse>, &WhatsappWebConnection<H>) + Send>) -> Result<()> {
         debug!("sending node {:?}", &node);
-        self.send_binary_message(tag, metric, &node.serialize(), cb);
+        #[cfg(feature = "raw-nodes")]
+        self.observe_frame(FrameDirection::Outbound, &node);
+        let mut node_buffer = take_scratch_buffer(&mut self.node_buffer);
+        node.serialize_into(&mut node_buffer);
+        let result = self.send_binary_message(tag, metric, &node_buffer, cb);
+        return_scratch_buffer(&mut self.node_buffer, node_buffer);
+        result
+    }
+
+    #[cfg(feature = "raw-nodes")]
+    fn observe_frame(&self, direction: FrameDirection, node: &Node) {
+        if let Some(ref frame_observer) = self.frame_observer {
+            frame_observer(direction, SystemTime::now(), node);
+        }
+    }
+
+    fn ws_send_message(&mut self, message: WebsocketMessage, callback: Box<FnOnce(Result<WebsocketResponse>, &WhatsappWebConnection<H>) + Send>) -> Result<()> {
+        let frame = message.serialize();
+        self.ws_send_frame(message.tag.into_owned(), frame, callback)
     }
 
-    fn ws_send_message(&mut self, message: WebsocketMessage, callback: Box<Fn(WebsocketResponse, &WhatsappWebConnection<H>) + Send>) {
+    fn ws_send_frame(&mut self, tag: String, frame: Message, callback: Box<FnOnce(Result<WebsocketResponse>, &WhatsappWebConnection<H>) + Send>) -> Result<()> {
         if let WebsocketState::Connected(ref sender, _) = self.websocket_state {
-            sender.send(message.serialize()).unwrap();
-            self.requests.insert(message.tag.into(), callback);
+            if let Err(err) = sender.send(frame) {
+                self.failed_requests.push((callback, "could not send message"));
+                return Err(err).chain_err(|| "could not send message");
+            }
+
+            if self.requests.len() >= MAX_PENDING_REQUESTS {
+                let oldest_tag = self.requests.iter().min_by_key(|&(_, request)| request.0).map(|(tag, _)| tag.clone());
+                if let Some(tag) = oldest_tag {
+                    warn!("dropping unanswered request {}", tag);
+                    if let Some((_, callback)) = self.requests.remove(&tag) {
+                        self.failed_requests.push((callback, "request evicted"));
+                    }
+                }
+            }
+            self.requests.insert(tag, (Instant::now(), callback));
+        } else {
+            self.failed_requests.push((callback, "not connected"));
         }
+        Ok(())
     }
 
     fn alloc_message_tag(&mut self) -> String {
         let tag = self.messages_tag_counter;
         self.messages_tag_counter += 1;
+        self.reserve_counters();
         tag.to_string()
     }
 
-    fn send_binary_message(&mut self, tag: Option<String>, metric: WebsocketMessageMetric, message: &[u8], cb: Box<Fn(WebsocketResponse, &WhatsappWebConnection<H>) + Send>) {
-        let encrypted_message = if let SessionState::Established { ref persistent_session } = self.session_state {
-            crypto::sign_and_encrypt_message(&persistent_session.enc, &persistent_session.mac, &message)
-        } else {
-            return;
+    fn send_binary_message(&mut self, tag: Option<String>, metric: WebsocketMessageMetric, message: &[u8], cb: Box<FnOnce(Result<WebsocketResponse>, &WhatsappWebConnection<H>) + Send>) -> Result<()> {
+        let tag = match self.session_state {
+            SessionState::Established { .. } => tag.unwrap_or_else(|| self.alloc_message_tag()),
+            _ => {
+                self.failed_requests.push((cb, "not connected"));
+                return Ok(())
+            }
         };
 
-        let tag = tag.unwrap_or_else(|| self.alloc_message_tag());
-        self.ws_send_message(WebsocketMessage {
-            tag: tag.into(),
-            payload: WebsocketMessagePayload::BinaryEphemeral(metric, &encrypted_message)
-        }, cb);
+        // The frame is assembled in a single buffer: tag, metric, hmac, iv and ciphertext
+        let mut frame = Vec::with_capacity(tag.len() + 3 + 32 + 16 + message.len() + 32);
+        websocket_protocol::write_binary_ephemeral_prefix(&tag, metric, &mut frame);
+        if let SessionState::Established { ref keys, .. } = self.session_state {
+            keys.sign_and_encrypt_message_into(message, &mut frame);
+        }
+
+        self.ws_send_frame(tag, Message::Binary(frame), cb)
     }
 
 
+    /// Session of the established connection with the reserved counters
+    fn persistent_session(&self) -> Option<PersistentSession> {
+        if let SessionState::Established { ref persistent_session, .. } = self.session_state {
+            Some(PersistentSession {
+                epoch: self.reserved_counters.0,
+                tag_counter: self.reserved_counters.1,
+                ..persistent_session.clone()
+            })
+        } else {
+            None
+        }
+    }
+
     fn decrypt_binary_message(&mut self, encrypted_message: &[u8]) -> Result<Vec<u8>> {
-        if let SessionState::Established { ref persistent_session } = self.session_state {
-            crypto::verify_and_decrypt_message(&persistent_session.enc[..], &persistent_session.mac[..], &encrypted_message)
+        if let SessionState::Established { ref keys, .. } = self.session_state {
+            keys.verify_and_decrypt_message(&encrypted_message)
         } else {
           bail!{"connection not established yet"}
         }
@@ -197,27 +542,35 @@ impl<H: WhatsappWebHandler<H> + Send + Sync + 'static> WhatsappWebConnectionInne
                 self.user_jid = Some(user_jid);
 
                 let persistent_session = PersistentSession {
+                    version: PERSISTENT_SESSION_VERSION,
                     client_token: client_token.to_string(),
                     server_token: server_token.to_string(),
                     client_id: *client_id,
                     enc,
-                    mac
+                    mac,
+                    epoch: self.reserved_counters.0,
+                    tag_counter: self.reserved_counters.1
                 };
 
-                (SessionState::Established { persistent_session: persistent_session.clone() }, persistent_session, self.user_jid.clone())
+                let keys = crypto::SessionKeys::new(&persistent_session.enc, &persistent_session.mac);
+                (SessionState::Established { persistent_session: persistent_session.clone(), keys }, persistent_session, self.user_jid.clone())
             }
             SessionState::PendingPersistent { ref persistent_session } => {
                 self.user_jid = Some(user_jid);
 
                 let new_persistent_session = PersistentSession {
+                    version: PERSISTENT_SESSION_VERSION,
                     client_id: persistent_session.client_id,
                     enc: persistent_session.enc,
                     mac: persistent_session.mac,
                     client_token: client_token.to_string(),
-                    server_token: server_token.to_string()
+                    server_token: server_token.to_string(),
+                    epoch: self.reserved_counters.0,
+                    tag_counter: self.reserved_counters.1
                 };
 
-                (SessionState::Established { persistent_session: new_persistent_session.clone() }, new_persistent_session, self.user_jid.clone())
+                let keys = crypto::SessionKeys::new(&new_persistent_session.enc, &new_persistent_session.mac);
+                (SessionState::Established { persistent_session: new_persistent_session.clone(), keys }, new_persistent_session, self.user_jid.clone())
             }
             _ => { bail!{"Session already established but received conn packet"} }
         };
@@ -225,7 +578,109 @@ impl<H: WhatsappWebHandler<H> + Send + Sync + 'static> WhatsappWebConnectionInne
         Ok((persistent_session, user_jid.unwrap()))
     }
 
+    /// Resend the messages whose ack is overdue, returns the messages which exhausted their resends
+    fn resend_unacked_messages(&mut self) -> Vec<SentMessage> {
+        let now = Instant::now();
+        let (overdue, window, max_attempts) = match self.ack_resend {
+            Some(ref mut ack_resend) => {
+                ack_resend.timer_armed = false;
+                let overdue: Vec<_> = ack_resend.pending.iter()
+                    .filter(|&(_, &(deadline, _))| deadline <= now)
+                    .map(|(id, &(_, attempts))| (id.clone(), attempts))
+                    .collect();
+                (overdue, ack_resend.window, ack_resend.max_attempts)
+            }
+            None => return Vec::new()
+        };
+
+        let mut failed_messages = Vec::new();
+        for (id, attempts) in overdue {
+            let sent_message = match self.sent_messages.get(&id).cloned() {
+                Some(sent_message) => sent_message,
+                None => {
+                    if let Some(ref mut ack_resend) = self.ack_resend {
+                        ack_resend.pending.remove(&id);
+                    }
+                    continue;
+                }
+            };
+            if attempts >= max_attempts {
+                if let Some(ref mut ack_resend) = self.ack_resend {
+                    ack_resend.pending.remove(&id);
+                }
+                self.sent_messages.remove(&id);
+                self.retried_messages.remove(&id);
+                failed_messages.push(sent_message);
+                continue;
+            }
+
+            debug!("resending unacknowledged message {:?}", &sent_message.id);
+            if let Some(ref mut ack_resend) = self.ack_resend {
+                ack_resend.pending.insert(id, (now + window, attempts + 1));
+            }
+            if let Err(err) = self.send_chat_message(sent_message.id, sent_message.jid, sent_message.content) {
+                warn!("could not resend message: {:?}", err);
+            }
+        }
+
+        if let (&WebsocketState::Connected(ref sender, _), &mut Some(ref mut ack_resend)) = (&self.websocket_state, &mut self.ack_resend) {
+            let next_deadline = ack_resend.pending.values().map(|&(deadline, _)| deadline).min();
+            if let Some(next_deadline) = next_deadline {
+                if !ack_resend.timer_armed {
+                    let delay = if next_deadline > now { next_deadline - now } else { Duration::from_millis(0) };
+                    ack_resend.timer_armed = sender.timeout(duration_millis(delay), timeout::ACK_RESEND_TOKEN).is_ok();
+                }
+            }
+        }
+        failed_messages
+    }
+
+    fn set_chat_state(&mut self, jid: Jid, status: PresenceStatus, duration: Duration) -> Result<()> {
+        let msg = AppMessage::MessagesEvents(Some(MessageEventType::Set), vec![AppEvent::PresenceChange(status, Some(jid.clone()))]);
+        self.send_app_message(None, WebsocketMessageMetric::Presence, msg, Box::new(|_, _| {}))?;
+
+        self.chat_states.insert(jid, Instant::now() + duration);
+        if let WebsocketState::Connected(ref sender, _) = self.websocket_state {
+            sender.timeout(duration_millis(duration), timeout::CHAT_STATE_TOKEN).ok();
+        }
+        Ok(())
+    }
+
+    /// Revert the expired typing or recording states to ```Paused```
+    fn expire_chat_states(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let expired: Vec<Jid> = self.chat_states.iter().filter(|&(_, &expiry)| expiry <= now).map(|(jid, _)| jid.clone()).collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+        for jid in &expired {
+            self.chat_states.remove(jid);
+        }
+        let events = expired.into_iter().map(|jid| AppEvent::PresenceChange(PresenceStatus::Paused, Some(jid))).collect();
+        self.send_app_message(None, WebsocketMessageMetric::Presence, AppMessage::MessagesEvents(Some(MessageEventType::Set), events), Box::new(|_, _| {}))
+    }
+
     fn on_timeout(&mut self, event: Token) {
+        if event == timeout::PRESENCE_KEEPALIVE_TOKEN {
+            self.presence_keepalive_timeout = None;
+            if let Err(err) = self.send_presence_keepalive() {
+                error!("could not send presence keepalive {:?}", err);
+            }
+            return;
+        }
+        if event == timeout::CHAT_STATE_TOKEN {
+            if let Err(err) = self.expire_chat_states() {
+                error!("could not revert chat state {:?}", err);
+            }
+            return;
+        }
+        if event == timeout::PRESENCE_SUBSCRIBE_TOKEN {
+            self.presence_subscribe_armed = false;
+            if let Err(err) = self.send_queued_presence_subscriptions() {
+                error!("could not subscribe presence {:?}", err);
+            }
+            return;
+        }
         if let WebsocketState::Connected(ref sender, ref mut timeout_manager) = self.websocket_state {
             match timeout_manager.on_timeout(event) {
                 Some(timeout::TimeoutState::Normal) => {
@@ -243,49 +698,281 @@ impl<H: WhatsappWebHandler<H> + Send + Sync + 'static> WhatsappWebConnectionInne
         }
     }
 
-    fn handle_server_challenge(&mut self, challenge: &[u8]) {
+    fn send_presence_keepalive(&mut self) -> Result<()> {
+        if !self.presence_keepalive {
+            return Ok(());
+        }
+        let msg = AppMessage::MessagesEvents(Some(MessageEventType::Set), vec![AppEvent::PresenceChange(PresenceStatus::Available, None)]);
+        self.send_app_message(None, WebsocketMessageMetric::Presence, msg, Box::new(|_, _| {}))?;
+
+        if let WebsocketState::Connected(ref sender, _) = self.websocket_state {
+            sender.timeout(timeout::PRESENCE_KEEPALIVE_INTERVAL, timeout::PRESENCE_KEEPALIVE_TOKEN).ok();
+        }
+        Ok(())
+    }
+
+    fn set_presence_keepalive(&mut self, presence_keepalive: bool) -> Result<()> {
+        if self.presence_keepalive == presence_keepalive {
+            return Ok(());
+        }
+        self.presence_keepalive = presence_keepalive;
+
+        if presence_keepalive {
+            if let SessionState::Established { .. } = self.session_state {
+                return self.send_presence_keepalive();
+            }
+        } else if let Some(timeout) = self.presence_keepalive_timeout.take() {
+            if let WebsocketState::Connected(ref sender, _) = self.websocket_state {
+                sender.cancel(timeout).ok();
+            }
+        }
+        Ok(())
+    }
+
+    fn subscribe_presences(&mut self, jids: &[Jid]) -> Result<()> {
+        for jid in jids {
+            if self.presence_subscriptions.insert(jid.clone()) {
+                self.presence_subscribe_queue.push_back(jid.clone());
+            }
+        }
+        self.send_queued_presence_subscriptions()
+    }
+
+    fn unsubscribe_presences(&mut self, jids: &[Jid]) {
+        for jid in jids {
+            self.presence_subscriptions.remove(jid);
+        }
+        self.presence_subscribe_queue.retain(|jid| !jids.contains(jid));
+    }
+
+    /// Subscriptions are bound to the session, so they have to be sent again after a reconnect
+    fn resubscribe_presences(&mut self) -> Result<()> {
+        self.presence_subscribe_queue = self.presence_subscriptions.iter().cloned().collect();
+        self.send_queued_presence_subscriptions()
+    }
+
+    /// Send the next batch of queued subscriptions, the remaining ones are sent once the interval elapsed
+    fn send_queued_presence_subscriptions(&mut self) -> Result<()> {
+        if self.presence_subscribe_armed {
+            return Ok(());
+        }
+        if let WebsocketState::Disconnected = self.websocket_state {
+            return Ok(());
+        }
+        for _ in 0..PRESENCE_SUBSCRIBE_BATCH {
+            match self.presence_subscribe_queue.pop_front() {
+                Some(jid) => self.send_presence_subscribe(jid)?,
+                None => break
+            }
+        }
+        if !self.presence_subscribe_queue.is_empty() {
+            if let WebsocketState::Connected(ref sender, _) = self.websocket_state {
+                self.presence_subscribe_armed = sender.timeout(timeout::PRESENCE_SUBSCRIBE_INTERVAL, timeout::PRESENCE_SUBSCRIBE_TOKEN).is_ok();
+            }
+        }
+        Ok(())
+    }
+
+    fn send_presence_subscribe(&mut self, jid: Jid) -> Result<()> {
+        self.send_json_message(json_protocol::build_presence_subscribe(&jid), Box::new(move |response, connection| {
+            let subscribed = response.and_then(|response| json_protocol::parse_response_status(&response)).is_ok();
+            connection.handler.on_user_data_changed(connection, UserData::PresenceSubscription(jid, subscribed));
+        }))
+    }
+
+    /// Returns false if the ack doesn't raise the level tracked for its message and side
+    fn track_ack_level(&mut self, message_ack: &MessageAck) -> bool {
+        let (ack_levels, order) = match self.ack_levels {
+            Some((ref mut ack_levels, ref mut order)) => (ack_levels, order),
+            None => return true
+        };
+        let key = (message_ack.id.clone(), message_ack.side.clone());
+        if let Some(level) = ack_levels.get_mut(&key) {
+            if !message_ack.level.supersedes(level) {
+                return false;
+            }
+            *level = message_ack.level;
+            return true;
+        }
+        if order.len() >= MAX_TRACKED_ACKS {
+            if let Some(oldest) = order.pop_front() {
+                ack_levels.remove(&oldest);
+            }
+        }
+        ack_levels.insert(key.clone(), message_ack.level);
+        order.push_back(key);
+        true
+    }
+
+    fn correlate_message_ack(&mut self, message_ack: MessageAck) -> Option<UserData> {
+        if let MessageAckSide::There(_) = message_ack.side {
+            if let Some(ref mut ack_resend) = self.ack_resend {
+                if message_ack.level != MessageAckLevel::PendingSend {
+                    ack_resend.pending.remove(&message_ack.id.0);
+                }
+            }
+        }
+
+        if !self.track_ack_level(&message_ack) {
+            return None;
+        }
+
+        let sent_message = match message_ack.side {
+            MessageAckSide::There(PeerAck::GroupIndividual { .. }) |
+            MessageAckSide::There(PeerAck::BroadcastIndividual { .. }) => self.sent_messages.get(&message_ack.id.0).cloned(),
+            MessageAckSide::There(_) => match message_ack.level {
+                MessageAckLevel::Read | MessageAckLevel::Played | MessageAckLevel::Error(_) => {
+                    self.retried_messages.remove(&message_ack.id.0);
+                    self.sent_messages.remove(&message_ack.id.0)
+                }
+                _ => self.sent_messages.get(&message_ack.id.0).cloned()
+            },
+            MessageAckSide::Here(ref peer) => {
+                if message_ack.level == MessageAckLevel::Read {
+                    self.track_read_message(peer, &message_ack.id);
+                }
+                None
+            }
+        };
+
+        Some(if let Some(sent_message) = sent_message {
+            UserData::SentMessageAck(message_ack, sent_message)
+        } else {
+            UserData::MessageAck(message_ack)
+        })
+    }
+
+    fn track_fetched_message(&mut self, message: &WhatsappMessage) {
+        let chat = match message.direction {
+            Direction::Sending(ref jid) => jid,
+            Direction::Receiving(ref peer) => peer.chat_jid()
+        };
+        let page = self.chat_pages.entry(chat.clone()).or_insert_with(ChatPage::default);
+        if page.oldest_fetched.as_ref().map_or(true, |&(_, time)| message.time < time) {
+            page.oldest_fetched = Some((message.id.clone(), message.time));
+        }
+    }
+
+    fn track_read_message(&mut self, peer: &Peer, id: &MessageId) {
+        self.chat_pages.entry(peer.chat_jid().clone()).or_insert_with(ChatPage::default).last_read = Some(id.clone());
+    }
+
+    fn cache_group_metadata(&mut self, meta: GroupMetadata) {
+        if let Some(ref mut cache) = self.group_metadata_cache {
+            cache.insert(meta.id.clone(), meta);
+        }
+    }
+
+    /// Applies the change to the cached group and requests its metadata again, as roles aren't part of the change
+    fn update_cached_group_participants(&mut self, group: &Jid, change: GroupParticipantsChange, participants: &[Jid]) -> Result<()> {
+        if let Some(ref mut cache) = self.group_metadata_cache {
+            if let Some(meta) = cache.get_mut(group) {
+                meta.apply_participants_change(change, participants);
+            }
+        } else {
+            return Ok(());
+        }
+
+        self.send_json_message(json_protocol::build_query(JsonQuery::GroupMetadata(group.clone())), Box::new(|response, connection| {
+            if let Ok(meta) = response.and_then(|response| json_protocol::parse_group_metadata_response(&response)) {
+                connection.lock_inner().cache_group_metadata(meta);
+            }
+        }))
+    }
+
+    fn update_cached_group_subject(&mut self, group: &Jid, subject: &str, subject_time: i64, subject_owner: &Jid) {
+        if let Some(meta) = self.group_metadata_cache.as_mut().and_then(|cache| cache.get_mut(group)) {
+            meta.subject = subject.to_string();
+            meta.subject_time = subject_time;
+            meta.subject_owner = subject_owner.clone();
+        }
+    }
+
+    fn update_phone_connectivity(&mut self, connected: bool) -> Option<bool> {
+        if self.phone_connected != connected {
+            self.phone_connected = connected;
+            Some(connected)
+        } else {
+            None
+        }
+    }
+
+    fn apply_chat_action(&mut self, jid: &Jid, action: ChatAction) -> Option<Chat> {
+        let key = jid.to_string();
+        if let ChatAction::Delete = action {
+            self.chats.remove(&key);
+            return None;
+        }
+
+        self.chats.get_mut(&key).map(|chat| {
+            chat.apply_action(action);
+            chat.clone()
+        })
+    }
+
+    fn rename_chat(&mut self, jid: &Jid, name: &str) -> Option<Chat> {
+        self.chats.get_mut(&jid.to_string()).map(|chat| {
+            chat.name = Some(name.to_string());
+            chat.clone()
+        })
+    }
+
+    fn handle_retry_request(&mut self, message_id: &MessageId) -> Result<()> {
+        if !self.resend_on_retry || !self.sent_messages.contains_key(&message_id.0) || !self.retried_messages.insert(message_id.0.clone()) {
+            return Ok(());
+        }
+
+        if let Some(sent_message) = self.sent_messages.get(&message_id.0).cloned() {
+            debug!("resending message {:?}", &sent_message.id);
+            self.send_chat_message(sent_message.id, sent_message.jid, sent_message.content)?;
+        }
+        Ok(())
+    }
+
+    fn handle_server_challenge(&mut self, challenge: &[u8]) -> Result<()> {
         let message = if let SessionState::PendingPersistent { ref persistent_session } = self.session_state {
             let signature = crypto::sign_challenge(&persistent_session.mac, challenge);
 
             json_protocol::build_challenge_response(persistent_session.server_token.as_str(), &base64::encode(&persistent_session.client_id), signature.as_ref())
         } else {
-            return;
+            return Ok(());
         };
 
-        self.send_json_message(message, Box::new(move |_, _| {}));
+        self.send_json_message(message, Box::new(move |_, _| {}))
     }
 
     fn handle_server_disconnect(&mut self) {
         self.session_state = SessionState::Teardown;
     }
 
-    fn ws_on_connected(&mut self, out: Sender) {
+    fn ws_on_connected(&mut self, out: Sender) -> Result<()> {
         let timeout_manager = timeout::TimeoutManager::new(&out, timeout::PING_TIMEOUT, timeout::TimeoutState::Normal);
 
         self.websocket_state = match self.websocket_state {
             WebsocketState::Disconnected => WebsocketState::Connected(out, timeout_manager),
-            WebsocketState::Connected(_, _) => return
+            WebsocketState::Connected(_, _) => return Ok(())
         };
-        let message: (JsonValue, Box<Fn(JsonValue, &WhatsappWebConnection<H>) + Send>) = match self.session_state {
+        let message: (JsonValue, Box<FnOnce(Result<JsonValue>, &WhatsappWebConnection<H>) + Send>) = match self.session_state {
             SessionState::PendingNew { ref client_id, .. } => {
                 let mut init_command = json_protocol::build_init_request(base64::encode(&client_id).as_str());
 
                 (init_command, Box::new(move |response, connection| {
-                    if let Ok(reference) = json_protocol::parse_init_response(&response) {
-                        match connection.inner.lock().unwrap().session_state {
+                    let reference = response.and_then(|response| json_protocol::parse_init_response(&response).map(|reference| reference.to_string()));
+                    let result = reference.and_then(|reference| {
+                        match connection.lock_inner().session_state {
                             SessionState::PendingNew { ref public_key, ref client_id, ref qr_callback, .. } => {
                                 debug!("QRCode: {}", reference);
 
-                                qr_callback(QrCode::new(
-                                    format!("{},{},{}", reference, base64::encode(&public_key), base64::encode(&client_id))
-                                ).unwrap());
+                                qr_callback(format!("{},{},{}", reference, base64::encode(&public_key), base64::encode(&client_id)))
                             }
                             _ => {
                                 unreachable!()
                             }
                         }
-                    } else {
-                        error!("error");
+                    });
+                    if let Err(err) = result {
+                        error!("{:?}", err);
+                        connection.handler.on_login_error(connection, err);
                     }
                 }))
             }
@@ -293,37 +980,46 @@ impl<H: WhatsappWebHandler<H> + Send + Sync + 'static> WhatsappWebConnectionInne
                 let mut init_command = json_protocol::build_init_request(base64::encode(&persistent_session.client_id).as_str());
 
                 (init_command, Box::new(move |response, connection| {
-                    if let Err(err) = json_protocol::parse_response_status(&response) {
+                    if let Err(err) = response.and_then(|response| json_protocol::parse_response_status(&response)) {
                         error!("error {:?}", err);
                     } else {
-                        let mut inner = connection.inner.lock().unwrap();
-                        let message: (JsonValue, Box<Fn(JsonValue, &WhatsappWebConnection<H>) + Send>) = match inner.session_state {
+                        let mut inner = connection.lock_inner();
+                        let message: (JsonValue, Box<FnOnce(Result<JsonValue>, &WhatsappWebConnection<H>) + Send>) = match inner.session_state {
                             SessionState::PendingPersistent { ref persistent_session } => {
                                 let mut login_command = json_protocol::build_takeover_request(persistent_session.client_token.as_str(),
                                                                                               persistent_session.server_token.as_str(),
                                                                                               &base64::encode(&persistent_session.client_id));
                                 (login_command, Box::new(move |response, connection| {
+                                    let response = match response {
+                                        Ok(response) => response,
+                                        Err(err) => {
+                                            error!("error {:?}", err);
+                                            return;
+                                        }
+                                    };
                                     if let Err(err) = json_protocol::parse_response_status(&response) {
                                         error!("error {:?}", err);
                                         connection.ws_disconnect();
-                                        connection.handler.on_disconnect(DisconnectReason::Removed);
+                                        connection.handler.on_disconnect(json_protocol::parse_disconnect_status(&response));
                                     }
                                 }))
                             }
                             _ => unreachable!()
                         };
-                        inner.send_json_message(message.0, message.1);
+                        let result = inner.send_json_message(message.0, message.1);
+                        drop(inner);
+                        connection.handle_result(result);
                     }
                 }))
             }
             _ => { unreachable!() }
         };
-        self.send_json_message(message.0, message.1);
+        self.send_json_message(message.0, message.1)
     }
 }
 
 impl<H: WhatsappWebHandler<H> + Send + Sync> WhatsappWebConnection<H> {
-    fn new<Q: Fn(QrCode) + Send + 'static>(qr_callback: Box<Q>, handler: H) -> WhatsappWebConnection<H> {
+    fn new(qr_callback: Box<Fn(String) -> Result<()> + Send>, options: ConnectionOptions, handler: H, media_workers: Arc<WorkerPool>) -> WhatsappWebConnection<H> {
         let mut client_id = [0u8; 8];
         SystemRandom::new().fill(&mut client_id).unwrap();
 
@@ -331,10 +1027,41 @@ impl<H: WhatsappWebHandler<H> + Send + Sync> WhatsappWebConnection<H> {
 
         WhatsappWebConnection {
             handler: Arc::new(handler),
+            media_workers,
+            media_jobs: Arc::new(PendingJobs::default()),
             inner: Arc::new(Mutex::new(WhatsappWebConnectionInner {
                 user_jid: None,
+                options,
                 websocket_state: WebsocketState::Disconnected,
                 requests: HashMap::new(),
+                failed_requests: Vec::new(),
+                sent_messages: HashMap::new(),
+                sent_order: VecDeque::new(),
+                retried_messages: HashSet::new(),
+                resend_on_retry: false,
+                ack_resend: None,
+                chats: HashMap::new(),
+                roster_chunk_size: None,
+                group_metadata_cache: None,
+                ack_levels: None,
+                presence_subscriptions: HashSet::new(),
+                presence_subscribe_queue: VecDeque::new(),
+                presence_subscribe_armed: false,
+                invite_on_add_restriction: false,
+                chat_pages: HashMap::new(),
+                identities: HashMap::new(),
+                push_name: None,
+                phone_info: PhoneInfo::default(),
+                battery: None,
+                phone_connected: true,
+                presence_keepalive: false,
+                presence_keepalive_timeout: None,
+                chat_states: HashMap::new(),
+                media_conn: None,
+                media_limits: MediaLimits::default(),
+                initial_sync: InitialSync::default(),
+                #[cfg(feature = "raw-nodes")]
+                frame_observer: None,
                 messages_tag_counter: 0,
                 session_state: SessionState::PendingNew {
                     private_key: Some(private_key),
@@ -342,177 +1069,523 @@ impl<H: WhatsappWebHandler<H> + Send + Sync> WhatsappWebConnection<H> {
                     client_id,
                     qr_callback
                 },
-                epoch: 0
+                epoch: 0,
+                reserved_counters: (0, 0),
+                counters_reserved: false,
+                node_buffer: Vec::new()
             }))
         }
     }
 
-    fn with_persistent_session(persistent_session: PersistentSession, handler: H) -> WhatsappWebConnection<H> {
+    fn with_persistent_session(persistent_session: PersistentSession, options: ConnectionOptions, handler: H, media_workers: Arc<WorkerPool>) -> WhatsappWebConnection<H> {
+        let (epoch, messages_tag_counter) = (persistent_session.epoch, persistent_session.tag_counter);
         WhatsappWebConnection {
             handler: Arc::new(handler),
+            media_workers,
+            media_jobs: Arc::new(PendingJobs::default()),
             inner: Arc::new(Mutex::new(WhatsappWebConnectionInner {
                 user_jid: None,
+                options,
                 websocket_state: WebsocketState::Disconnected,
                 requests: HashMap::new(),
-                messages_tag_counter: 0,
+                failed_requests: Vec::new(),
+                sent_messages: HashMap::new(),
+                sent_order: VecDeque::new(),
+                retried_messages: HashSet::new(),
+                resend_on_retry: false,
+                ack_resend: None,
+                chats: HashMap::new(),
+                roster_chunk_size: None,
+                group_metadata_cache: None,
+                ack_levels: None,
+                presence_subscriptions: HashSet::new(),
+                presence_subscribe_queue: VecDeque::new(),
+                presence_subscribe_armed: false,
+                invite_on_add_restriction: false,
+                chat_pages: HashMap::new(),
+                identities: HashMap::new(),
+                push_name: None,
+                phone_info: PhoneInfo::default(),
+                battery: None,
+                phone_connected: true,
+                presence_keepalive: false,
+                presence_keepalive_timeout: None,
+                chat_states: HashMap::new(),
+                media_conn: None,
+                media_limits: MediaLimits::default(),
+                initial_sync: InitialSync::default(),
+                #[cfg(feature = "raw-nodes")]
+                frame_observer: None,
+                messages_tag_counter,
                 session_state: SessionState::PendingPersistent {
                     persistent_session
                 },
-                epoch: 0
+                epoch,
+                reserved_counters: (epoch, messages_tag_counter),
+                counters_reserved: false,
+                node_buffer: Vec::new()
             }))
         }
     }
 
-    fn send_json_message(&self, message: JsonValue, cb: Box<Fn(JsonValue, &WhatsappWebConnection<H>) + Send>) {
-        self.inner.lock().unwrap().send_json_message(message, cb);
+    fn send_json_message(&self, message: JsonValue, cb: Box<FnOnce(Result<JsonValue>, &WhatsappWebConnection<H>) + Send>) {
+        let result = self.lock_inner().send_json_message(message, cb);
+        self.handle_result(result);
     }
 
-    fn send_app_message(&self, tag: Option<String>, metric: WebsocketMessageMetric, app_message: AppMessage, cb: Box<Fn(WebsocketResponse, &WhatsappWebConnection<H>) + Send>) {
-        self.inner.lock().unwrap().send_app_message(tag, metric, app_message, cb)
+    fn send_app_message(&self, tag: Option<String>, metric: WebsocketMessageMetric, app_message: AppMessage, cb: Box<FnOnce(Result<WebsocketResponse>, &WhatsappWebConnection<H>) + Send>) {
+        let result = self.lock_inner().send_app_message(tag, metric, app_message, cb);
+        self.handle_result(result);
     }
 
-    fn ws_on_disconnected(&self) {
-        let mut inner = self.inner.lock().unwrap();
-        inner.websocket_state = WebsocketState::Disconnected;
-
-        inner.session_state = match inner.session_state {
-            SessionState::Established { ref persistent_session } => {
-                SessionState::PendingPersistent { persistent_session: persistent_session.clone() }
+    /// Run ```f```, which calls into the handler, and recover from panics so the websocket thread stays alive
+    fn catch_handler_panic<F: FnOnce()>(&self, f: F) {
+        let was_broken = self.is_broken();
+        if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(f)) {
+            let message = panic.downcast_ref::<&str>().map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            error!("handler panicked: {}", message);
+            panic::catch_unwind(AssertUnwindSafe(|| self.handler.on_error(self, ErrorKind::HandlerPanicked(message).into()))).ok();
+            if !was_broken && self.is_broken() {
+                panic::catch_unwind(AssertUnwindSafe(|| self.handler.on_state_changed(self, State::Broken))).ok();
             }
-            _ => return
-        };
+        }
+    }
 
-        drop(inner);
+    /// A panic while the state was locked may have left it inconsistent,
+    /// the state is still used afterwards but the connection is reported as ```State::Broken```
+    fn lock_inner(&self) -> MutexGuard<WhatsappWebConnectionInner<H>> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
 
-        self.handler.on_state_changed(self, State::Reconnecting);
+    /// Whether a panic occurred while the state of the connection was locked,
+    /// the connection should be disconnected and recreated from the ```PersistentSession```
+    pub fn is_broken(&self) -> bool {
+        self.inner.is_poisoned()
     }
 
-    fn ws_on_message(&self, message: &Message) {
-        trace!("received websocket message {:?}", message);
-        let mut inner = self.inner.lock().unwrap();
-        if let WebsocketState::Connected(ref out, ref mut timeout_manager) = inner.websocket_state {
-            timeout_manager.arm(out, timeout::PING_TIMEOUT, timeout::TimeoutState::Normal);
-        } else {
-            return;
+    /// Chat and sender of a protocol message like a revoke or an edit.
+    /// The remote jid of the referenced key is relative to the sender, so the chat is taken from the protocol message
+    fn protocol_message_origin(&self, direction: &Direction) -> (Jid, Option<Jid>) {
+        match *direction {
+            Direction::Sending(ref jid) => (jid.clone(), self.lock_inner().user_jid.clone()),
+            Direction::Receiving(Peer::Individual(ref jid)) => (jid.clone(), Some(jid.clone())),
+            Direction::Receiving(Peer::Group { ref group, ref participant }) |
+            Direction::Receiving(Peer::Broadcast { broadcast: ref group, sender: ref participant }) => (group.clone(), Some(participant.clone()))
         }
-        let message = WebsocketMessage::deserialize(message).unwrap();
+    }
 
+    /// Route errors of operations without caller, which could handle them, to the handler
+    fn handle_result(&self, result: Result<()>) {
+        self.flush_deferred();
+        if let Err(err) = result {
+            error!("{:?}", err);
+            self.handler.on_error(self, err);
+        }
+    }
 
-        match message.payload {
-            WebsocketMessagePayload::Json(payload) => {
-                debug!("received json: {:?}", &payload);
+    /// Run the callbacks which were deferred while the state was locked
+    fn flush_deferred(&self) {
+        let mut inner = self.lock_inner();
+        let failed_requests = mem::replace(&mut inner.failed_requests, Vec::new());
+        let persistent_session = if mem::replace(&mut inner.counters_reserved, false) { inner.persistent_session() } else { None };
+        drop(inner);
 
-                if let Some(cb) = inner.requests.remove(message.tag.deref()) {
-                    drop(inner);
-                    cb(WebsocketResponse::Json(payload), &self);
+        for (callback, reason) in failed_requests {
+            callback(Err(reason.into()), self);
+        }
+        if let Some(persistent_session) = persistent_session {
+            self.handler.on_persistent_session_data_changed(persistent_session);
+        }
+    }
+
+    /// Parse and hand out the initial contacts or chats in chunks of ```chunk_size``` entries
+    fn deliver_roster_chunks(&self, roster: Roster, chunk_size: usize) {
+        let (nodes, is_contacts) = match roster {
+            Roster::Contacts(nodes) => (nodes, true),
+            Roster::Chats(nodes) => (nodes, false)
+        };
+        if !is_contacts {
+            self.lock_inner().chats.clear();
+        }
+
+        let total = nodes.len();
+        let mut delivered = 0;
+        let mut nodes = nodes.into_iter();
+        loop {
+            let chunk: Vec<Node> = nodes.by_ref().take(chunk_size).collect();
+            delivered += chunk.len();
+            let last = delivered == total;
+
+            let user_data = if is_contacts {
+                node_protocol::parse_contact_nodes(chunk).map(|contacts| UserData::ContactsChunk { contacts, last })
+            } else {
+                node_protocol::parse_chat_nodes(chunk).map(|chats| {
+                    self.lock_inner().chats.extend(chats.iter().map(|chat| (chat.jid.to_string(), chat.clone())));
+                    UserData::ChatsChunk { chats, last }
+                })
+            };
+
+            let sync_complete = last && {
+                let mut inner = self.lock_inner();
+                if is_contacts {
+                    inner.initial_sync.contacts = true;
                 } else {
-                    match ServerMessage::deserialize(&payload) {
-                        Ok(ServerMessage::ConnectionAck { user_jid, client_token, server_token, secret }) => {
-                            if let Ok((persistent_session, user_jid)) = inner.handle_server_conn(user_jid, client_token, server_token, secret) {
-                                drop(inner);
-                                self.handler.on_state_changed(self, State::Connected);
-                                self.handler.on_persistent_session_data_changed(persistent_session);
-                                self.handler.on_user_data_changed(&self, UserData::UserJid(user_jid));
-                            }
-                        }
-                        Ok(ServerMessage::ChallengeRequest(challenge)) => {
-                            inner.handle_server_challenge(&challenge)
-                        }
-                        Ok(ServerMessage::Disconnect(kind)) => {
-                            inner.handle_server_disconnect();
+                    inner.initial_sync.chats = true;
+                }
+                inner.initial_sync.check_complete()
+            };
+
+            match user_data {
+                Ok(user_data) => self.handler.on_user_data_changed(self, user_data),
+                Err(err) => warn!("received invalid roster chunk: {:?}", err)
+            }
+            if sync_complete {
+                self.handler.on_user_data_changed(self, UserData::SyncComplete);
+            }
+            if last {
+                break;
+            }
+        }
+    }
+
+    fn ws_on_disconnected(&self) {
+        let mut inner = self.lock_inner();
+        inner.websocket_state = WebsocketState::Disconnected;
+        inner.presence_keepalive_timeout = None;
+        inner.initial_sync = InitialSync::default();
+        inner.presence_subscribe_armed = false;
+        if let Some(ref mut ack_resend) = inner.ack_resend {
+            ack_resend.timer_armed = false;
+        }
+        inner.chat_states.clear();
+
+        // Responses to requests of the closed socket will never arrive
+        let pending_requests: Vec<_> = inner.requests.drain().map(|(_, (_, callback))| callback).collect();
+        let failed_requests = mem::replace(&mut inner.failed_requests, Vec::new());
+
+        // The counters are continued by the next login of this session
+        let persistent_session = inner.persistent_session();
+        if let Some(ref persistent_session) = persistent_session {
+            inner.session_state = SessionState::PendingPersistent { persistent_session: persistent_session.clone() };
+        }
+
+        drop(inner);
+
+        for (callback, reason) in failed_requests {
+            callback(Err(reason.into()), self);
+        }
+        for callback in pending_requests {
+            callback(Err("connection closed".into()), self);
+        }
+
+        if let Some(persistent_session) = persistent_session {
+            self.handler.on_persistent_session_data_changed(persistent_session);
+            self.handler.on_state_changed(self, State::Reconnecting);
+        }
+    }
+
+    fn ws_on_message(&self, message: &Message) {
+        trace!("received websocket message {:?}", message);
+        let mut inner = self.lock_inner();
+        if let WebsocketState::Connected(ref out, ref mut timeout_manager) = inner.websocket_state {
+            timeout_manager.arm(out, timeout::PING_TIMEOUT, timeout::TimeoutState::Normal);
+        } else {
+            return;
+        }
+        let message = match WebsocketMessage::deserialize(message) {
+            Ok(message) => message,
+            Err(_) => {
+                warn!("received invalid websocket message");
+                return;
+            }
+        };
+
+
+        match message.payload {
+            WebsocketMessagePayload::Json(payload) => {
+                debug!("received json: {:?}", &payload);
+
+                if let Some((_, cb)) = inner.requests.remove(message.tag.deref()) {
+                    let phone_connectivity = json_protocol::parse_phone_connectivity(&payload)
+                        .and_then(|connected| inner.update_phone_connectivity(connected));
+                    drop(inner);
+                    if let Some(connected) = phone_connectivity {
+                        self.handler.on_user_data_changed(self, UserData::PhoneConnectivity(connected));
+                    }
+                    cb(Ok(WebsocketResponse::Json(payload)), &self);
+                } else {
+                    match ServerMessage::deserialize(&payload) {
+                        Ok(ServerMessage::ConnectionAck { user_jid, client_token, server_token, secret, push_name, phone }) => {
+                            inner.push_name = push_name.map(|push_name| push_name.to_string());
+                            inner.phone_info = phone;
+                            if let Ok((persistent_session, user_jid)) = inner.handle_server_conn(user_jid, client_token, server_token, secret) {
+                                let result = inner.send_presence_keepalive().and_then(|_| inner.resubscribe_presences());
+                                // Timers of the previous socket are gone, overdue messages are resent right away
+                                let failed_messages = inner.resend_unacked_messages();
+                                drop(inner);
+                                self.handle_result(result);
+                                self.handler.on_state_changed(self, State::Connected);
+                                self.handler.on_persistent_session_data_changed(persistent_session);
+                                self.handler.on_user_data_changed(&self, UserData::UserJid(user_jid));
+                                for failed_message in failed_messages {
+                                    self.handler.on_user_data_changed(self, UserData::MessageSendFailed(failed_message));
+                                }
+                            }
+                        }
+                        Ok(ServerMessage::ChallengeRequest(challenge)) => {
+                            let result = inner.handle_server_challenge(&challenge);
+                            drop(inner);
+                            self.handle_result(result);
+                        }
+                        Ok(ServerMessage::Disconnect(reason)) => {
+                            inner.handle_server_disconnect();
                             drop(inner);
                             self.handler.on_state_changed(self, State::Disconnecting);
-                            self.handler.on_disconnect(if kind.is_some() {
-                                DisconnectReason::Replaced
-                            } else {
-                                DisconnectReason::Removed
-                            });
+                            self.handler.on_disconnect(reason);
                         }
-                        Ok(ServerMessage::PresenceChange { jid, status, time }) => {
+                        Ok(ServerMessage::PresenceChange { jid, participant, status, time }) => {
                             drop(inner);
-                            let presence_change = UserData::PresenceChange(
-                                jid,
-                                status,
-                                time.and_then(|timestamp| if timestamp != 0 {
-                                    Some(NaiveDateTime::from_timestamp(timestamp, 0))
-                                } else {
-                                    None
-                                })
-                            );
+                            let time = time.and_then(|timestamp| if timestamp != 0 {
+                                Some(NaiveDateTime::from_timestamp(timestamp, 0))
+                            } else {
+                                None
+                            });
+                            let presence_change = if let Some(participant) = participant {
+                                UserData::GroupPresenceChange { group: jid, participant, status, time }
+                            } else {
+                                UserData::PresenceChange(jid, status, time)
+                            };
                             self.handler.on_user_data_changed(self, presence_change);
                         }
                         Ok(ServerMessage::MessageAck { message_id, level, sender, receiver, participant, time }) => {
-                            self.handler.on_user_data_changed(self, UserData::MessageAck(MessageAck::from_server_message(
-                                message_id,
-                                level,
-                                sender,
-                                receiver,
-                                participant,
-                                time,
-                                inner.user_jid.as_ref().unwrap()
-                            )))
+                            // Acks can only be classified once the own jid is known
+                            let user_data = match inner.user_jid.clone() {
+                                Some(own_jid) => {
+                                    let message_ack = MessageAck::from_server_message(
+                                        message_id,
+                                        level,
+                                        sender,
+                                        receiver,
+                                        participant,
+                                        time,
+                                        &own_jid
+                                    );
+                                    inner.correlate_message_ack(message_ack)
+                                }
+                                None => {
+                                    warn!("dropped ack received before login");
+                                    None
+                                }
+                            };
+                            drop(inner);
+                            if let Some(user_data) = user_data {
+                                self.handler.on_user_data_changed(self, user_data);
+                            }
                         }
                         Ok(ServerMessage::MessageAcks { message_ids, level, sender, receiver, participant, time }) => {
-                            for message_id in message_ids {
-                                self.handler.on_user_data_changed(self, UserData::MessageAck(MessageAck::from_server_message(
-                                    message_id,
-                                    level,
-                                    sender.clone(),
-                                    receiver.clone(),
-                                    participant.clone(),
-                                    time,
-                                    inner.user_jid.as_ref().unwrap()
-                                )))
+                            let mut user_datas = Vec::with_capacity(message_ids.len());
+                            if let Some(own_jid) = inner.user_jid.clone() {
+                                for message_id in message_ids {
+                                    let message_ack = MessageAck::from_server_message(
+                                        message_id,
+                                        level,
+                                        sender.clone(),
+                                        receiver.clone(),
+                                        participant.clone(),
+                                        time,
+                                        &own_jid
+                                    );
+                                    user_datas.extend(inner.correlate_message_ack(message_ack));
+                                }
+                            } else {
+                                warn!("dropped acks received before login");
+                            }
+                            drop(inner);
+                            for user_data in user_datas {
+                                self.handler.on_user_data_changed(self, user_data);
                             }
                         }
                         Ok(ServerMessage::GroupIntroduce { newly_created, inducer, meta }) => {
+                            inner.cache_group_metadata(meta.clone());
                             drop(inner);
                             self.handler.on_user_data_changed(self, UserData::GroupIntroduce { newly_created, inducer, meta });
                         }
                         Ok(ServerMessage::GroupParticipantsChange { group, change, inducer, participants }) => {
+                            let result = inner.update_cached_group_participants(&group, change, &participants);
                             drop(inner);
+                            self.handle_result(result);
                             self.handler.on_user_data_changed(self, UserData::GroupParticipantsChange { group, change, inducer, participants });
                         }
-                        _ => {}
+                        Ok(ServerMessage::GroupSubjectChange { group, subject, subject_time, subject_owner }) => {
+                            inner.update_cached_group_subject(&group, &subject, subject_time, &subject_owner);
+                            drop(inner);
+                            self.handler.on_user_data_changed(self, UserData::GroupSubjectChange { group, subject, subject_time, subject_owner });
+                        }
+                        _ => {
+                            let opcode = payload[0].as_str().unwrap_or("").to_string();
+                            if opcode == "Props" {
+                                json_protocol::parse_media_limits(&payload[1], &mut inner.media_limits);
+                            }
+                            drop(inner);
+                            self.handler.on_user_data_changed(self, UserData::ServerNotice { opcode, payload: payload[1].dump() });
+                        }
                     }
                 }
             }
             WebsocketMessagePayload::BinarySimple(encrypted_payload) => {
-                let payload = Node::deserialize(&inner.decrypt_binary_message(encrypted_payload).unwrap()).unwrap();
+                let decrypted_payload = match inner.decrypt_binary_message(encrypted_payload) {
+                    Ok(decrypted_payload) => decrypted_payload,
+                    Err(err) => {
+                        warn!("received invalid binary message: {:?}", err);
+                        return;
+                    }
+                };
+                let mut payload = match Node::deserialize(&decrypted_payload) {
+                    Ok(payload) => payload,
+                    Err(err) => {
+                        warn!("received invalid binary message: {:?}", err);
+                        return;
+                    }
+                };
                 debug!("received node: {:?}", &payload);
+                #[cfg(feature = "raw-nodes")]
+                inner.observe_frame(FrameDirection::Inbound, &payload);
 
-                if let Some(cb) = inner.requests.remove(message.tag.deref()) {
+                if let Some(connected) = inner.update_phone_connectivity(true) {
                     drop(inner);
-                    cb(WebsocketResponse::Node(payload), &self);
+                    self.handler.on_user_data_changed(self, UserData::PhoneConnectivity(connected));
+                    inner = self.lock_inner();
+                }
+
+                if let Some((_, cb)) = inner.requests.remove(message.tag.deref()) {
+                    drop(inner);
+                    cb(Ok(WebsocketResponse::Node(payload)), &self);
                 } else {
+                    if let Some(chunk_size) = inner.roster_chunk_size {
+                        if let Some(roster) = node_protocol::take_roster(&mut payload) {
+                            drop(inner);
+                            self.deliver_roster_chunks(roster, chunk_size);
+                            return;
+                        }
+                    }
                     match AppMessage::deserialize(payload) {
                         Ok(AppMessage::Contacts(contacts)) => {
+                            inner.initial_sync.contacts = true;
+                            let sync_complete = inner.initial_sync.check_complete();
                             drop(inner);
                             self.handler.on_user_data_changed(self, UserData::ContactsInitial(contacts));
+                            if sync_complete {
+                                self.handler.on_user_data_changed(self, UserData::SyncComplete);
+                            }
                         }
                         Ok(AppMessage::Chats(chats)) => {
+                            inner.chats = chats.iter().map(|chat| (chat.jid.to_string(), chat.clone())).collect();
+                            inner.initial_sync.chats = true;
+                            let sync_complete = inner.initial_sync.check_complete();
                             drop(inner);
                             self.handler.on_user_data_changed(self, UserData::Chats(chats));
+                            if sync_complete {
+                                self.handler.on_user_data_changed(self, UserData::SyncComplete);
+                            }
                         }
                         Ok(AppMessage::MessagesEvents(event_type, events)) => {
+                            if event_type == Some(MessageEventType::Last) {
+                                inner.initial_sync.messages = true;
+                            }
+                            let sync_complete = inner.initial_sync.check_complete();
                             drop(inner);
                             for event in events {
                                 match event {
-                                    AppEvent::Message(message) => self.handler.on_message(self, event_type == Some(MessageEventType::Relay), message),
-                                    AppEvent::MessageAck(message_ack) => self.handler.on_user_data_changed(self, UserData::MessageAck(message_ack)),
+                                    AppEvent::Message(message) => {
+                                        if event_type == Some(MessageEventType::Last) || event_type == Some(MessageEventType::Before) {
+                                            self.lock_inner().track_fetched_message(&message);
+                                        }
+                                        let user_data = match message.content {
+                                            ChatMessageContent::ProtocolMessage(_, ProtocolMessageKind::EphemeralSetting(duration)) => {
+                                                let chat = message.direction.reply_jid().clone();
+                                                let duration = if duration.as_secs() != 0 { Some(duration) } else { None };
+                                                Some(UserData::EphemeralSettingChanged { chat, duration })
+                                            }
+                                            ChatMessageContent::ProtocolMessage(ref key, ProtocolMessageKind::Revoke) => {
+                                                let (chat, by) = self.protocol_message_origin(&message.direction);
+                                                by.map(|by| UserData::MessageRevoked { chat, id: key.message_id(), by })
+                                            }
+                                            ChatMessageContent::ProtocolMessage(ref key, ProtocolMessageKind::MessageEdit(ref new_content)) => {
+                                                // The key is relative to the sender as well, so it references one of its own messages
+                                                // only if it is marked as sent by the sender
+                                                if !key.from_me {
+                                                    warn!("dropping edit of message {} of another sender", key.id);
+                                                    continue;
+                                                }
+                                                let (chat, by) = self.protocol_message_origin(&message.direction);
+                                                by.map(|by| UserData::MessageEdited { chat, id: key.message_id(), by, new_content: (**new_content).clone() })
+                                            }
+                                            _ => None
+                                        };
+                                        if let Some(user_data) = user_data {
+                                            self.handler.on_user_data_changed(self, user_data);
+                                        } else {
+                                            self.handler.on_message(self, event_type == Some(MessageEventType::Relay), message);
+                                        }
+                                    }
+                                    AppEvent::MessageAck(message_ack) => {
+                                        let user_data = self.lock_inner().correlate_message_ack(message_ack);
+                                        if let Some(user_data) = user_data {
+                                            self.handler.on_user_data_changed(self, user_data);
+                                        }
+                                    }
                                     AppEvent::ContactDelete(jid) => self.handler.on_user_data_changed(self, UserData::ContactDelete(jid)),
                                     AppEvent::ContactAddChange(contact) => self.handler.on_user_data_changed(self, UserData::ContactAddChange(contact)),
-                                    AppEvent::ChatAction(jid, action) => self.handler.on_user_data_changed(self, UserData::ChatAction(jid, action)),
-                                    AppEvent::Battery(level) => self.handler.on_user_data_changed(self, UserData::Battery(level)),
+                                    AppEvent::ChatAction(jid, action) => {
+                                        let chat = self.lock_inner().apply_chat_action(&jid, action);
+                                        self.handler.on_user_data_changed(self, UserData::ChatAction(jid, action));
+                                        if let Some(chat) = chat {
+                                            self.handler.on_user_data_changed(self, UserData::ChatUpdated(chat));
+                                        }
+                                    }
+                                    AppEvent::ChatMute { chat, muted, tone } => {
+                                        let action = if muted == Muted::No { ChatAction::Unmute } else { ChatAction::Mute(muted) };
+                                        let updated_chat = self.lock_inner().apply_chat_action(&chat, action);
+                                        self.handler.on_user_data_changed(self, UserData::ChatMuteChanged { chat, muted, tone });
+                                        if let Some(updated_chat) = updated_chat {
+                                            self.handler.on_user_data_changed(self, UserData::ChatUpdated(updated_chat));
+                                        }
+                                    }
+                                    AppEvent::ChatNameChange(jid, name) => {
+                                        let chat = self.lock_inner().rename_chat(&jid, &name);
+                                        self.handler.on_user_data_changed(self, UserData::ChatNameChange(jid, name));
+                                        if let Some(chat) = chat {
+                                            self.handler.on_user_data_changed(self, UserData::ChatUpdated(chat));
+                                        }
+                                    }
+                                    AppEvent::MessageStar { chat, id, starred } => {
+                                        self.handler.on_user_data_changed(self, UserData::MessageStarChanged { chat, id, starred })
+                                    }
+                                    AppEvent::Battery(level) => {
+                                        self.lock_inner().battery = Some(level);
+                                        self.handler.on_user_data_changed(self, UserData::Battery(level))
+                                    }
+                                    AppEvent::RetryRequested(message_id) => {
+                                        let result = self.lock_inner().handle_retry_request(&message_id);
+                                        self.handle_result(result);
+                                        self.handler.on_user_data_changed(self, UserData::RetryRequested(message_id))
+                                    }
                                     AppEvent::MessageRead { .. } => unreachable!(),
                                     AppEvent::MessagePlayed { .. } => unreachable!(),
                                     AppEvent::GroupCommand { .. } => unreachable!(),
+                                    AppEvent::BroadcastCommand { .. } => unreachable!(),
+                                    AppEvent::ContactPush { .. } => unreachable!(),
                                     AppEvent::PresenceChange(_, _) => unreachable!(),
                                     AppEvent::StatusChange(_) => unreachable!(),
                                     AppEvent::NotifyChange(_) => unreachable!(),
                                     AppEvent::BlockProfile { .. } => unreachable!(),
                                 }
                             }
+                            if sync_complete {
+                                self.handler.on_user_data_changed(self, UserData::SyncComplete);
+                            }
                         }
                         _ => {}
                     }
@@ -524,13 +1597,19 @@ impl<H: WhatsappWebHandler<H> + Send + Sync> WhatsappWebConnection<H> {
 
 
     pub fn send_message_played(&self, id: MessageId, peer: Peer) {
-        let mut inner = self.inner.lock().unwrap();
-        inner.epoch += 1;
-        let msg = AppMessage::MessagesEvents(Some(MessageEventType::Set), vec![AppEvent::MessagePlayed { id, peer }]);
+        self.mark_played(vec![id], Direction::Receiving(peer));
+    }
+
+    /// Mark the voice notes ```messages``` of the chat as played in a single frame,
+    /// ```direction``` is the one of the messages and determines whether they are owned by the own user
+    pub fn mark_played(&self, messages: Vec<MessageId>, direction: Direction) {
+        let events = messages.into_iter().map(|id| AppEvent::MessagePlayed { id, direction: direction.clone() }).collect();
+        let msg = AppMessage::MessagesEvents(Some(MessageEventType::Set), events);
         self.send_app_message(None, WebsocketMessageMetric::Received, msg, Box::new(|_, _| {}));
     }
 
     pub fn send_message_read(&self, id: MessageId, peer: Peer) {
+        self.lock_inner().track_read_message(&peer, &id);
         let msg = AppMessage::MessagesEvents(Some(MessageEventType::Set), vec![AppEvent::MessageRead { id, peer }]);
         self.send_app_message(None, WebsocketMessageMetric::Read, msg, Box::new(|_, _| {}));
     }
@@ -540,9 +1619,45 @@ impl<H: WhatsappWebHandler<H> + Send + Sync> WhatsappWebConnection<H> {
         self.send_app_message(None, WebsocketMessageMetric::Presence, msg, Box::new(|_, _| {}));
     }
 
-    pub fn set_status(&self, status: String) {
+    /// Show ```jid``` that a message is typed for ```duration```, afterwards ```Paused``` is sent automatically
+    pub fn typing(&self, jid: Jid, duration: Duration) {
+        let result = self.lock_inner().set_chat_state(jid, PresenceStatus::Typing, duration);
+        self.handle_result(result);
+    }
+
+    /// Show ```jid``` that a voice note is recorded for ```duration```, afterwards ```Paused``` is sent automatically
+    pub fn recording(&self, jid: Jid, duration: Duration) {
+        let result = self.lock_inner().set_chat_state(jid, PresenceStatus::Recording, duration);
+        self.handle_result(result);
+    }
+
+    /// Announce ```PresenceStatus::Available``` on connect and periodically afterwards,
+    /// so the phone keeps relaying messages to this client
+    pub fn set_presence_keepalive(&self, presence_keepalive: bool) {
+        let result = self.lock_inner().set_presence_keepalive(presence_keepalive);
+        self.handle_result(result);
+    }
+
+    /// Change the own status text, ```callback``` is called with the error if the server rejected the change
+    pub fn set_status(&self, status: String, callback: Option<Box<Fn(Result<()>) + Send + Sync>>) {
         let msg = AppMessage::MessagesEvents(Some(MessageEventType::Set), vec![AppEvent::StatusChange(status)]);
-        self.send_app_message(None, WebsocketMessageMetric::Status, msg, Box::new(|_, _| {}));
+        self.send_app_message(None, WebsocketMessageMetric::Status, msg, Box::new(move |response, _| {
+            if let Some(callback) = callback {
+                match response {
+                    Ok(WebsocketResponse::Json(json)) => callback(json_protocol::parse_response_status(&json)),
+                    Ok(WebsocketResponse::Node(_)) => callback(Ok(())),
+                    Err(err) => callback(Err(err))
+                }
+            }
+        }));
+    }
+
+    /// Query the status text of the own account
+    pub fn get_own_status(&self, callback: Box<Fn(Option<&str>) + Send + Sync>) {
+        match self.user_jid() {
+            Some(jid) => self.get_profile_status(&jid, callback),
+            None => callback(None)
+        }
     }
 
     pub fn set_notify_name(&self, name: String) {
@@ -560,88 +1675,487 @@ impl<H: WhatsappWebHandler<H> + Send + Sync> WhatsappWebConnection<H> {
         self.send_app_message(None, WebsocketMessageMetric::Chat, msg, Box::new(|_, _| {}));
     }
 
+    /// Delete the chat together with its messages on the phone
+    pub fn delete_chat(&self, chat: Jid) {
+        self.send_chat_action(ChatAction::Delete, chat);
+    }
+
+    /// Media exceeding the ```media_limits``` is rejected with an error passed to ```WhatsappWebHandler::on_error```
     pub fn send_message(&self, message_content: ChatMessageContent, jid: Jid) {
+        let result = self.try_send_message(message_content, jid).map(|_| ());
+        self.handle_result(result);
+    }
+
+    /// Send a message and return its id, media exceeding the ```media_limits``` is rejected with an error
+    pub fn try_send_message(&self, message_content: ChatMessageContent, jid: Jid) -> Result<MessageId> {
         let message_id = MessageId::generate();
+        let result = self.lock_inner().send_chat_message(message_id.clone(), jid, message_content);
+        self.flush_deferred();
+        result.map(|_| message_id)
+    }
 
-        let msg = AppMessage::MessagesEvents(Some(MessageEventType::Relay), vec![AppEvent::Message(Box::new(WhatsappMessage {
-            content: message_content,
-            time: Utc::now().naive_utc(),
-            direction: Direction::Sending(jid),
-            id: message_id.clone()
-        }))]);
-        self.send_app_message(Some(message_id.0), WebsocketMessageMetric::Message, msg, Box::new(|_, _| {}));
+    /// Limits of outgoing media as announced by the server
+    pub fn media_limits(&self) -> MediaLimits {
+        self.lock_inner().media_limits
     }
 
-    pub fn group_create(&self, subject: String, participants: Vec<Jid>) {
-        self.inner.lock().unwrap().send_group_command(GroupCommand::Create(subject), participants);
+    /// Replace the text of a message sent by this account to ```peer```
+    pub fn edit_message(&self, id: MessageId, peer: Peer, new_text: String) {
+        let jid = peer.chat_jid().clone();
+        let key = MessageKey::new(id, &Direction::Sending(jid.clone()));
+        let content = ChatMessageContent::ProtocolMessage(key, ProtocolMessageKind::MessageEdit(Box::new(ChatMessageContent::Text(new_text))));
+        self.send_message(content, jid);
     }
 
-    pub fn group_participants_change(&self, jid: Jid, participants_change: GroupParticipantsChange, participants: Vec<Jid>) {
-        self.inner.lock().unwrap().send_group_command(GroupCommand::ParticipantsChange(jid, participants_change), participants);
+    /// Send ```nodes``` which aren't modeled by this crate yet, e.g. calls or labels, within a ```set``` action
+    /// tagged with ```metric```. The nodes aren't checked against the ```media_limits```
+    #[cfg(feature = "raw-nodes")]
+    pub fn send_app_message_with_metric(&self, metric: WebsocketMessageMetric, nodes: Vec<Node<'static>>, callback: Box<Fn(Result<()>) + Send + Sync>) {
+        self.send_app_message(None, metric, AppMessage::Raw(nodes), Box::new(move |response, _| {
+            match response {
+                Ok(WebsocketResponse::Json(json)) => callback(json_protocol::parse_response_status(&json)),
+                Ok(WebsocketResponse::Node(_)) => callback(Ok(())),
+                Err(err) => callback(Err(err))
+            }
+        }));
+    }
+
+    /// Resend messages with the same id if the server didn't acknowledge them within ```window```,
+    /// e.g. because the connection dropped right after sending. After ```max_attempts``` resends
+    /// ```MessageSendFailed``` is emitted. Disabled by default, ```None``` disables it again
+    pub fn set_ack_resend(&self, window: Option<Duration>, max_attempts: u32) {
+        let mut inner = self.lock_inner();
+        let timer_armed = inner.ack_resend.as_ref().map_or(false, |ack_resend| ack_resend.timer_armed);
+        inner.ack_resend = window.map(|window| AckResend { window, max_attempts, pending: HashMap::new(), timer_armed });
+    }
+
+    /// Resend a message once if the recipient requests it again because it couldn't be decrypted
+    pub fn set_resend_on_retry(&self, resend_on_retry: bool) {
+        self.lock_inner().resend_on_retry = resend_on_retry;
+    }
+
+    /// Create a group, ```callback``` is called with the jid of the created group
+    pub fn group_create(&self, subject: String, participants: Vec<Jid>, callback: Box<Fn(Result<Jid>) + Send + Sync>) {
+        let result = self.lock_inner().send_group_command(GroupCommand::Create(subject), participants, Box::new(move |response, _| {
+            match response {
+                Ok(WebsocketResponse::Json(json)) => callback(json_protocol::parse_group_create_response(&json)),
+                Ok(WebsocketResponse::Node(_)) => callback(Err("unexpected node response".into())),
+                Err(err) => callback(Err(err))
+            }
+        }));
+        self.handle_result(result);
+    }
+
+    /// Push phonebook entries of jid and name to the phone, so the numbers can be messaged by their name.
+    /// Entries which already exist on the phone have to be pushed with ```update``` set
+    pub fn push_contacts(&self, entries: Vec<(Jid, String)>, update: bool, callback: Box<Fn(Result<()>) + Send + Sync>) {
+        let events = entries.into_iter().map(|(jid, name)| AppEvent::ContactPush { jid, name, update }).collect();
+        let msg = AppMessage::MessagesEvents(Some(MessageEventType::Set), events);
+        self.send_app_message(None, WebsocketMessageMetric::Contact, msg, Box::new(move |response, _| {
+            match response {
+                Ok(WebsocketResponse::Json(json)) => callback(json_protocol::parse_response_status(&json)),
+                Ok(WebsocketResponse::Node(_)) => callback(Ok(())),
+                Err(err) => callback(Err(err))
+            }
+        }));
+    }
+
+    /// Create a broadcast list named ```name```, ```callback``` is called with the jid of the created list
+    pub fn broadcast_list_create(&self, name: String, recipients: Vec<Jid>, callback: Box<Fn(Result<Jid>) + Send + Sync>) {
+        let result = self.lock_inner().send_broadcast_command(BroadcastCommand::Create(name), recipients, Box::new(move |response, _| {
+            match response {
+                Ok(WebsocketResponse::Json(json)) => callback(json_protocol::parse_broadcast_create_response(&json)),
+                Ok(WebsocketResponse::Node(node)) => callback(node_protocol::parse_broadcast_create_response(node)),
+                Err(err) => callback(Err(err))
+            }
+        }));
+        self.handle_result(result);
+    }
+
+    pub fn broadcast_list_add(&self, jid: Jid, recipients: Vec<Jid>, callback: Box<Fn(Result<()>) + Send + Sync>) {
+        if !jid.is_broadcast() {
+            return callback(Err("not a broadcast jid".into()));
+        }
+        self.send_broadcast_command(BroadcastCommand::Add(jid), recipients, callback);
+    }
+
+    pub fn broadcast_list_remove(&self, jid: Jid, recipients: Vec<Jid>, callback: Box<Fn(Result<()>) + Send + Sync>) {
+        if !jid.is_broadcast() {
+            return callback(Err("not a broadcast jid".into()));
+        }
+        self.send_broadcast_command(BroadcastCommand::Remove(jid), recipients, callback);
+    }
+
+    fn send_broadcast_command(&self, command: BroadcastCommand, recipients: Vec<Jid>, callback: Box<Fn(Result<()>) + Send + Sync>) {
+        let result = self.lock_inner().send_broadcast_command(command, recipients, Box::new(move |response, _| {
+            match response {
+                Ok(WebsocketResponse::Json(json)) => callback(json_protocol::parse_response_status(&json)),
+                Ok(WebsocketResponse::Node(_)) => callback(Ok(())),
+                Err(err) => callback(Err(err))
+            }
+        }));
+        self.handle_result(result);
+    }
+
+    pub fn get_broadcast_list_recipients(&self, jid: Jid, callback: Box<Fn(Result<Vec<Jid>>) + Send + Sync>) {
+        if !jid.is_broadcast() {
+            return callback(Err("not a broadcast jid".into()));
+        }
+        let msg = AppMessage::Query(Query::BroadcastRecipients(jid));
+        self.send_app_message(None, WebsocketMessageMetric::QueryGroup, msg, Box::new(move |response, _| {
+            match response {
+                Ok(WebsocketResponse::Node(node)) => callback(node_protocol::parse_broadcast_recipients_response(node)),
+                Ok(WebsocketResponse::Json(_)) => callback(Err("unexpected json response".into())),
+                Err(err) => callback(Err(err))
+            }
+        }));
+    }
+
+    /// Change the participants of a group, ```callback``` is called with the outcome for each participant.
+    /// Participants which can't be added due to their privacy settings are invited instead if enabled with ```set_invite_on_add_restriction```
+    pub fn group_participants_change(&self, jid: Jid, participants_change: GroupParticipantsChange, participants: Vec<Jid>,
+                                     callback: Box<Fn(Result<Vec<(Jid, ParticipantChangeStatus)>>) + Send + Sync>) {
+        let group = jid.clone();
+        let result = self.lock_inner().send_group_command(GroupCommand::ParticipantsChange(jid, participants_change), participants, Box::new(move |response, connection| {
+            let participants = match response {
+                Ok(WebsocketResponse::Json(json)) => json_protocol::parse_group_participants_change_response(&json),
+                Ok(WebsocketResponse::Node(_)) => Err("unexpected node response".into()),
+                Err(err) => Err(err)
+            };
+            if let Ok(ref participants) = participants {
+                connection.send_restricted_participant_invites(&group, participants);
+            }
+            callback(participants);
+        }));
+        self.handle_result(result);
+    }
+
+    /// Send a group invite message to participants which can't be added due to their privacy settings, disabled by default
+    pub fn set_invite_on_add_restriction(&self, enabled: bool) {
+        self.lock_inner().invite_on_add_restriction = enabled;
+    }
+
+    fn send_restricted_participant_invites(&self, group: &Jid, participants: &[(Jid, ParticipantChangeStatus)]) {
+        let mut inner = self.lock_inner();
+        if !inner.invite_on_add_restriction {
+            return;
+        }
+        let name = inner.group_metadata_cache.as_ref().and_then(|cache| cache.get(group)).map(|meta| meta.subject.clone()).unwrap_or_default();
+        let mut result = Ok(());
+        for &(ref participant, ref status) in participants {
+            if let ParticipantChangeStatus::InviteRequired { ref code, expiration } = *status {
+                let invite = ChatMessageContent::GroupInvite(GroupInvite {
+                    group: group.clone(),
+                    code: code.clone(),
+                    expiration,
+                    name: name.clone(),
+                    caption: String::new(),
+                    thumbnail: Vec::new()
+                });
+                result = inner.send_chat_message(MessageId::generate(), participant.clone(), invite);
+                if result.is_err() {
+                    break;
+                }
+            }
+        }
+        drop(inner);
+        self.handle_result(result);
     }
 
     pub fn get_messages_before(&self, jid: Jid, id: String, count: u16, callback: Box<Fn(Option<Vec<WhatsappMessage>>) + Send + Sync>) {
         let msg = AppMessage::Query(Query::MessagesBefore { jid, id, count });
-        self.send_app_message(None, WebsocketMessageMetric::QueryMessages, msg, Box::new(move |response, _| {
+        self.send_app_message(None, WebsocketMessageMetric::QueryMessages, msg, Box::new(move |response, connection| {
+            match response {
+                Ok(WebsocketResponse::Node(node)) => {
+                    let messages = node_protocol::parse_message_response(node).ok();
+                    if let Some(ref messages) = messages {
+                        let mut inner = connection.lock_inner();
+                        for message in messages {
+                            inner.track_fetched_message(message);
+                        }
+                    }
+                    callback(messages);
+                }
+                _ => callback(None)
+            }
+        }));
+    }
+
+    /// Query the ```count``` messages preceding the oldest message of the chat received so far.
+    /// ```callback``` is called with None if no message of the chat was received yet
+    pub fn next_page(&self, jid: Jid, count: u16, callback: Box<Fn(Option<Vec<WhatsappMessage>>) + Send + Sync>) {
+        match self.oldest_fetched_message(&jid) {
+            Some(id) => self.get_messages_before(jid, id.0, count, callback),
+            None => callback(None)
+        }
+    }
+
+    /// Oldest message of the chat received with the initial messages or ```get_messages_before```
+    pub fn oldest_fetched_message(&self, jid: &Jid) -> Option<MessageId> {
+        self.lock_inner().chat_pages.get(jid).and_then(|page| page.oldest_fetched.as_ref().map(|&(ref id, _)| id.clone()))
+    }
+
+    /// Last message of the chat marked as read by this connection or the app
+    pub fn last_read_message(&self, jid: &Jid) -> Option<MessageId> {
+        self.lock_inner().chat_pages.get(jid).and_then(|page| page.last_read.clone())
+    }
+
+    /// Query the list of chats, e.g. after the cache of the initial ```Chats``` was dropped
+    pub fn get_chats(&self, callback: Box<Fn(Result<Vec<Chat>>) + Send + Sync>) {
+        let msg = AppMessage::Query(Query::Chats);
+        self.send_app_message(None, WebsocketMessageMetric::QueryChat, msg, Box::new(move |response, connection| {
             match response {
-                WebsocketResponse::Node(node) => {
-                    callback(node_protocol::parse_message_response(node).ok());
+                Ok(WebsocketResponse::Node(node)) => {
+                    let chats = node_protocol::parse_chats_response(node);
+                    if let Ok(ref chats) = chats {
+                        connection.lock_inner().chats = chats.iter().map(|chat| (chat.jid.to_string(), chat.clone())).collect();
+                    }
+                    callback(chats);
                 }
-                _ => unimplemented!()
+                Ok(WebsocketResponse::Json(_)) => callback(Err("unexpected json response".into())),
+                Err(err) => callback(Err(err))
             }
         }));
     }
 
-    pub fn request_file_upload(&self, hash: &[u8], media_type: MediaType, callback: Box<Fn(Result<&str>) + Send + Sync>) {
+    /// Query the contacts, e.g. to refetch the roster which is otherwise only sent at login
+    pub fn get_contacts(&self, callback: Box<Fn(Result<Vec<Contact>>) + Send + Sync>) {
+        let msg = AppMessage::Query(Query::Contacts);
+        self.send_app_message(None, WebsocketMessageMetric::QueryContacts, msg, Box::new(move |response, _| {
+            match response {
+                Ok(WebsocketResponse::Node(node)) => callback(node_protocol::parse_contacts_response(node)),
+                Ok(WebsocketResponse::Json(_)) => callback(Err("unexpected json response".into())),
+                Err(err) => callback(Err(err))
+            }
+        }));
+    }
+
+    /// Let the server generate title, description and thumbnail of ```url```,
+    /// e.g. to attach them to a message containing the url
+    pub fn get_url_preview(&self, url: String, callback: Box<Fn(Result<UrlPreview>) + Send + Sync>) {
+        let msg = AppMessage::Query(Query::UrlPreview(url.clone()));
+        self.send_app_message(None, WebsocketMessageMetric::QueryUrl, msg, Box::new(move |response, _| {
+            match response {
+                Ok(WebsocketResponse::Node(node)) => callback(node_protocol::parse_url_preview_response(node, url)),
+                Ok(WebsocketResponse::Json(_)) => callback(Err("unexpected json response".into())),
+                Err(err) => callback(Err(err))
+            }
+        }));
+    }
+
+    /// Query the identity key of a contact, ```IdentityChanged``` is emitted if it differs from the previously queried key
+    pub fn get_identity(&self, jid: Jid, callback: Box<Fn(Result<Identity>) + Send + Sync>) {
+        let msg = AppMessage::Query(Query::Identity(jid.clone()));
+        self.send_app_message(None, WebsocketMessageMetric::QueryIdentity, msg, Box::new(move |response, connection| {
+            let identity = match response {
+                Ok(WebsocketResponse::Node(node)) => node_protocol::parse_identity_response(node, jid),
+                Ok(WebsocketResponse::Json(_)) => Err("unexpected json response".into()),
+                Err(err) => Err(err)
+            };
+            if let Ok(ref identity) = identity {
+                let previous_key = connection.lock_inner().identities.insert(identity.jid.clone(), identity.key.clone());
+                if previous_key.map_or(false, |previous_key| previous_key != identity.key) {
+                    connection.handler.on_user_data_changed(connection, UserData::IdentityChanged(identity.clone()));
+                }
+            }
+            callback(identity);
+        }));
+    }
+
+    /// Send ```query``` and call ```callback``` with the raw response
+    pub fn query_json(&self, query: JsonQuery, callback: Box<Fn(Result<JsonValue>) + Send + Sync>) {
+        self.send_json_message(json_protocol::build_query(query), Box::new(move |response, _| callback(response)));
+    }
+
+    pub fn request_file_upload(&self, hash: &[u8], media_type: MediaType, callback: Box<FnOnce(Result<&str>) + Send>) {
         self.send_json_message(json_protocol::build_file_upload_request(hash, media_type), Box::new(move |response, _| {
-            callback(json_protocol::parse_file_upload_response(&response));
+            match response {
+                Ok(response) => callback(json_protocol::parse_file_upload_response(&response)),
+                Err(err) => callback(Err(err))
+            }
+        }));
+    }
+
+    /// Query the media connection info used for uploads, it is cached until its ttl expires
+    /// and refreshed in the background shortly before
+    pub fn get_media_conn(&self, callback: Box<FnOnce(Result<MediaConn>) + Send>) {
+        let cached = self.lock_inner().media_conn.as_ref()
+            .and_then(|&(fetched, ref media_conn)| if fetched.elapsed() < media_conn.ttl { Some(media_conn.clone()) } else { None });
+        if let Some(media_conn) = cached {
+            return callback(Ok(media_conn));
+        }
+
+        self.query_media_conn(callback);
+    }
+
+    fn query_media_conn(&self, callback: Box<FnOnce(Result<MediaConn>) + Send>) {
+        self.send_json_message(json_protocol::build_query(JsonQuery::MediaConn), Box::new(move |response, connection| {
+            let media_conn = response.and_then(|response| json_protocol::parse_media_conn_response(&response));
+            if let Ok(ref media_conn) = media_conn {
+                let mut inner = connection.lock_inner();
+                inner.media_conn = Some((Instant::now(), media_conn.clone()));
+                if let WebsocketState::Connected(ref sender, _) = inner.websocket_state {
+                    sender.timeout(duration_millis(media_conn_refresh_delay(media_conn.ttl)), timeout::MEDIA_CONN_REFRESH_TOKEN).ok();
+                }
+            }
+            callback(media_conn);
         }));
     }
 
+    /// Requery the media connection info if it is due, a newer query may have armed another refresh in the meantime
+    fn refresh_media_conn(&self) {
+        let due = self.lock_inner().media_conn.as_ref()
+            .map_or(false, |&(fetched, ref media_conn)| fetched.elapsed() >= media_conn_refresh_delay(media_conn.ttl));
+        if due {
+            self.query_media_conn(Box::new(|media_conn| if let Err(err) = media_conn {
+                error!("could not refresh media connection {:?}", err);
+            }));
+        }
+    }
+
+    /// Drop the cached media connection info, e.g. after the media host rejected its auth
+    pub(crate) fn invalidate_media_conn(&self) {
+        self.lock_inner().media_conn = None;
+    }
+
     pub fn get_profile_picture(&self, jid: &Jid, callback: Box<Fn(Option<&str>) + Send + Sync>) {
-        self.send_json_message(json_protocol::build_profile_picture_request(jid), Box::new(move |response, _| {
-            callback(json_protocol::parse_profile_picture_response(&response));
+        self.send_json_message(json_protocol::build_query(JsonQuery::ProfilePicThumb(jid.clone())), Box::new(move |response, _| {
+            let response = response.ok();
+            callback(response.as_ref().and_then(json_protocol::parse_profile_picture_response));
+        }));
+    }
+
+    /// Query the full-size profile picture, ```get_profile_picture``` only returns the thumbnail
+    pub fn get_profile_picture_full(&self, jid: &Jid, callback: Box<Fn(Option<ProfilePicture>) + Send + Sync>) {
+        self.send_json_message(json_protocol::build_query(JsonQuery::ProfilePic(jid.clone())), Box::new(move |response, _| {
+            callback(response.ok().as_ref().and_then(json_protocol::parse_profile_picture_full_response));
         }));
     }
 
     pub fn get_profile_status(&self, jid: &Jid, callback: Box<Fn(Option<&str>) + Send + Sync>) {
-        self.send_json_message(json_protocol::build_profile_status_request(jid), Box::new(move |response, _| {
-            callback(json_protocol::parse_profile_status_response(&response));
+        self.send_json_message(json_protocol::build_query(JsonQuery::Status(jid.clone())), Box::new(move |response, _| {
+            let response = response.ok();
+            callback(response.as_ref().and_then(json_protocol::parse_profile_status_response));
         }));
     }
 
     pub fn get_group_metadata(&self, jid: &Jid, callback: Box<Fn(Option<GroupMetadata>) + Send + Sync>) {
-        debug_assert!(jid.is_group);
-        self.send_json_message(json_protocol::build_group_metadata_request(jid), Box::new(move |response, _| {
-            callback(json_protocol::parse_group_metadata_response(&response).ok());
+        debug_assert!(jid.is_group());
+        self.send_json_message(json_protocol::build_query(JsonQuery::GroupMetadata(jid.clone())), Box::new(move |response, connection| {
+            let meta = response.and_then(|response| json_protocol::parse_group_metadata_response(&response)).ok();
+            if let Some(ref meta) = meta {
+                connection.lock_inner().cache_group_metadata(meta.clone());
+            }
+            callback(meta);
+        }));
+    }
+
+    /// Install or remove the tap receiving every node sent or received, e.g. to analyze the protocol
+    #[cfg(feature = "raw-nodes")]
+    pub fn set_frame_observer(&self, frame_observer: Option<FrameObserver>) {
+        self.lock_inner().frame_observer = frame_observer;
+    }
+
+    /// Hand out the initial contacts and chats in chunks of at most ```chunk_size``` entries
+    /// as ```ContactsChunk``` and ```ChatsChunk``` instead of at once as ```ContactsInitial``` and ```Chats```.
+    /// Disabled by default. The received frame is still decoded into nodes at once, only the conversion
+    /// to ```Contact``` and ```Chat``` happens chunk by chunk, so the parsed entries are never all held at the same time
+    pub fn set_roster_chunk_size(&self, chunk_size: Option<usize>) {
+        self.lock_inner().roster_chunk_size = chunk_size.map(|chunk_size| chunk_size.max(1));
+    }
+
+    /// Enable or disable aggregation of acks, disabled by default.
+    /// If enabled acks which are sent redundantly, e.g. after a reconnect, are dropped
+    /// and only acks raising the level of a message are delivered
+    pub fn set_ack_aggregation(&self, enabled: bool) {
+        let mut inner = self.lock_inner();
+        if !enabled {
+            inner.ack_levels = None;
+        } else if inner.ack_levels.is_none() {
+            inner.ack_levels = Some((HashMap::new(), VecDeque::new()));
+        }
+    }
+
+    /// Enable or disable caching of group metadata, disabled by default.
+    /// Groups are cached once they were requested with ```get_group_metadata``` or introduced by the server
+    /// and are kept up to date with the changes of their participants and subject
+    pub fn set_group_metadata_cache(&self, enabled: bool) {
+        let mut inner = self.lock_inner();
+        if !enabled {
+            inner.group_metadata_cache = None;
+        } else if inner.group_metadata_cache.is_none() {
+            inner.group_metadata_cache = Some(HashMap::new());
+        }
+    }
+
+    /// Cached metadata of the group, see ```set_group_metadata_cache```
+    pub fn group(&self, jid: &Jid) -> Option<GroupMetadata> {
+        self.lock_inner().group_metadata_cache.as_ref().and_then(|cache| cache.get(jid).cloned())
+    }
+
+    /// Join the group of the invite, the callback receives the jid of the joined group
+    pub fn accept_group_invite(&self, invite: &GroupInvite, callback: Box<Fn(Result<Jid>) + Send + Sync>) {
+        if invite.expiration.map_or(false, |expiration| expiration < Utc::now().naive_utc()) {
+            return callback(Err("invite expired".into()));
+        }
+        self.send_json_message(json_protocol::build_accept_group_invite_request(&invite.code), Box::new(move |response, _| {
+            callback(response.and_then(|response| json_protocol::parse_accept_group_invite_response(&response)));
         }));
     }
 
     fn ws_connect(&self) -> JoinHandle<()> {
         let whatsapp_connection = self.clone();
-        thread::spawn(move || loop {
+        thread::Builder::new().name("whatsappweb-websocket".to_string()).spawn(move || loop {
             let last_try = SystemTime::now();
             let whatsapp_connection1 = whatsapp_connection.clone();
-            ws::connect(ENDPOINT_URL, move |out| {
-                whatsapp_connection1.inner.lock().unwrap().ws_on_connected(out);
+            let result = ws::connect(ENDPOINT_URL, move |out| {
+                let result = whatsapp_connection1.lock_inner().ws_on_connected(out);
+                whatsapp_connection1.handle_result(result);
                 WsHandler {
                     whatsapp_connection: whatsapp_connection1.clone()
                 }
-            }).unwrap();
+            }).chain_err(|| "could not connect");
+            whatsapp_connection.handle_result(result);
 
-            if let SessionState::Teardown = whatsapp_connection.inner.lock().unwrap().session_state {
+            if let SessionState::Teardown = whatsapp_connection.lock_inner().session_state {
                 break
             }
             let duration = SystemTime::now().duration_since(last_try).unwrap_or_else(|_|Duration::new(0, 0));
             if let Some(duration) = Duration::new(10, 0).checked_sub(duration) { 
                 thread::sleep(duration);
             }
-        })
+        }).expect("could not spawn websocket thread")
+    }
+
+    /// Run ```job``` on the media worker pool of this connection, jobs are queued while all workers are busy
+    pub(crate) fn execute_media_job<F: FnOnce() + Send + 'static>(&self, job: F) {
+        WorkerPool::execute_counted(&self.media_workers, &self.media_jobs, job);
+    }
+
+    #[cfg(feature = "media")]
+    pub(crate) fn http_client(&self) -> reqwest::Client {
+        self.lock_inner().options.http_client.client.clone().unwrap_or_else(media::shared_http_client)
+    }
+
+    /// Set the maximum number of threads transferring media files concurrently, defaults to ```DEFAULT_MEDIA_WORKERS```.
+    /// Connections of a ```ConnectionManager``` share their workers, so this changes them for all accounts
+    pub fn set_media_workers(&self, workers: usize) {
+        WorkerPool::set_max_workers(&self.media_workers, workers);
+    }
+
+    /// Disconnect and wait until the websocket thread and the queued media transfers of this connection finished.
+    /// Transfers of other connections sharing the media workers, e.g. of a ```ConnectionManager```, aren't waited for.
+    /// ```join_handle``` is the handle returned together with the connection
+    pub fn shutdown_and_join(&self, join_handle: JoinHandle<()>) -> Result<()> {
+        self.ws_disconnect();
+        let result = join_handle.join().map_err(|_| "websocket thread panicked".into());
+        self.media_jobs.wait();
+        result
     }
 
     pub fn ws_disconnect(&self) {
         self.handler.on_state_changed(self, State::Disconnecting);
-        let mut inner = self.inner.lock().unwrap();
+        let mut inner = self.lock_inner();
         inner.session_state = SessionState::Teardown;
         if let WebsocketState::Connected(ref out, ref mut timeout_manager) = inner.websocket_state {
             out.close(CloseCode::Normal).ok();
@@ -650,16 +2164,55 @@ impl<H: WhatsappWebHandler<H> + Send + Sync> WhatsappWebConnection<H> {
 
     }
 
+    /// Subscribe to the presence of ```jid```, the result is delivered as ```UserData::PresenceSubscription```.
+    /// The subscription is renewed after reconnects
     pub fn subscribe_presence(&self, jid: &Jid) {
-        self.send_json_message(json_protocol::build_presence_subscribe(jid), Box::new(|_, _| {}));
+        self.subscribe_presence_many(&[jid.clone()]);
+    }
+
+    /// Subscribe to the presence of all ```jids```, see ```subscribe_presence```.
+    /// The subscriptions are queued and sent in batches, so the server doesn't throttle the connection
+    pub fn subscribe_presence_many(&self, jids: &[Jid]) {
+        let result = self.lock_inner().subscribe_presences(jids);
+        self.handle_result(result);
+    }
+
+    /// Stop renewing the subscription of ```jid```, see ```unsubscribe_presence_many```
+    pub fn unsubscribe_presence(&self, jid: &Jid) {
+        self.unsubscribe_presence_many(&[jid.clone()]);
+    }
+
+    /// Stop renewing the subscriptions of ```jids``` and drop them if they weren't sent yet.
+    /// The server keeps relaying the presences of sent subscriptions until the next reconnect
+    pub fn unsubscribe_presence_many(&self, jids: &[Jid]) {
+        self.lock_inner().unsubscribe_presences(jids);
     }
 
     pub fn state(&self) -> State {
-        match self.inner.lock().unwrap().session_state {
-            SessionState::PendingNew { .. } => State::Uninitialized,
-            SessionState::PendingPersistent { .. } => State::Reconnecting,
-            SessionState::Established { .. } => State::Connected,
-            SessionState::Teardown => State::Disconnecting
+        if self.is_broken() {
+            return State::Broken;
+        }
+        self.lock_inner().state()
+    }
+
+    /// Jid of the own user, known once the connection is established
+    pub fn user_jid(&self) -> Option<Jid> {
+        self.lock_inner().user_jid.clone()
+    }
+
+    pub fn client_id(&self) -> Option<[u8; 8]> {
+        self.lock_inner().client_id()
+    }
+
+    pub fn info(&self) -> ConnectionInfo {
+        let inner = self.lock_inner();
+        ConnectionInfo {
+            state: inner.state(),
+            user_jid: inner.user_jid.clone(),
+            push_name: inner.push_name.clone(),
+            client_id: inner.client_id(),
+            battery: inner.battery,
+            phone: inner.phone_info.clone()
         }
     }
 }
@@ -673,48 +2226,322 @@ impl<H: WhatsappWebHandler<H> + Send + Sync + 'static> Handler for WsHandler<H>
     fn build_request(&mut self, url: &Url) -> ws::Result<Request> {
         trace!("Handler is building request to {}.", url);
         let mut request = Request::from_url(url)?;
-        request.headers_mut().push(("Origin".to_string(), b"https://web.whatsapp.com".to_vec()));
+        let inner = self.whatsapp_connection.lock_inner();
+        let headers = request.headers_mut();
+        headers.push(("Origin".to_string(), inner.options.origin.as_bytes().to_vec()));
+        if let Some(ref user_agent) = inner.options.user_agent {
+            headers.push(("User-Agent".to_string(), user_agent.as_bytes().to_vec()));
+        }
+        for &(ref name, ref value) in &inner.options.headers {
+            headers.push((name.clone(), value.as_bytes().to_vec()));
+        }
         Ok(request)
     }
 
     fn on_message(&mut self, msg: Message) -> ws::Result<()> {
         debug!("Received message {:?}", msg);
-        self.whatsapp_connection.ws_on_message(&msg);
+        let whatsapp_connection = &self.whatsapp_connection;
+        whatsapp_connection.catch_handler_panic(|| {
+            whatsapp_connection.ws_on_message(&msg);
+            whatsapp_connection.flush_deferred();
+        });
         Ok(())
     }
     fn on_timeout(&mut self, event: Token) -> ws::Result<()> {
-        let mut inner = self.whatsapp_connection.inner.lock().unwrap();
-        inner.on_timeout(event);
+        let whatsapp_connection = &self.whatsapp_connection;
+        if event == timeout::ACK_RESEND_TOKEN {
+            whatsapp_connection.catch_handler_panic(|| {
+                let failed_messages = whatsapp_connection.lock_inner().resend_unacked_messages();
+                for failed_message in failed_messages {
+                    whatsapp_connection.handler.on_user_data_changed(whatsapp_connection, UserData::MessageSendFailed(failed_message));
+                }
+            });
+            return Ok(());
+        }
+        if event == timeout::MEDIA_CONN_REFRESH_TOKEN {
+            whatsapp_connection.refresh_media_conn();
+            return Ok(());
+        }
+        whatsapp_connection.lock_inner().on_timeout(event);
+        whatsapp_connection.catch_handler_panic(|| whatsapp_connection.flush_deferred());
         Ok(())
     }
 
     fn on_new_timeout(&mut self, event: Token, timeout: Timeout) -> ws::Result<()> {
-        if let WebsocketState::Connected(_, ref mut timeout_manager) = self.whatsapp_connection.inner.lock().unwrap().websocket_state {
+        let mut inner = self.whatsapp_connection.lock_inner();
+        if event == timeout::PRESENCE_KEEPALIVE_TOKEN {
+            inner.presence_keepalive_timeout = Some(timeout);
+        } else if let WebsocketState::Connected(_, ref mut timeout_manager) = inner.websocket_state {
             timeout_manager.on_new_timeout(event, timeout);
         }
         Ok(())
     }
     fn on_close(&mut self, _: CloseCode, _: &str) {
-        self.whatsapp_connection.ws_on_disconnected();
+        let whatsapp_connection = &self.whatsapp_connection;
+        whatsapp_connection.catch_handler_panic(|| whatsapp_connection.ws_on_disconnected());
+    }
+}
+
+/// Version of the ```PersistentSession``` layout written by this crate.
+/// New fields are only appended, so older versions of the crate can still read the fields they know
+pub const PERSISTENT_SESSION_VERSION: u32 = 2;
+const PERSISTENT_SESSION_MAGIC: &[u8] = b"WAPS";
+
+/// Parameters of the websocket connection, e.g. for deployments behind a gateway requiring additional headers
+#[derive(Debug, Clone)]
+pub struct ConnectionOptions {
+    /// Value of the ```Origin``` header, the server rejects other origins than the web client
+    pub origin: String,
+    pub user_agent: Option<String>,
+    /// Additional headers sent with the websocket handshake
+    pub headers: Vec<(String, String)>,
+    /// Client used for media uploads and downloads, e.g. configured with a proxy.
+    /// By default a client shared by all connections is used
+    pub http_client: HttpClient,
+}
+
+/// Client used for media transfers, it is unused without the ```media``` feature
+#[derive(Debug, Clone, Default)]
+pub struct HttpClient {
+    #[cfg(feature = "media")]
+    client: Option<reqwest::Client>,
+}
+
+impl HttpClient {
+    #[cfg(feature = "media")]
+    pub fn new(client: reqwest::Client) -> HttpClient {
+        HttpClient { client: Some(client) }
+    }
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> ConnectionOptions {
+        ConnectionOptions {
+            origin: "https://web.whatsapp.com".to_string(),
+            user_agent: None,
+            headers: Vec::new(),
+            http_client: HttpClient::default()
+        }
     }
 }
 
 /// Stores the parameters to login without scanning the qrcode again.
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct PersistentSession {
+    pub version: u32,
     pub client_token: String,
     pub server_token: String,
     pub client_id: [u8; 8],
     pub enc: [u8; 32],
-    pub mac: [u8; 32]
+    pub mac: [u8; 32],
+    /// Epoch of the last app message sent within this session. It is continued after reconnects and restores,
+    /// as the server drops action frames with an epoch it has already seen.
+    /// The stored counters are reserved ahead of the used ones and the session is handed out again
+    /// before they are used up, so a session restored after a crash doesn't reuse them either
+    pub epoch: u32,
+    /// Counter of the tags of the last sent message, continued like ```epoch```
+    pub tag_counter: u32
+}
+
+/// Layout of version 1, which didn't store the counters
+#[derive(Deserialize)]
+struct PersistentSessionV1 {
+    #[allow(dead_code)]
+    version: u32,
+    client_token: String,
+    server_token: String,
+    client_id: [u8; 8],
+    enc: [u8; 32],
+    mac: [u8; 32]
+}
+
+impl From<PersistentSessionV1> for PersistentSession {
+    fn from(session: PersistentSessionV1) -> PersistentSession {
+        PersistentSession {
+            version: PERSISTENT_SESSION_VERSION,
+            client_token: session.client_token,
+            server_token: session.server_token,
+            client_id: session.client_id,
+            enc: session.enc,
+            mac: session.mac,
+            epoch: 0,
+            tag_counter: 0
+        }
+    }
+}
+
+/// Layout of sessions stored before ```PersistentSession``` was versioned
+#[derive(Deserialize)]
+struct UnversionedPersistentSession {
+    client_token: String,
+    server_token: String,
+    client_id: [u8; 8],
+    enc: [u8; 32],
+    mac: [u8; 32]
+}
+
+impl From<UnversionedPersistentSession> for PersistentSession {
+    fn from(session: UnversionedPersistentSession) -> PersistentSession {
+        PersistentSession {
+            version: PERSISTENT_SESSION_VERSION,
+            client_token: session.client_token,
+            server_token: session.server_token,
+            client_id: session.client_id,
+            enc: session.enc,
+            mac: session.mac,
+            epoch: 0,
+            tag_counter: 0
+        }
+    }
+}
+
+impl PersistentSession {
+    /// Serialize the session with bincode, prefixed by a marker to distinguish it from the unversioned layout
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let mut data = PERSISTENT_SESSION_MAGIC.to_vec();
+        bincode::serialize_into(&mut data, self)?;
+        Ok(data)
+    }
+
+    /// Deserialize a session written by ```serialize``` of this or any other version,
+    /// or a plain bincode serialized session of the unversioned layout
+    pub fn deserialize(data: &[u8]) -> Result<PersistentSession> {
+        if data.starts_with(PERSISTENT_SESSION_MAGIC) {
+            let data = &data[PERSISTENT_SESSION_MAGIC.len()..];
+            let version: u32 = bincode::deserialize(data)?;
+            if version < 2 {
+                return Ok(bincode::deserialize::<PersistentSessionV1>(data)?.into());
+            }
+            // Fields appended by newer versions are trailing and therefore ignored
+            let mut session: PersistentSession = bincode::deserialize(data)?;
+            if session.version > PERSISTENT_SESSION_VERSION {
+                warn!("session of newer version {} is downgraded to {}", session.version, PERSISTENT_SESSION_VERSION);
+                session.version = PERSISTENT_SESSION_VERSION;
+            }
+            Ok(session)
+        } else {
+            Ok(bincode::deserialize::<UnversionedPersistentSession>(data)?.into())
+        }
+    }
+
+    /// Store the session encrypted with a key derived from ```passphrase```
+    pub fn save_encrypted<P: AsRef<Path>>(&self, path: P, passphrase: &str) -> Result<()> {
+        let encrypted = crypto::encrypt_with_passphrase(passphrase, &self.serialize()?)?;
+        File::create(path)?.write_all(&encrypted)?;
+        Ok(())
+    }
+
+    /// Load a session stored by ```save_encrypted```
+    pub fn load_encrypted<P: AsRef<Path>>(path: P, passphrase: &str) -> Result<PersistentSession> {
+        let mut encrypted = Vec::new();
+        File::open(path)?.read_to_end(&mut encrypted)?;
+        PersistentSession::deserialize(&crypto::decrypt_with_passphrase(passphrase, &encrypted)?)
+    }
 }
 
 const ENDPOINT_URL: &str = "wss://w7.web.whatsapp.com/ws";
+/// Requests without response are dropped, starting with the oldest, once this limit is exceeded
+const MAX_PENDING_REQUESTS: usize = 512;
+/// Number of epochs and tags reserved at once, the session is handed to the handler again once they are used up
+const COUNTER_RESERVATION: u32 = 100;
+/// Number of presence subscriptions sent at once, the others are sent after ```PRESENCE_SUBSCRIBE_INTERVAL```
+const PRESENCE_SUBSCRIBE_BATCH: usize = 10;
+/// Number of messages whose ack level is remembered for aggregation
+const MAX_TRACKED_ACKS: usize = 4096;
+/// Number of sent messages kept for resending, the oldest ones are forgotten beyond this limit
+const MAX_SENT_MESSAGES: usize = 1024;
+/// The scratch buffer isn't kept if it grew beyond this capacity, e.g. while sending media
+const MAX_SCRATCH_BUFFER_CAPACITY: usize = 64 * 1024;
+/// Largest qr-code version, the login qr-code is retried with every version up to it
+#[cfg(feature = "qr")]
+const MAX_QR_VERSION: i16 = 40;
+
+fn duration_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_nanos() / 1_000_000)
+}
+
+/// Lower bound of the media connection refresh, so a tiny ttl doesn't cause a query loop
+const MIN_MEDIA_CONN_REFRESH_DELAY: Duration = Duration::from_secs(60);
+
+/// The media connection info is refreshed once 90% of its ttl elapsed, but at most once a minute
+fn media_conn_refresh_delay(ttl: Duration) -> Duration {
+    (ttl - ttl / 10).max(MIN_MEDIA_CONN_REFRESH_DELAY)
+}
+
+fn take_scratch_buffer(buffer: &mut Vec<u8>) -> Vec<u8> {
+    let mut buffer = mem::replace(buffer, Vec::new());
+    buffer.clear();
+    buffer
+}
+
+fn return_scratch_buffer(slot: &mut Vec<u8>, buffer: Vec<u8>) {
+    if buffer.capacity() <= MAX_SCRATCH_BUFFER_CAPACITY {
+        *slot = buffer;
+    }
+}
 
 /// Create new connection and session.
 /// Will eventual call ```qr_cb``` with the generated qr-code.
+#[cfg(feature = "qr")]
 pub fn new<Q: Fn(QrCode) + Send + 'static, H: WhatsappWebHandler<H> + Send + Sync + 'static>(qr_cb: Q, handler: H) -> (WhatsappWebConnection<H>, JoinHandle<()>) {
-    let whatsapp_connection = WhatsappWebConnection::new(Box::new(qr_cb), handler);
+    new_with_options(qr_cb, ConnectionOptions::default(), handler)
+}
+
+/// Create new connection and session using the given ```options```.
+/// Will eventual call ```qr_cb``` with the generated qr-code.
+#[cfg(feature = "qr")]
+pub fn new_with_options<Q: Fn(QrCode) + Send + 'static, H: WhatsappWebHandler<H> + Send + Sync + 'static>(qr_cb: Q, options: ConnectionOptions, handler: H) -> (WhatsappWebConnection<H>, JoinHandle<()>) {
+    new_with_qr_string_callback(qr_code_callback(qr_cb), options, handler, default_media_workers())
+}
+
+#[cfg(feature = "qr")]
+pub(crate) fn qr_code_callback<Q: Fn(QrCode) + Send + 'static>(qr_cb: Q) -> Box<Fn(String) -> Result<()> + Send> {
+    Box::new(move |qr_string: String| {
+        generate_qr_code(&qr_string).map(|qr_code| qr_cb(qr_code))
+    })
+}
+
+/// Encode ```qr_string``` with the default error correction level, retrying with the next larger
+/// qr-code version until the content fits
+#[cfg(feature = "qr")]
+fn generate_qr_code(qr_string: &str) -> Result<QrCode> {
+    let mut last_error = None;
+    for version in 1..(MAX_QR_VERSION + 1) {
+        match QrCode::with_version(qr_string, Version::Normal(version), EcLevel::M) {
+            Ok(qr_code) => return Ok(qr_code),
+            Err(err) => last_error = Some(err)
+        }
+    }
+    Err(ErrorKind::QrCode(format!("{:?}", last_error.unwrap())).into())
+}
+
+/// Create new connection and session.
+/// Will eventual call ```qr_cb``` with the content of the qr-code, which has to be rendered by the caller.
+pub fn new_with_qr_string<Q: Fn(String) + Send + 'static, H: WhatsappWebHandler<H> + Send + Sync + 'static>(qr_cb: Q, handler: H) -> (WhatsappWebConnection<H>, JoinHandle<()>) {
+    new_with_qr_string_and_options(qr_cb, ConnectionOptions::default(), handler)
+}
+
+/// Create new connection and session using the given ```options```.
+/// Will eventual call ```qr_cb``` with the content of the qr-code, which has to be rendered by the caller.
+pub fn new_with_qr_string_and_options<Q: Fn(String) + Send + 'static, H: WhatsappWebHandler<H> + Send + Sync + 'static>(qr_cb: Q, options: ConnectionOptions, handler: H) -> (WhatsappWebConnection<H>, JoinHandle<()>) {
+    new_with_qr_string_callback(qr_string_callback(qr_cb), options, handler, default_media_workers())
+}
+
+pub(crate) fn qr_string_callback<Q: Fn(String) + Send + 'static>(qr_cb: Q) -> Box<Fn(String) -> Result<()> + Send> {
+    Box::new(move |qr_string: String| {
+        qr_cb(qr_string);
+        Ok(())
+    })
+}
+
+pub(crate) fn default_media_workers() -> Arc<WorkerPool> {
+    Arc::new(WorkerPool::new("whatsappweb-media", DEFAULT_MEDIA_WORKERS))
+}
+
+/// Create a new connection and session whose media transfers run on ```media_workers```
+pub(crate) fn new_with_qr_string_callback<H: WhatsappWebHandler<H> + Send + Sync + 'static>(qr_cb: Box<Fn(String) -> Result<()> + Send>, options: ConnectionOptions, handler: H,
+                                                                                              media_workers: Arc<WorkerPool>) -> (WhatsappWebConnection<H>, JoinHandle<()>) {
+    let whatsapp_connection = WhatsappWebConnection::new(qr_cb, options, handler, media_workers);
 
     let join_handle = whatsapp_connection.ws_connect();
 
@@ -723,9 +2550,213 @@ pub fn new<Q: Fn(QrCode) + Send + 'static, H: WhatsappWebHandler<H> + Send + Syn
 
 /// Create new connection and restore the session with the given ```persistent_session```.
 pub fn with_persistent_session<H: WhatsappWebHandler<H> + Send + Sync + 'static>(persistent_session: PersistentSession, handler: H) -> (WhatsappWebConnection<H>, JoinHandle<()>) {
-    let whatsapp_connection = WhatsappWebConnection::with_persistent_session(persistent_session, handler);
+    with_persistent_session_and_options(persistent_session, ConnectionOptions::default(), handler)
+}
+
+/// Create new connection using the given ```options``` and restore the session with the given ```persistent_session```.
+pub fn with_persistent_session_and_options<H: WhatsappWebHandler<H> + Send + Sync + 'static>(persistent_session: PersistentSession, options: ConnectionOptions,
+                                                                                             handler: H) -> (WhatsappWebConnection<H>, JoinHandle<()>) {
+    with_persistent_session_and_workers(persistent_session, options, handler, default_media_workers())
+}
+
+/// Restore the session with the given ```persistent_session```, media transfers run on ```media_workers```
+pub(crate) fn with_persistent_session_and_workers<H: WhatsappWebHandler<H> + Send + Sync + 'static>(persistent_session: PersistentSession, options: ConnectionOptions,
+                                                                                                     handler: H, media_workers: Arc<WorkerPool>) -> (WhatsappWebConnection<H>, JoinHandle<()>) {
+    let whatsapp_connection = WhatsappWebConnection::with_persistent_session(persistent_session, options, handler, media_workers);
 
     let join_handle = whatsapp_connection.ws_connect();
 
     (whatsapp_connection, join_handle)
 }
+
+/// Connection which never connects, for tests of the handlers built on top of it
+#[cfg(test)]
+pub(crate) fn new_unconnected<H: WhatsappWebHandler<H> + Send + Sync + 'static>(handler: H, media_workers: Arc<WorkerPool>) -> (WhatsappWebConnection<H>, JoinHandle<()>) {
+    (WhatsappWebConnection::new(Box::new(|_| Ok(())), ConnectionOptions::default(), handler, media_workers), thread::spawn(|| {}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use JidKind;
+    use std::sync::mpsc;
+
+    fn session() -> PersistentSession {
+        PersistentSession {
+            version: PERSISTENT_SESSION_VERSION,
+            client_token: "client".to_string(),
+            server_token: "server".to_string(),
+            client_id: [1; 8],
+            enc: [2; 32],
+            mac: [3; 32],
+            epoch: 42,
+            tag_counter: 7
+        }
+    }
+
+    #[cfg(feature = "qr")]
+    #[test]
+    fn test_generate_qr_code() {
+        let qr_string = format!("{},{},{}", "1@".repeat(30), base64::encode(&[1; 32]), base64::encode(&[2; 16]));
+        assert_eq!(generate_qr_code(&qr_string).unwrap().error_correction_level(), EcLevel::M);
+
+        match generate_qr_code(&"x".repeat(4096)) {
+            Err(Error(ErrorKind::QrCode(_), _)) => {}
+            _ => panic!("expected qr-code error")
+        }
+    }
+
+    #[test]
+    fn test_media_conn_refresh_delay() {
+        assert_eq!(media_conn_refresh_delay(Duration::from_secs(3600)), Duration::from_secs(3240));
+        assert_eq!(media_conn_refresh_delay(Duration::from_secs(10)), MIN_MEDIA_CONN_REFRESH_DELAY);
+        assert_eq!(media_conn_refresh_delay(Duration::from_secs(0)), MIN_MEDIA_CONN_REFRESH_DELAY);
+    }
+
+    #[test]
+    fn test_persistent_session_roundtrip() {
+        let session = session();
+        assert_eq!(PersistentSession::deserialize(&session.serialize().unwrap()).unwrap(), session);
+    }
+
+    #[test]
+    fn test_persistent_session_migration() {
+        let expected = PersistentSession { epoch: 0, tag_counter: 0, ..session() };
+
+        let unversioned = bincode::serialize(&("client", "server", [1u8; 8], [2u8; 32], [3u8; 32])).unwrap();
+        assert_eq!(PersistentSession::deserialize(&unversioned).unwrap(), expected);
+
+        let mut version_1 = PERSISTENT_SESSION_MAGIC.to_vec();
+        bincode::serialize_into(&mut version_1, &(1u32, "client", "server", [1u8; 8], [2u8; 32], [3u8; 32])).unwrap();
+        assert_eq!(PersistentSession::deserialize(&version_1).unwrap(), expected);
+    }
+
+    #[derive(Default)]
+    struct SessionRecorder {
+        sessions: Mutex<Vec<PersistentSession>>
+    }
+
+    impl WhatsappWebHandler for SessionRecorder {
+        fn on_state_changed(&self, _: &WhatsappWebConnection<SessionRecorder>, _: State) {}
+
+        fn on_user_data_changed(&self, _: &WhatsappWebConnection<SessionRecorder>, _: UserData) {}
+
+        fn on_persistent_session_data_changed(&self, persistent_session: PersistentSession) {
+            self.sessions.lock().unwrap().push(persistent_session);
+        }
+
+        fn on_disconnect(&self, _: DisconnectReason) {}
+
+        fn on_message(&self, _: &WhatsappWebConnection<SessionRecorder>, _: bool, _: Box<WhatsappMessage>) {}
+    }
+
+    #[test]
+    fn test_not_connected_callbacks() {
+        let (connection, _) = new_unconnected(SessionRecorder::default(), default_media_workers());
+        let errors = Arc::new(Mutex::new(Vec::new()));
+
+        let media_conn_errors = errors.clone();
+        connection.get_media_conn(Box::new(move |result| {
+            media_conn_errors.lock().unwrap().push(result.unwrap_err().to_string());
+        }));
+        let recipients_errors = errors.clone();
+        let broadcast = Jid { id: "1500000000".to_string(), kind: JidKind::Broadcast };
+        connection.get_broadcast_list_recipients(broadcast, Box::new(move |result| {
+            recipients_errors.lock().unwrap().push(result.unwrap_err().to_string());
+        }));
+
+        assert_eq!(*errors.lock().unwrap(), vec!["not connected".to_string(), "not connected".to_string()]);
+    }
+
+    #[test]
+    fn test_broadcast_list_invalid_jid() {
+        let (connection, _) = new_unconnected(SessionRecorder::default(), default_media_workers());
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let individual = Jid { id: "491234567890".to_string(), kind: JidKind::Individual };
+
+        let add_errors = errors.clone();
+        connection.broadcast_list_add(individual.clone(), Vec::new(), Box::new(move |result| {
+            add_errors.lock().unwrap().push(result.unwrap_err().to_string());
+        }));
+        let remove_errors = errors.clone();
+        connection.broadcast_list_remove(individual.clone(), Vec::new(), Box::new(move |result| {
+            remove_errors.lock().unwrap().push(result.unwrap_err().to_string());
+        }));
+        let recipients_errors = errors.clone();
+        connection.get_broadcast_list_recipients(individual, Box::new(move |result| {
+            recipients_errors.lock().unwrap().push(result.unwrap_err().to_string());
+        }));
+
+        assert_eq!(*errors.lock().unwrap(), vec!["not a broadcast jid".to_string(); 3]);
+    }
+
+    #[test]
+    fn test_shutdown_with_shared_media_workers() {
+        let media_workers = default_media_workers();
+        let (first, first_join_handle) = new_unconnected(SessionRecorder::default(), media_workers.clone());
+        let (second, _) = new_unconnected(SessionRecorder::default(), media_workers.clone());
+
+        let (release_sender, release_receiver) = mpsc::channel::<()>();
+        second.execute_media_job(move || { release_receiver.recv().ok(); });
+        let finished = Arc::new(Mutex::new(false));
+        let first_finished = finished.clone();
+        first.execute_media_job(move || *first_finished.lock().unwrap() = true);
+
+        // Only the transfers of the shut down connection are waited for
+        first.shutdown_and_join(first_join_handle).unwrap();
+        assert!(*finished.lock().unwrap());
+
+        release_sender.send(()).unwrap();
+        media_workers.close_and_join();
+    }
+
+    enum TraceEvent {
+        Send(u32),
+        Reconnect,
+        Crash
+    }
+
+    fn establish(connection: &WhatsappWebConnection<SessionRecorder>) {
+        let user_jid = Jid { id: "491234567890".to_string(), kind: JidKind::Individual };
+        connection.lock_inner().handle_server_conn(user_jid, "client", "server", None).unwrap();
+    }
+
+    fn restore(persistent_session: PersistentSession) -> WhatsappWebConnection<SessionRecorder> {
+        let connection = WhatsappWebConnection::with_persistent_session(persistent_session, ConnectionOptions::default(), SessionRecorder::default(), default_media_workers());
+        establish(&connection);
+        connection
+    }
+
+    #[test]
+    fn test_counters_trace_replay() {
+        // Session which sent presence updates, reconnected once and crashed twice without a clean shutdown
+        let trace = [TraceEvent::Send(150), TraceEvent::Crash, TraceEvent::Send(30), TraceEvent::Reconnect,
+            TraceEvent::Send(250), TraceEvent::Crash, TraceEvent::Send(1)];
+
+        let mut connection = restore(session());
+        let (mut used_epoch, mut tag_counter) = (session().epoch, session().tag_counter);
+        for event in &trace {
+            match *event {
+                TraceEvent::Send(count) => for _ in 0..count {
+                    let presence = AppEvent::PresenceChange(PresenceStatus::Available, None);
+                    connection.send_app_message(None, WebsocketMessageMetric::Presence, AppMessage::MessagesEvents(Some(MessageEventType::Set), vec![presence]), Box::new(|_, _| {}));
+                    let inner = connection.lock_inner();
+                    assert!(inner.epoch > used_epoch);
+                    assert!(inner.messages_tag_counter > tag_counter);
+                    used_epoch = inner.epoch;
+                    tag_counter = inner.messages_tag_counter;
+                },
+                TraceEvent::Reconnect => {
+                    connection.ws_on_disconnected();
+                    establish(&connection);
+                }
+                TraceEvent::Crash => {
+                    let stored = connection.handler.sessions.lock().unwrap().last().cloned().unwrap();
+                    assert!(stored.epoch >= used_epoch);
+                    assert!(stored.tag_counter >= tag_counter);
+                    connection = restore(stored);
+                }
+            }
+        }
+    }
+}
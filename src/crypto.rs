@@ -1,15 +1,27 @@
 extern crate crypto;
 
+use std::cmp;
+use std::io::{Read, Write};
+
 use ring;
-use ring::{agreement, rand, hkdf, hmac, digest};
+use ring::{agreement, rand, hkdf, hmac, digest, aead, pbkdf2};
 use ring::rand::{SystemRandom, SecureRandom};
-use self::crypto::{aes, blockmodes};
-use self::crypto::buffer::{RefWriteBuffer, RefReadBuffer, WriteBuffer};
+use self::crypto::{aes, aessafe, blockmodes};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use self::crypto::{aesni, util};
+use self::crypto::blockmodes::{CbcEncryptor, CbcDecryptor, PkcsPadding};
+use self::crypto::symmetriccipher::{Encryptor, Decryptor};
+use self::crypto::buffer::{RefWriteBuffer, RefReadBuffer, WriteBuffer, ReadBuffer, BufferResult};
 use untrusted;
 
 use MediaType;
 use errors::*;
 
+const MEDIA_SIDECAR_CHUNK_SIZE: usize = 64 * 1024;
+const PASSPHRASE_PBKDF2_ITERATIONS: u32 = 100_000;
+const PASSPHRASE_SALT_LEN: usize = 16;
+const PASSPHRASE_NONCE_LEN: usize = 12;
+
 pub(crate) fn generate_keypair() -> (agreement::EphemeralPrivateKey, Vec<u8>) {
     let rng = rand::SystemRandom::new();
 
@@ -55,36 +67,166 @@ pub(crate) fn calculate_secret_keys(secret: &[u8], private_key: agreement::Ephem
     Ok((enc, mac))
 }
 
-pub fn verify_and_decrypt_message(enc: &[u8], mac: &[u8], message_encrypted: &[u8]) -> Result<Vec<u8>> {
-    hmac::verify(&hmac::VerificationKey::new(&digest::SHA256, &mac),
-                 &message_encrypted[32..], &message_encrypted[..32]).chain_err(|| "Invalid mac")?;
+/// Expanded AES-256 key, using AES-NI if it is supported like ```aes::cbc_encryptor``` does
+#[derive(Clone, Copy)]
+enum AesKey {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    AesNi(aesni::AesNiEncryptor, aesni::AesNiDecryptor),
+    Safe(aessafe::AesSafe256Encryptor, aessafe::AesSafe256Decryptor)
+}
+
+impl AesKey {
+    fn new(key: &[u8]) -> AesKey {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if util::supports_aesni() {
+                return AesKey::AesNi(aesni::AesNiEncryptor::new(aes::KeySize::KeySize256, key),
+                                     aesni::AesNiDecryptor::new(aes::KeySize::KeySize256, key));
+            }
+        }
+        AesKey::Safe(aessafe::AesSafe256Encryptor::new(key), aessafe::AesSafe256Decryptor::new(key))
+    }
+
+    fn encrypt(&self, iv: &[u8], input: &[u8], output: &mut [u8]) -> usize {
+        match *self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            AesKey::AesNi(encryptor, _) => run_encryptor(CbcEncryptor::new(encryptor, PkcsPadding, iv.to_vec()), input, output),
+            AesKey::Safe(encryptor, _) => run_encryptor(CbcEncryptor::new(encryptor, PkcsPadding, iv.to_vec()), input, output)
+        }
+    }
+
+    fn decrypt(&self, iv: &[u8], input: &[u8], output: &mut [u8]) -> Result<usize> {
+        match *self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            AesKey::AesNi(_, decryptor) => run_decryptor(CbcDecryptor::new(decryptor, PkcsPadding, iv.to_vec()), input, output),
+            AesKey::Safe(_, decryptor) => run_decryptor(CbcDecryptor::new(decryptor, PkcsPadding, iv.to_vec()), input, output)
+        }
+    }
+}
 
-    let mut message = vec![0u8; message_encrypted.len() - 48];
+fn run_encryptor<E: Encryptor>(mut encryptor: E, input: &[u8], output: &mut [u8]) -> usize {
+    let mut read_buffer = RefReadBuffer::new(input);
+    let mut write_buffer = RefWriteBuffer::new(output);
+
+    encryptor.encrypt(&mut read_buffer, &mut write_buffer, true).unwrap();
+    write_buffer.position()
+}
+
+fn run_decryptor<D: Decryptor>(mut decryptor: D, input: &[u8], output: &mut [u8]) -> Result<usize> {
+    let mut read_buffer = RefReadBuffer::new(input);
+    let mut write_buffer = RefWriteBuffer::new(output);
+
+    decryptor.decrypt(&mut read_buffer, &mut write_buffer, true).map_err(|_| "Invalid padding")?;
+    Ok(write_buffer.position())
+}
 
-    let size_without_padding = aes_decrypt(enc, &message_encrypted[32..48], &message_encrypted[48..], &mut message);
-    message.truncate(size_without_padding);
-    Ok(message)
+/// Key objects derived from the ```enc``` and ```mac``` keys of a session.
+/// They are set up once per session instead of once per message
+pub struct SessionKeys {
+    aes_key: AesKey,
+    signing_key: hmac::SigningKey,
+    verification_key: hmac::VerificationKey
 }
 
-pub(crate) fn sign_and_encrypt_message(enc: &[u8], mac: &[u8], message: &[u8]) -> Vec<u8> {
-    let mut message_encrypted = vec![0u8; 32 + 16 + message.len() + 32];
+impl SessionKeys {
+    pub fn new(enc: &[u8], mac: &[u8]) -> SessionKeys {
+        SessionKeys {
+            aes_key: AesKey::new(enc),
+            signing_key: hmac::SigningKey::new(&digest::SHA256, mac),
+            verification_key: hmac::VerificationKey::new(&digest::SHA256, mac)
+        }
+    }
+
+    pub fn verify_and_decrypt_message(&self, message_encrypted: &[u8]) -> Result<Vec<u8>> {
+        if message_encrypted.len() < 48 {
+            bail! {"Message too short"}
+        }
+        hmac::verify(&self.verification_key, &message_encrypted[32..], &message_encrypted[..32]).chain_err(|| "Invalid mac")?;
+
+        let mut message = vec![0u8; message_encrypted.len() - 48];
+
+        let size_without_padding = self.aes_key.decrypt(&message_encrypted[32..48], &message_encrypted[48..], &mut message)?;
+        message.truncate(size_without_padding);
+        Ok(message)
+    }
+
+    /// Appends the signed and encrypted ```message``` to ```output```, so the buffer can be reused
+    pub fn sign_and_encrypt_message_into(&self, message: &[u8], output: &mut Vec<u8>) {
+        let start = output.len();
+        output.resize(start + 32 + 16 + message.len() + 32, 0);
 
+        let mut iv = [0u8; 16];
+        SystemRandom::new().fill(&mut iv).unwrap();
 
-    let mut iv = vec![0u8; 16];
-    SystemRandom::new().fill(&mut iv).unwrap();
+        let size_with_padding = self.aes_key.encrypt(&iv, &message, &mut output[start + 48..]);
+        output.truncate(start + 32 + 16 + size_with_padding);
 
-    let size_with_padding = aes_encrypt(enc, &iv, &message, &mut message_encrypted[48..]);
-    message_encrypted.truncate(32 + 16 + size_with_padding);
+        output[start + 32..start + 48].clone_from_slice(&iv);
 
-    message_encrypted[32..48].clone_from_slice(&iv);
+        let signature = hmac::sign(&self.signing_key, &output[start + 32..]);
+
+        output[start..start + 32].clone_from_slice(signature.as_ref());
+    }
+}
 
-    let signature = hmac::sign(&hmac::SigningKey::new(&digest::SHA256, &mac),
-                               &message_encrypted[32..]);
+pub fn verify_and_decrypt_message(enc: &[u8], mac: &[u8], message_encrypted: &[u8]) -> Result<Vec<u8>> {
+    SessionKeys::new(enc, mac).verify_and_decrypt_message(message_encrypted)
+}
 
-    message_encrypted[0..32].clone_from_slice(signature.as_ref());
+pub fn sign_and_encrypt_message(enc: &[u8], mac: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut message_encrypted = Vec::new();
+    sign_and_encrypt_message_into(enc, mac, message, &mut message_encrypted);
     message_encrypted
 }
 
+/// Like ```sign_and_encrypt_message``` but appends to ```output```, so the buffer can be reused
+pub fn sign_and_encrypt_message_into(enc: &[u8], mac: &[u8], message: &[u8], output: &mut Vec<u8>) {
+    SessionKeys::new(enc, mac).sign_and_encrypt_message_into(message, output)
+}
+
+fn derive_passphrase_key(passphrase: &str, salt: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::derive(&digest::SHA256, PASSPHRASE_PBKDF2_ITERATIONS, salt, passphrase.as_bytes(), &mut key);
+    key
+}
+
+/// Encrypt ```data``` with ChaCha20-Poly1305 using a key derived from ```passphrase``` by PBKDF2.
+/// The random salt and nonce are prepended to the ciphertext
+pub fn encrypt_with_passphrase(passphrase: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let header_len = PASSPHRASE_SALT_LEN + PASSPHRASE_NONCE_LEN;
+    let tag_len = aead::CHACHA20_POLY1305.tag_len();
+
+    let mut output = vec![0u8; header_len];
+    SystemRandom::new().fill(&mut output)?;
+
+    let key = derive_passphrase_key(passphrase, &output[..PASSPHRASE_SALT_LEN]);
+    let sealing_key = aead::SealingKey::new(&aead::CHACHA20_POLY1305, &key)?;
+    let nonce = output[PASSPHRASE_SALT_LEN..].to_vec();
+
+    output.extend_from_slice(data);
+    output.resize(header_len + data.len() + tag_len, 0);
+    let sealed_len = aead::seal_in_place(&sealing_key, &nonce, &[], &mut output[header_len..], tag_len)?;
+    output.truncate(header_len + sealed_len);
+    Ok(output)
+}
+
+/// Decrypt data encrypted by ```encrypt_with_passphrase```, fails if the passphrase is wrong or the data was modified
+pub fn decrypt_with_passphrase(passphrase: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let header_len = PASSPHRASE_SALT_LEN + PASSPHRASE_NONCE_LEN;
+    if data.len() < header_len + aead::CHACHA20_POLY1305.tag_len() {
+        bail! {"Encrypted data too short"}
+    }
+
+    let key = derive_passphrase_key(passphrase, &data[..PASSPHRASE_SALT_LEN]);
+    let opening_key = aead::OpeningKey::new(&aead::CHACHA20_POLY1305, &key)?;
+
+    let mut buffer = data[header_len..].to_vec();
+    let len = aead::open_in_place(&opening_key, &data[PASSPHRASE_SALT_LEN..header_len], &[], 0, &mut buffer)
+        .chain_err(|| "Wrong passphrase or corrupted data")?.len();
+    buffer.truncate(len);
+    Ok(buffer)
+}
+
 pub(crate) fn sign_challenge(mac: &[u8], challenge: &[u8]) -> hmac::Signature {
     hmac::sign(&hmac::SigningKey::new(&digest::SHA256, &mac), &challenge)
 }
@@ -157,6 +299,80 @@ pub fn decrypt_media_message(key: &[u8], media_type: MediaType, file_encrypted:
     Ok(file)
 }
 
+/// Decrypt an encrypted media file chunk by chunk while it is read, so it never has to be kept in memory as a whole.
+/// As the mac is only known at the end, ```writer``` may already have received data when the mac turns out to be invalid
+pub fn decrypt_media_stream<R: Read, W: Write>(key: &[u8], media_type: MediaType, reader: &mut R, writer: &mut W) -> Result<()> {
+    let media_key_expanded = derive_media_keys(key, media_type);
+    let iv = &media_key_expanded[0..16];
+
+    let mut signing_context = hmac::SigningContext::with_key(&hmac::SigningKey::new(&digest::SHA256, &media_key_expanded[48..80]));
+    signing_context.update(iv);
+
+    let mut aes_decrypt = aes::cbc_decryptor(aes::KeySize::KeySize256, &media_key_expanded[16..48], iv, blockmodes::PkcsPadding);
+
+    let mut chunk = vec![0u8; MEDIA_SIDECAR_CHUNK_SIZE];
+    let mut output = vec![0u8; MEDIA_SIDECAR_CHUNK_SIZE];
+    // The last 10 bytes are the mac, so they are held back until the end of the file is reached
+    let mut pending = Vec::with_capacity(MEDIA_SIDECAR_CHUNK_SIZE + 10);
+
+    loop {
+        let read = reader.read(&mut chunk)?;
+        let eof = read == 0;
+        pending.extend_from_slice(&chunk[..read]);
+
+        if pending.len() < 10 {
+            if eof {
+                bail! {"File too short"}
+            }
+            continue;
+        }
+
+        let processable = pending.len() - 10;
+        signing_context.update(&pending[..processable]);
+        {
+            let mut read_buffer = RefReadBuffer::new(&pending[..processable]);
+            loop {
+                let mut write_buffer = RefWriteBuffer::new(&mut output);
+                let result = aes_decrypt.decrypt(&mut read_buffer, &mut write_buffer, eof).map_err(|_| Error::from("Invalid padding"))?;
+                writer.write_all(write_buffer.take_read_buffer().take_remaining())?;
+                if let BufferResult::BufferUnderflow = result {
+                    break;
+                }
+            }
+        }
+        pending.drain(..processable);
+
+        if eof {
+            break;
+        }
+    }
+
+    if pending[..] != signing_context.sign().as_ref()[..10] {
+        bail! {"Invalid mac"}
+    }
+
+    Ok(())
+}
+
+/// Generate the sidecar of an encrypted media file, which allows the recipient to verify and play
+/// videos and audios while they are still downloading
+pub fn generate_media_sidecar(key: &[u8], media_type: MediaType, file_encrypted: &[u8]) -> Vec<u8> {
+    let media_key_expanded = derive_media_keys(key, media_type);
+    let signing_key = hmac::SigningKey::new(&digest::SHA256, &media_key_expanded[48..80]);
+
+    let data = [&media_key_expanded[0..16], &file_encrypted[..file_encrypted.len() - 10]].concat();
+
+    let mut sidecar = Vec::with_capacity((data.len() / MEDIA_SIDECAR_CHUNK_SIZE + 1) * 10);
+    let mut offset = 0;
+    while offset + 16 < data.len() {
+        let end = cmp::min(offset + MEDIA_SIDECAR_CHUNK_SIZE + 16, data.len());
+        sidecar.extend_from_slice(&hmac::sign(&signing_key, &data[offset..end]).as_ref()[..10]);
+        offset += MEDIA_SIDECAR_CHUNK_SIZE;
+    }
+
+    sidecar
+}
+
 pub(crate) fn aes_encrypt(key: &[u8], iv: &[u8], input: &[u8], output: &mut [u8]) -> usize {
     let mut aes_encrypt = aes::cbc_encryptor(aes::KeySize::KeySize256, key, iv, blockmodes::PkcsPadding);
 
@@ -185,6 +401,7 @@ mod tests {
     use base64;
     use node_wire::Node;
     use std::io::stdin;
+    use proptest::prelude::*;
 
 
     #[test]
@@ -240,4 +457,80 @@ mod tests {
 
         assert_eq!(msg, dec_msg);
     }
+
+    #[test]
+    fn test_decrypt_media_stream() {
+        let mut msg = vec![0u8; 200_000];
+        SystemRandom::new().fill(&mut msg).unwrap();
+
+        let media_type = MediaType::Document;
+
+        let (enc_msg, key) = encrypt_media_message(media_type, &msg);
+
+        let mut dec_msg = Vec::new();
+        decrypt_media_stream(&key, media_type, &mut &enc_msg[..], &mut dec_msg).unwrap();
+
+        assert_eq!(msg, dec_msg);
+    }
+
+    #[test]
+    fn test_media_sidecar() {
+        let mut msg = vec![0u8; 200_000];
+        SystemRandom::new().fill(&mut msg).unwrap();
+
+        let media_type = MediaType::Video;
+
+        let (enc_msg, key) = encrypt_media_message(media_type, &msg);
+
+        let sidecar = generate_media_sidecar(&key, media_type, &enc_msg);
+
+        assert_eq!(sidecar.len(), 4 * 10);
+        assert_eq!(sidecar, generate_media_sidecar(&key, media_type, &enc_msg));
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_with_passphrase() {
+        let mut data = vec![0u8; 150];
+        SystemRandom::new().fill(&mut data).unwrap();
+
+        let encrypted = encrypt_with_passphrase("passphrase", &data).unwrap();
+
+        assert_eq!(decrypt_with_passphrase("passphrase", &encrypted).unwrap(), data);
+        assert!(decrypt_with_passphrase("wrong passphrase", &encrypted).is_err());
+    }
+
+    fn arb_media_type() -> BoxedStrategy<MediaType> {
+        prop_oneof![
+            Just(MediaType::Image),
+            Just(MediaType::Video),
+            Just(MediaType::Audio),
+            Just(MediaType::Document)
+        ].boxed()
+    }
+
+    proptest! {
+        #[test]
+        fn test_encrypt_decrypt_message_roundtrip(ref enc in prop::collection::vec(any::<u8>(), 32),
+                                                  ref mac in prop::collection::vec(any::<u8>(), 32),
+                                                  ref msg in prop::collection::vec(any::<u8>(), 0..2048)) {
+            let enc_msg = sign_and_encrypt_message(enc, mac, msg);
+            prop_assert_eq!(&verify_and_decrypt_message(enc, mac, &enc_msg).unwrap(), msg);
+        }
+
+        #[test]
+        fn test_encrypt_decrypt_media_roundtrip(media_type in arb_media_type(),
+                                                ref msg in prop::collection::vec(any::<u8>(), 0..20_000)) {
+            let (enc_msg, key) = encrypt_media_message(media_type, msg);
+            prop_assert_eq!(&decrypt_media_message(&key, media_type, &enc_msg).unwrap(), msg);
+
+            let mut dec_msg = Vec::new();
+            decrypt_media_stream(&key, media_type, &mut &enc_msg[..], &mut dec_msg).unwrap();
+            prop_assert_eq!(&dec_msg, msg);
+        }
+
+        #[test]
+        fn test_verify_and_decrypt_arbitrary_bytes(ref data in prop::collection::vec(any::<u8>(), 0..512)) {
+            prop_assert!(verify_and_decrypt_message(&[0u8; 32], &[0u8; 32], data).is_err());
+        }
+    }
 }
@@ -1,21 +1,26 @@
 use std::io;
 use ws;
 use ring;
-#[cfg(feature = "media")]
+#[cfg(any(feature = "media", feature = "webhook"))]
 use reqwest;
+#[cfg(feature = "serde-json")]
+use serde_json;
 use json;
 use base64;
 use protobuf;
+use bincode;
 
 error_chain! {
         foreign_links {
             Io(io::Error);
             Websocket(ws::Error);
             Crypto(ring::error::Unspecified);
-            Reqwest(reqwest::Error) #[cfg(feature = "media")];
+            Reqwest(reqwest::Error) #[cfg(any(feature = "media", feature = "webhook"))];
+            SerdeJson(serde_json::Error) #[cfg(feature = "serde-json")];
             Json(json::Error);
             Base64(base64::DecodeError);
             Protobuf(protobuf::ProtobufError);
+            Bincode(bincode::Error);
         }
 
         errors {
@@ -28,5 +33,20 @@ error_chain! {
                 description("missing field in json")
                 display("missing field '{}' in json", field)
             }
+
+            HandlerPanicked(message: String) {
+                description("handler panicked")
+                display("handler panicked: '{}'", message)
+            }
+
+            MediaUnauthorized {
+                description("media host rejected the media connection auth")
+                display("media host rejected the media connection auth")
+            }
+
+            QrCode(reason: String) {
+                description("could not generate qr code")
+                display("could not generate qr code: {}", reason)
+            }
         }
 }
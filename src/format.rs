@@ -0,0 +1,171 @@
+//! Formatting of message text, WhatsApp uses a markdown like syntax:
+//! ```*bold*```, ```_italic_```, ```~strikethrough~```, ```` ```monospace``` ```` and ```` `inline code` ````
+use std::mem;
+
+const MONOSPACE_MARKER: [char; 3] = ['`', '`', '`'];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Span {
+    Plain(String),
+    Bold(Vec<Span>),
+    Italic(Vec<Span>),
+    Strikethrough(Vec<Span>),
+    /// Block of monospace text, its content isn't formatted
+    Monospace(String),
+    /// Inline code, its content isn't formatted
+    Code(String),
+}
+
+pub fn bold(text: &str) -> String {
+    format!("*{}*", text)
+}
+
+pub fn italic(text: &str) -> String {
+    format!("_{}_", text)
+}
+
+pub fn strikethrough(text: &str) -> String {
+    format!("~{}~", text)
+}
+
+pub fn monospace(text: &str) -> String {
+    format!("```{}```", text)
+}
+
+pub fn code(text: &str) -> String {
+    format!("`{}`", text)
+}
+
+/// Parse the formatting of a received message text
+pub fn parse(text: &str) -> Vec<Span> {
+    let chars: Vec<char> = text.chars().collect();
+    parse_spans(&chars)
+}
+
+/// Build the message text of ```spans```, inverse of ```parse```
+pub fn render(spans: &[Span]) -> String {
+    let mut text = String::new();
+    render_into(spans, &mut text);
+    text
+}
+
+fn render_into(spans: &[Span], text: &mut String) {
+    for span in spans {
+        match *span {
+            Span::Plain(ref plain) => text.push_str(plain),
+            Span::Bold(ref spans) => render_styled('*', spans, text),
+            Span::Italic(ref spans) => render_styled('_', spans, text),
+            Span::Strikethrough(ref spans) => render_styled('~', spans, text),
+            Span::Monospace(ref monospace_text) => text.push_str(&monospace(monospace_text)),
+            Span::Code(ref code_text) => text.push_str(&code(code_text)),
+        }
+    }
+}
+
+fn render_styled(marker: char, spans: &[Span], text: &mut String) {
+    text.push(marker);
+    render_into(spans, text);
+    text.push(marker);
+}
+
+fn parse_spans(chars: &[char]) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let mut plain = String::new();
+    let mut position = 0;
+
+    while position < chars.len() {
+        if let Some((span, end)) = parse_styled(chars, position) {
+            if !plain.is_empty() {
+                spans.push(Span::Plain(mem::replace(&mut plain, String::new())));
+            }
+            spans.push(span);
+            position = end;
+        } else {
+            plain.push(chars[position]);
+            position += 1;
+        }
+    }
+
+    if !plain.is_empty() {
+        spans.push(Span::Plain(plain));
+    }
+    spans
+}
+
+/// Parse a span starting with a marker at ```start```, returns the span and the position after its closing marker
+fn parse_styled(chars: &[char], start: usize) -> Option<(Span, usize)> {
+    // Markers within words, e.g. in snake_case, don't start a span
+    if start > 0 && chars[start - 1].is_alphanumeric() {
+        return None;
+    }
+
+    if chars[start..].starts_with(&MONOSPACE_MARKER) {
+        let content_start = start + MONOSPACE_MARKER.len();
+        return (content_start + 1..chars.len())
+            .find(|&end| chars[end..].starts_with(&MONOSPACE_MARKER))
+            .map(|end| (Span::Monospace(chars[content_start..end].iter().collect()), end + MONOSPACE_MARKER.len()));
+    }
+
+    let marker = chars[start];
+    if marker != '*' && marker != '_' && marker != '~' && marker != '`' {
+        return None;
+    }
+    let content_start = start + 1;
+    if chars.get(content_start).map_or(true, |c| c.is_whitespace()) {
+        return None;
+    }
+
+    for end in content_start + 1..chars.len() {
+        if chars[end] == '\n' {
+            return None;
+        }
+        if chars[end] == marker && !chars[end - 1].is_whitespace() && chars.get(end + 1).map_or(true, |c| !c.is_alphanumeric()) {
+            let content = &chars[content_start..end];
+            let span = match marker {
+                '*' => Span::Bold(parse_spans(content)),
+                '_' => Span::Italic(parse_spans(content)),
+                '~' => Span::Strikethrough(parse_spans(content)),
+                _ => Span::Code(content.iter().collect())
+            };
+            return Some((span, end + 1));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("hello *bold _and italic_* world"), vec![
+            Span::Plain("hello ".to_string()),
+            Span::Bold(vec![Span::Plain("bold ".to_string()), Span::Italic(vec![Span::Plain("and italic".to_string())])]),
+            Span::Plain(" world".to_string())
+        ]);
+        assert_eq!(parse("~gone~ and ```*raw*```"), vec![
+            Span::Strikethrough(vec![Span::Plain("gone".to_string())]),
+            Span::Plain(" and ".to_string()),
+            Span::Monospace("*raw*".to_string())
+        ]);
+        assert_eq!(parse("use `snake_case_names`"), vec![
+            Span::Plain("use ".to_string()),
+            Span::Code("snake_case_names".to_string())
+        ]);
+    }
+
+    #[test]
+    fn test_parse_unformatted() {
+        for text in &["snake_case_name", "2 * 3 * 4", "*not\nbold*", "* no *", "*", "```"] {
+            assert_eq!(parse(text), vec![Span::Plain(text.to_string())]);
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let text = format!("{} {} {}", bold("a"), italic(&strikethrough("b")), monospace("c"));
+        assert_eq!(text, "*a* _~b~_ ```c```");
+        assert_eq!(render(&parse(&text)), text);
+    }
+}
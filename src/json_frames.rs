@@ -0,0 +1,133 @@
+//! Strongly typed view of the json frames sent by the server, parsed with serde_json.
+//! The connection itself still parses and builds its frames with the ```json``` crate,
+//! this module is meant for frames which aren't handled by the connection: they are delivered
+//! as ```UserData::ServerNotice``` and can be parsed with ```JsonFrame::from_notice```
+use serde_json;
+use serde_json::{Value, Map};
+
+use errors::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonFrame {
+    Conn(ConnFrame),
+    Cmd(CmdFrame),
+    Chat(ChatFrame),
+    Msg(MsgFrame),
+    MsgInfo(MsgFrame),
+    Presence(PresenceFrame),
+    Status(StatusFrame),
+    Blocklist(BlocklistFrame),
+    Props(Map<String, Value>),
+    Other(String, Value)
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ConnFrame {
+    pub wid: String,
+    #[serde(rename = "serverToken")]
+    pub server_token: String,
+    #[serde(rename = "clientToken")]
+    pub client_token: String,
+    pub secret: Option<String>,
+    pub pushname: Option<String>,
+    pub platform: Option<String>,
+    #[serde(default)]
+    pub phone: PhoneFrame,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+pub struct PhoneFrame {
+    pub wa_version: Option<String>,
+    pub os_version: Option<String>,
+    pub device_manufacturer: Option<String>,
+    pub device_model: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CmdFrame {
+    #[serde(rename = "type")]
+    pub cmd_type: String,
+    pub challenge: Option<String>,
+    pub kind: Option<String>,
+    pub jid: Option<String>,
+    pub tag: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ChatFrame {
+    pub id: String,
+    #[serde(default)]
+    pub data: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum MessageIds {
+    One(String),
+    Many(Vec<String>)
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MsgFrame {
+    pub cmd: String,
+    pub id: MessageIds,
+    pub from: String,
+    pub to: String,
+    pub participant: Option<String>,
+    pub t: i64,
+    pub ack: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PresenceFrame {
+    pub id: String,
+    pub participant: Option<String>,
+    #[serde(rename = "type")]
+    pub presence_type: String,
+    pub t: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct StatusFrame {
+    pub id: String,
+    pub status: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BlocklistFrame {
+    #[serde(default)]
+    pub blocklist: Vec<String>,
+}
+
+impl JsonFrame {
+    /// Parse a complete frame like ```["Presence", {...}]```
+    pub fn parse(json: &str) -> Result<JsonFrame> {
+        let mut frame: Vec<Value> = serde_json::from_str(json)?;
+        if frame.is_empty() {
+            bail! {"server message without opcode"}
+        }
+        let payload = if frame.len() > 1 { frame.swap_remove(1) } else { Value::Null };
+        let opcode = frame[0].as_str().ok_or("server message without opcode")?.to_string();
+        JsonFrame::from_payload(opcode, payload)
+    }
+
+    /// Parse the opcode and payload of a ```UserData::ServerNotice```
+    pub fn from_notice(opcode: &str, payload: &str) -> Result<JsonFrame> {
+        JsonFrame::from_payload(opcode.to_string(), serde_json::from_str(payload)?)
+    }
+
+    fn from_payload(opcode: String, payload: Value) -> Result<JsonFrame> {
+        Ok(match opcode.as_str() {
+            "Conn" => JsonFrame::Conn(serde_json::from_value(payload)?),
+            "Cmd" => JsonFrame::Cmd(serde_json::from_value(payload)?),
+            "Chat" => JsonFrame::Chat(serde_json::from_value(payload)?),
+            "Msg" => JsonFrame::Msg(serde_json::from_value(payload)?),
+            "MsgInfo" => JsonFrame::MsgInfo(serde_json::from_value(payload)?),
+            "Presence" => JsonFrame::Presence(serde_json::from_value(payload)?),
+            "Status" => JsonFrame::Status(serde_json::from_value(payload)?),
+            "Blocklist" => JsonFrame::Blocklist(serde_json::from_value(payload)?),
+            "Props" => JsonFrame::Props(serde_json::from_value(payload)?),
+            _ => JsonFrame::Other(opcode, payload)
+        })
+    }
+}
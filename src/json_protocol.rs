@@ -1,19 +1,22 @@
 use std::str::FromStr;
+use std::time::Duration;
 
 use json::JsonValue;
 use base64;
+use chrono::NaiveDateTime;
 
-use super::{Jid, PresenceStatus, GroupMetadata, GroupParticipantsChange, MediaType};
-use message::MessageAckLevel;
+use super::{Jid, PresenceStatus, GroupMetadata, GroupParticipantsChange, MediaType, Participant, ParticipantRole, ProfilePicture, ParticipantChangeStatus, MediaConn};
+use message::{MessageAckLevel, MediaLimits};
+use connection::{DisconnectReason, PhoneInfo, JsonQuery};
 use errors::*;
 
 
 #[derive(Debug)]
 pub enum ServerMessage<'a> {
-    ConnectionAck { user_jid: Jid, client_token: &'a str, server_token: &'a str, secret: Option<&'a str> },
+    ConnectionAck { user_jid: Jid, client_token: &'a str, server_token: &'a str, secret: Option<&'a str>, push_name: Option<&'a str>, phone: PhoneInfo },
     ChallengeRequest(Vec<u8>),
-    Disconnect(Option<&'a str>),
-    PresenceChange { jid: Jid, status: PresenceStatus, time: Option<i64> },
+    Disconnect(DisconnectReason),
+    PresenceChange { jid: Jid, participant: Option<Jid>, status: PresenceStatus, time: Option<i64> },
     MessageAck { message_id: &'a str, level: MessageAckLevel, sender: Jid, receiver: Jid, participant: Option<Jid>, time: i64 },
     MessageAcks { message_ids: Vec<&'a str>, level: MessageAckLevel, sender: Jid, receiver: Jid, participant: Option<Jid>, time: i64 },
     GroupIntroduce { newly_created: bool, inducer: Jid, meta: GroupMetadata },
@@ -36,7 +39,15 @@ impl<'a> ServerMessage<'a> {
                     user_jid: payload.get_str("wid").and_then(|jid| Jid::from_str(jid))?,
                     server_token: payload.get_str("serverToken")?,
                     client_token: payload.get_str("clientToken")?,
-                    secret: payload["secret"].as_str()
+                    secret: payload["secret"].as_str(),
+                    push_name: payload["pushname"].as_str(),
+                    phone: PhoneInfo {
+                        platform: payload["platform"].as_str().map(|platform| platform.to_string()),
+                        wa_version: payload["phone"]["wa_version"].as_str().map(|version| version.to_string()),
+                        os_version: payload["phone"]["os_version"].as_str().map(|version| version.to_string()),
+                        device_manufacturer: payload["phone"]["device_manufacturer"].as_str().map(|manufacturer| manufacturer.to_string()),
+                        device_model: payload["phone"]["device_model"].as_str().map(|model| model.to_string())
+                    }
                 }
             }
             "Cmd" => {
@@ -46,7 +57,7 @@ impl<'a> ServerMessage<'a> {
                         ServerMessage::ChallengeRequest(base64::decode(&payload.get_str("challenge")?)?)
                     }
                     "disconnect" => {
-                        ServerMessage::Disconnect(payload["kind"].as_str())
+                        ServerMessage::Disconnect(DisconnectReason::from_json(payload["kind"].as_str()))
                     }
                     "picture" => {
                         ServerMessage::PictureChange { jid: Jid::from_str(payload.get_str("jid")?)?, removed: payload["tag"] == "removed" }
@@ -64,15 +75,18 @@ impl<'a> ServerMessage<'a> {
                         let group_metadata_json = &data[2];
                         let admins_json = &group_metadata_json["admins"];
                         let regulars_json = &group_metadata_json["regulars"];
+                        let creator = group_metadata_json["creator"].as_str().and_then(|jid| Jid::from_str(jid).ok());
 
                         let mut participants = Vec::with_capacity(admins_json.len() + regulars_json.len());
 
                         for participant in admins_json.members() {
-                            participants.push((Jid::from_str(participant.as_str().ok_or("not a string")?)?, true));
+                            let jid = Jid::from_str(participant.as_str().ok_or("not a string")?)?;
+                            let role = if creator.as_ref() == Some(&jid) { ParticipantRole::SuperAdmin } else { ParticipantRole::Admin };
+                            participants.push(Participant { jid, role });
                         }
 
                         for participant in regulars_json.members() {
-                            participants.push((Jid::from_str(participant.as_str().ok_or("not a string")?)?, false));
+                            participants.push(Participant { jid: Jid::from_str(participant.as_str().ok_or("not a string")?)?, role: ParticipantRole::Regular });
                         }
 
                         ServerMessage::GroupIntroduce {
@@ -80,7 +94,7 @@ impl<'a> ServerMessage<'a> {
                             newly_created: typ == "create",
                             meta: GroupMetadata {
                                 id: chat,
-                                owner: None,
+                                owner: creator,
                                 creation_time: group_metadata_json.get_i64("creation")?,
                                 subject: group_metadata_json.get_str("subject")?.to_string(),
                                 subject_owner: Jid::from_str(group_metadata_json.get_str("s_o")?)?,
@@ -123,15 +137,15 @@ impl<'a> ServerMessage<'a> {
                         receiver: Jid::from_str(payload.get_str("to")?)?,
                         participant: payload["participant"].as_str().and_then(|jid| Jid::from_str(jid).ok()),
                         time: payload.get_i64("t")?,
-                        level: MessageAckLevel::from_json(payload.get_u8("ack")?)?
+                        level: MessageAckLevel::from_json(payload.get_u8("ack")?)
                     },
                     "acks" => ServerMessage::MessageAcks {
-                        message_ids: payload["id"].members().map(|id| id.as_str().unwrap()).collect(),
+                        message_ids: payload["id"].members().map(|id| id.as_str().ok_or("message id not a string")).collect::<::std::result::Result<Vec<_>, _>>()?,
                         sender: Jid::from_str(payload.get_str("from")?)?,
                         receiver: Jid::from_str(payload.get_str("to")?)?,
                         participant: payload["participant"].as_str().and_then(|jid| Jid::from_str(jid).ok()),
                         time: payload.get_i64("t")?,
-                        level: MessageAckLevel::from_json(payload.get_u8("ack")?)?
+                        level: MessageAckLevel::from_json(payload.get_u8("ack")?)
                     },
                     _ => bail! { "invalid or unsupported 'Msg' or 'MsgInfo' subcommand type {}", cmd_type}
                 }
@@ -139,6 +153,7 @@ impl<'a> ServerMessage<'a> {
             "Presence" => {
                 ServerMessage::PresenceChange {
                     jid: Jid::from_str(payload.get_str("id")?)?,
+                    participant: payload["participant"].as_str().and_then(|jid| Jid::from_str(jid).ok()),
                     status: PresenceStatus::from_json(payload.get_str("type")?)?,
                     time: payload["t"].as_i64()
                 }
@@ -152,15 +167,37 @@ impl<'a> ServerMessage<'a> {
 }
 
 impl MessageAckLevel {
-    fn from_json(value: u8) -> Result<MessageAckLevel> {
-        Ok(match value {
+    fn from_json(value: u8) -> MessageAckLevel {
+        match value {
             0 => MessageAckLevel::PendingSend,
             1 => MessageAckLevel::Send,
             2 => MessageAckLevel::Received,
             3 => MessageAckLevel::Read,
             4 => MessageAckLevel::Played,
-            _ => bail! {"Invalid message ack level {}", value}
-        })
+            _ => MessageAckLevel::Error(value)
+        }
+    }
+}
+
+impl DisconnectReason {
+    fn from_json(kind: Option<&str>) -> DisconnectReason {
+        match kind {
+            None => DisconnectReason::LoggedOutFromPhone,
+            Some("replaced") => DisconnectReason::Replaced,
+            Some("banned") | Some("ban") => DisconnectReason::Banned,
+            Some("timeout") => DisconnectReason::Timeout,
+            Some(kind) => DisconnectReason::Unknown(kind.to_string())
+        }
+    }
+
+    fn from_status_code(status_code: u16) -> DisconnectReason {
+        match status_code {
+            401 => DisconnectReason::LoggedOutFromPhone,
+            403 => DisconnectReason::Banned,
+            408 => DisconnectReason::Timeout,
+            409 => DisconnectReason::Replaced,
+            _ => DisconnectReason::Unknown(status_code.to_string())
+        }
     }
 }
 
@@ -171,6 +208,7 @@ impl PresenceStatus {
             "available" => PresenceStatus::Available,
             "composing" => PresenceStatus::Typing,
             "recording" => PresenceStatus::Recording,
+            "paused" => PresenceStatus::Paused,
             _ => bail! {"Invalid presence status {}", value}
         })
     }
@@ -181,7 +219,16 @@ impl GroupMetadata {
         let participants_json = &value["participants"];
         let mut participants = Vec::with_capacity(participants_json.len());
         for participant in participants_json.members() {
-            participants.push((Jid::from_str(participant.get_str("id")?)?, participant.get_bool("isAdmin")?));
+            participants.push(Participant {
+                jid: Jid::from_str(participant.get_str("id")?)?,
+                role: if participant["isSuperAdmin"].as_bool().unwrap_or(false) {
+                    ParticipantRole::SuperAdmin
+                } else if participant.get_bool("isAdmin")? {
+                    ParticipantRole::Admin
+                } else {
+                    ParticipantRole::Regular
+                }
+            });
         }
 
         Ok(GroupMetadata {
@@ -216,6 +263,19 @@ pub fn parse_response_status(response: &JsonValue) -> Result<()> {
     })
 }
 
+/// Queries relayed to the phone are answered with status 599 while it is unreachable
+pub fn parse_phone_connectivity(response: &JsonValue) -> Option<bool> {
+    match response["status"].as_u16() {
+        Some(200) => Some(true),
+        Some(599) => Some(false),
+        _ => None
+    }
+}
+
+pub fn parse_disconnect_status(response: &JsonValue) -> DisconnectReason {
+    response["status"].as_u16().map_or_else(|| DisconnectReason::Unknown("missing status".to_string()), DisconnectReason::from_status_code)
+}
+
 pub fn build_init_request(client_id: &str) -> JsonValue {
     array!["admin", "init", array![0, 3, 416], array!["ww-rs", "ww-rs"], client_id, true]
 }
@@ -251,24 +311,97 @@ pub fn parse_file_upload_response<'a>(response: &'a JsonValue) -> Result<&'a str
     response.get_str("url")
 }
 
-pub fn build_profile_picture_request(jid: &Jid) -> JsonValue {
-    array!["query", "ProfilePicThumb", jid.to_string()]
+pub fn build_query(query: JsonQuery) -> JsonValue {
+    match query {
+        JsonQuery::ProfilePicThumb(jid) => array!["query", "ProfilePicThumb", jid.to_string()],
+        JsonQuery::ProfilePic(jid) => array!["query", "ProfilePic", jid.to_string()],
+        JsonQuery::Status(jid) => array!["query", "Status", jid.to_string()],
+        JsonQuery::GroupMetadata(jid) => array!["query", "GroupMetadata", jid.to_string()],
+        JsonQuery::Exists(jid) => array!["query", "exist", jid.to_string()],
+        JsonQuery::MediaConn => array!["query", "mediaConn"],
+        JsonQuery::Other(query) => query
+    }
+}
+
+pub fn parse_media_conn_response(response: &JsonValue) -> Result<MediaConn> {
+    parse_response_status(response)?;
+    let media_conn = &response["media_conn"];
+    Ok(MediaConn {
+        auth: media_conn.get_str("auth")?.to_string(),
+        ttl: Duration::from_secs(media_conn["ttl"].as_u64().ok_or(ErrorKind::JsonFieldMissing("ttl"))?),
+        hosts: media_conn["hosts"].members().filter_map(|host| host["hostname"].as_str()).map(|host| host.to_string()).collect()
+    })
+}
+
+/// Apply the limits contained in the ```Props``` sent after login, missing limits are kept
+pub fn parse_media_limits(props: &JsonValue, media_limits: &mut MediaLimits) {
+    if let Some(image_max_kbytes) = props["imageMaxKBytes"].as_usize() {
+        media_limits.max_image_size = image_max_kbytes * 1024;
+    }
+    if let Some(max_file_size) = props["maxFileSize"].as_usize() {
+        media_limits.max_file_size = max_file_size * 1024 * 1024;
+    }
 }
 
 pub fn parse_profile_picture_response(response: &JsonValue) -> Option<&str> {
     response["eurl"].as_str()
 }
 
-pub fn build_profile_status_request(jid: &Jid) -> JsonValue {
-    array!["query", "Status", jid.to_string()]
+pub fn parse_profile_picture_full_response(response: &JsonValue) -> Option<ProfilePicture> {
+    response["eurl"].as_str().map(|url| ProfilePicture {
+        url: url.to_string(),
+        tag: response["tag"].as_str().map(|tag| tag.to_string()).or_else(|| response["tag"].as_u64().map(|tag| tag.to_string()))
+    })
 }
 
 pub fn parse_profile_status_response(response: &JsonValue) -> Option<&str> {
     response["status"].as_str()
 }
 
-pub fn build_group_metadata_request(jid: &Jid) -> JsonValue {
-    array!["query", "GroupMetadata", jid.to_string()]
+pub fn parse_group_create_response(response: &JsonValue) -> Result<Jid> {
+    parse_response_status(response)?;
+    Jid::from_str(response.get_str("gid")?)
+}
+
+/// The created broadcast list is answered like ```{"status": 200, "jid": "<id>@broadcast"}```
+pub fn parse_broadcast_create_response(response: &JsonValue) -> Result<Jid> {
+    parse_response_status(response)?;
+    Jid::from_str(response.get_str("jid")?)
+}
+
+/// Group commands are answered with the status of each participant like ```{"participants": [{"<jid>": {"code": "403", ...}}]}```
+pub fn parse_group_participants_change_response(response: &JsonValue) -> Result<Vec<(Jid, ParticipantChangeStatus)>> {
+    if !response["participants"].is_array() {
+        parse_response_status(response)?;
+        return Ok(Vec::new());
+    }
+
+    let mut participants = Vec::new();
+    for participant in response["participants"].members() {
+        for (jid, status) in participant.entries() {
+            let code = status["code"].as_str().and_then(|code| code.parse().ok()).or_else(|| status["code"].as_u16()).unwrap_or(200);
+            participants.push((Jid::from_str(jid)?, match code {
+                200 => ParticipantChangeStatus::Success,
+                403 if status["invite_code"].is_string() => ParticipantChangeStatus::InviteRequired {
+                    code: status.get_str("invite_code")?.to_string(),
+                    expiration: status["invite_code_exp"].as_str().and_then(|exp| exp.parse().ok())
+                        .or_else(|| status["invite_code_exp"].as_i64())
+                        .and_then(|exp| NaiveDateTime::from_timestamp_opt(exp, 0))
+                },
+                code => ParticipantChangeStatus::Failed(code)
+            }));
+        }
+    }
+    Ok(participants)
+}
+
+pub fn build_accept_group_invite_request(code: &str) -> JsonValue {
+    array!["action", "invite", code]
+}
+
+pub fn parse_accept_group_invite_response(response: &JsonValue) -> Result<Jid> {
+    parse_response_status(response)?;
+    Jid::from_str(response.get_str("gid")?)
 }
 
 pub fn parse_group_metadata_response(response: &JsonValue) -> Result<GroupMetadata> {
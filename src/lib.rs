@@ -7,7 +7,9 @@ extern crate url;
 extern crate json;
 extern crate ring;
 extern crate base64;
+#[cfg(feature = "qr")]
 extern crate qrcode;
+#[cfg(feature = "media")]
 extern crate image;
 extern crate untrusted;
 #[macro_use]
@@ -18,58 +20,179 @@ extern crate byteorder;
 extern crate chrono;
 #[macro_use]
 extern crate error_chain;
-#[cfg(feature = "media")]
+#[macro_use]
+extern crate lazy_static;
+#[cfg(any(feature = "media", feature = "webhook"))]
 extern crate reqwest;
+#[cfg(feature = "serde-json")]
+extern crate serde_json;
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
 
 pub mod connection;
+pub mod manager;
 pub mod message;
+pub mod format;
 #[cfg(feature = "media")]
 pub mod media;
+#[cfg(feature = "raw-proto")]
+pub mod message_wire;
+#[cfg(not(feature = "raw-proto"))]
 mod message_wire;
 mod node_protocol;
+mod worker_pool;
+#[cfg(any(feature = "internals", feature = "raw-nodes"))]
+#[cfg_attr(not(feature = "raw-nodes"), doc(hidden))]
+pub mod node_wire;
+#[cfg(not(any(feature = "internals", feature = "raw-nodes")))]
 mod node_wire;
+#[cfg(feature = "internals")]
+#[doc(hidden)]
+pub mod json_protocol;
+#[cfg(not(feature = "internals"))]
 mod json_protocol;
+#[cfg(feature = "serde-json")]
+pub mod json_frames;
+#[cfg(feature = "bridge")]
+pub mod bridge;
+#[cfg(feature = "webhook")]
+pub mod webhook;
+#[cfg(feature = "internals")]
+#[doc(hidden)]
+pub mod websocket_protocol;
+#[cfg(not(feature = "internals"))]
 mod websocket_protocol;
 pub mod crypto;
 mod timeout;
 pub mod errors;
 
 use std::str::FromStr;
+use std::fmt;
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
 
 use errors::*;
 
 
-#[derive(Debug, Clone, PartialOrd, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
+pub enum JidKind {
+    Individual,
+    Group,
+    /// Broadcast list or the status broadcast
+    Broadcast,
+}
+
+#[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub struct Jid {
     pub id: String,
-    pub is_group: bool,
+    pub kind: JidKind,
 }
 
-/// Jid used to identify either a group or an individual
+/// Jid used to identify either a group, an individual or a broadcast list
 impl Jid {
-    pub fn to_string(&self) -> String {
-        self.id.to_string() + if self.is_group { "@g.us" } else { "@c.us" }
+    pub fn is_group(&self) -> bool {
+        self.kind == JidKind::Group
+    }
+
+    /// Whether the Jid is a broadcast list or the status broadcast
+    pub fn is_broadcast(&self) -> bool {
+        self.kind == JidKind::Broadcast
+    }
+
+    /// Whether the Jid is the broadcast of the status updates of the contacts
+    pub fn is_status(&self) -> bool {
+        self.kind == JidKind::Broadcast && self.id == "status"
+    }
+
+    /// Jid of the broadcast of the status updates of the contacts
+    pub fn status() -> Jid {
+        Jid { id: "status".to_string(), kind: JidKind::Broadcast }
+    }
+
+    /// Create the Jid of a broadcast list from its id
+    pub fn from_broadcast_id(id: &str) -> Result<Jid> {
+        if id.is_empty() || id.chars().any(|c| !c.is_digit(10)) {
+            bail! {"not a valid broadcast id: {}", id}
+        }
+        Ok(Jid { id: id.to_string(), kind: JidKind::Broadcast })
     }
 
     /// If the Jid is from an individual return the international phonenumber, else None
     pub fn phonenumber(&self) -> Option<String> {
-        if !self.is_group {
+        if self.kind == JidKind::Individual {
             Some("+".to_string() + &self.id)
         } else {
             None
         }
     }
 
-    pub fn from_phonenumber(mut phonenumber: String) -> Result<Jid> {
-        if phonenumber.starts_with('+') {
-            phonenumber.remove(0);
-        }
+    /// Create the Jid of an individual from its international phonenumber,
+    /// formatting characters like spaces, dashes, dots and parentheses are ignored
+    pub fn from_phone_number(phone_number: String) -> Result<Jid> {
+        let phone_number = phone_number.trim();
+        let phone_number = if phone_number.starts_with('+') { &phone_number[1..] } else { phone_number };
+
+        let id: String = phone_number.chars().filter(|c| !c.is_whitespace() && !"-./()".contains(*c)).collect();
 
-        if phonenumber.chars().any(|c| !c.is_digit(10)) {
+        if id.is_empty() || id.chars().any(|c| !c.is_digit(10)) {
             return Err("not a valid phonenumber".into());
         }
 
-        Ok(Jid { id: phonenumber, is_group: false })
+        Ok(Jid { id, kind: JidKind::Individual })
+    }
+
+    /// Create the Jid of a group from the phonenumber of its creator and its creation time
+    pub fn group_from_parts(creator_phone_number: &str, creation_time: i64) -> Result<Jid> {
+        let creator = Jid::from_phone_number(creator_phone_number.to_string())?;
+        Jid::from_group_id(&format!("{}-{}", creator.id, creation_time))
+    }
+
+    /// Create the Jid of a group from its id like ```<creator phonenumber>-<creation time>```
+    pub fn from_group_id(id: &str) -> Result<Jid> {
+        if !Jid::is_valid_group_id(id) {
+            bail! {"not a valid group id: {}", id}
+        }
+        Ok(Jid { id: id.to_string(), kind: JidKind::Group })
+    }
+
+    pub fn is_valid_group_id(id: &str) -> bool {
+        let mut parts = id.splitn(2, '-');
+        match (parts.next(), parts.next()) {
+            (Some(creator), Some(creation_time)) => !creator.is_empty() && creator.chars().all(|c| c.is_digit(10))
+                && !creation_time.is_empty() && creation_time.chars().all(|c| c.is_digit(10)),
+            _ => false
+        }
+    }
+
+    /// Phonenumber of the creator and creation time of a group, None if the Jid isn't a group
+    pub fn group_parts(&self) -> Option<(String, i64)> {
+        if !self.is_group() {
+            return None;
+        }
+        let mut parts = self.id.splitn(2, '-');
+        match (parts.next(), parts.next().and_then(|creation_time| creation_time.parse().ok())) {
+            (Some(creator), Some(creation_time)) => Some(("+".to_string() + creator, creation_time)),
+            _ => None
+        }
+    }
+
+    #[deprecated(note = "use from_phone_number")]
+    pub fn from_phonenumber(phonenumber: String) -> Result<Jid> {
+        Jid::from_phone_number(phonenumber)
+    }
+}
+
+impl fmt::Display for Jid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.id, match self.kind {
+            JidKind::Individual => "@c.us",
+            JidKind::Group => "@g.us",
+            JidKind::Broadcast => "@broadcast"
+        })
     }
 }
 
@@ -82,18 +205,19 @@ impl FromStr for Jid {
         let (id, surfix) = jid.split_at(at);
         Ok(Jid {
             id: id.to_string(),
-            is_group: match surfix {
-                "@c.us" => false,
-                "@g.us" => true,
-                "@s.whatsapp.net" => false,
-                "@broadcast" => false, //TODO
+            kind: match surfix {
+                "@c.us" => JidKind::Individual,
+                "@g.us" => JidKind::Group,
+                "@s.whatsapp.net" => JidKind::Individual,
+                "@broadcast" => JidKind::Broadcast,
                 _ => return Err("invalid surfix".into())
             },
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub struct Contact {
     ///name used in phonebook, set by user
     pub name: Option<String>,
@@ -102,38 +226,164 @@ pub struct Contact {
     pub jid: Jid,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub struct Chat {
     pub name: Option<String>,
     pub jid: Jid,
     pub last_activity: i64,
-    pub pin_time: Option<i64>,
-    pub mute_until: Option<i64>,
+    pub pin_time: Option<NaiveDateTime>,
+    pub muted: Muted,
     pub spam: bool,
     pub read_only: bool,
+    pub archived: bool,
 }
 
+impl Chat {
+    /// Apply an action received from the app to this chat
+    pub fn apply_action(&mut self, action: ChatAction) {
+        match action {
+            ChatAction::Spam => self.spam = true,
+            ChatAction::Archive => self.archived = true,
+            ChatAction::Unarchive => self.archived = false,
+            ChatAction::Pin(time) => self.pin_time = Some(time),
+            ChatAction::Unpin => self.pin_time = None,
+            ChatAction::Mute(muted) => self.muted = muted,
+            ChatAction::Unmute => self.muted = Muted::No,
+            ChatAction::Delete | ChatAction::Clear | ChatAction::Read | ChatAction::Unread => {}
+        }
+    }
+}
+
+
+/// Full-size profile picture
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
+pub struct ProfilePicture {
+    pub url: String,
+    /// Changes with each new picture, usable to invalidate cached downloads
+    pub tag: Option<String>,
+}
+
+/// Media connection info of the server, authorizes uploads to the media hosts
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaConn {
+    pub auth: String,
+    /// Validity of ```auth```
+    pub ttl: Duration,
+    /// Hosts for uploads and downloads, in order of preference
+    pub hosts: Vec<String>,
+}
+
+/// Link preview generated by the server for an url
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
+pub struct UrlPreview {
+    pub url: String,
+    pub canonical_url: Option<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Jpeg thumbnail of the linked page
+    pub thumbnail: Option<Vec<u8>>,
+}
 
-#[derive(Debug, Copy, Clone)]
+/// Identity key of a contact, it changes together with the security code of the chat
+/// e.g. if the contact reinstalled WhatsApp
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
+pub struct Identity {
+    pub jid: Jid,
+    pub key: Vec<u8>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
+pub enum Muted {
+    No,
+    Until(NaiveDateTime),
+    Forever,
+}
+
+impl Muted {
+    /// Whether notifications are muted at ```time```
+    pub fn is_muted_at(&self, time: NaiveDateTime) -> bool {
+        match *self {
+            Muted::No => false,
+            Muted::Until(until) => time < until,
+            Muted::Forever => true
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub enum PresenceStatus {
     Unavailable,
     Available,
     Typing,
     Recording,
+    /// Stopped typing or recording
+    Paused,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
+pub enum ParticipantRole {
+    Regular,
+    Admin,
+    /// Creator of the group, can't be demoted by other admins
+    SuperAdmin,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
+pub struct Participant {
+    pub jid: Jid,
+    pub role: ParticipantRole,
+}
+
+impl Participant {
+    pub fn is_admin(&self) -> bool {
+        self.role != ParticipantRole::Regular
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub struct GroupMetadata {
     pub creation_time: i64,
     pub id: Jid,
     pub owner: Option<Jid>,
-    pub participants: Vec<(Jid, bool)>,
+    pub participants: Vec<Participant>,
     pub subject: String,
     pub subject_owner: Jid,
     pub subject_time: i64,
 }
 
-#[derive(Debug, Copy, Clone)]
+impl GroupMetadata {
+    /// Apply a change of the participants received from the server to this group
+    pub fn apply_participants_change(&mut self, change: GroupParticipantsChange, participants: &[Jid]) {
+        match change {
+            GroupParticipantsChange::Add => {
+                for jid in participants {
+                    if !self.participants.iter().any(|participant| &participant.jid == jid) {
+                        self.participants.push(Participant { jid: jid.clone(), role: ParticipantRole::Regular });
+                    }
+                }
+            }
+            GroupParticipantsChange::Remove => self.participants.retain(|participant| !participants.contains(&participant.jid)),
+            GroupParticipantsChange::Promote | GroupParticipantsChange::Demote => {
+                let role = if change == GroupParticipantsChange::Promote { ParticipantRole::Admin } else { ParticipantRole::Regular };
+                for participant in self.participants.iter_mut().filter(|participant| participants.contains(&participant.jid)) {
+                    participant.role = role;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub enum GroupParticipantsChange {
     Add,
     Remove,
@@ -141,22 +391,39 @@ pub enum GroupParticipantsChange {
     Demote,
 }
 
-#[derive(Debug, Copy, Clone)]
+/// Outcome of a ```GroupParticipantsChange``` for a single participant
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
+pub enum ParticipantChangeStatus {
+    Success,
+    /// Participant can't be added due to its privacy settings, so it has to be invited with the code instead.
+    /// The expiration is missing if the server didn't send a valid one
+    InviteRequired { code: String, expiration: Option<NaiveDateTime> },
+    /// Error code sent by the server
+    Failed(u16),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub enum ChatAction {
-    Add,
-    Remove,
+    /// Chat was reported as spam
+    Spam,
+    /// Chat was deleted together with its messages
+    Delete,
     Archive,
     Unarchive,
     Clear,
-    Pin(i64),
+    Pin(NaiveDateTime),
     Unpin,
-    Mute(i64),
+    /// ```Muted::No``` is equivalent to ```Unmute```
+    Mute(Muted),
     Unmute,
     Read,
     Unread,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub enum MediaType {
     Image,
     Video,
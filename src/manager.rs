@@ -0,0 +1,238 @@
+//! Manages the connections of several accounts, e.g. for bots serving multiple phone numbers.
+//! Events of all connections are delivered to a single shared ```ManagerHandler``` together with the account they belong to.
+//! Each account runs on its own websocket thread, while the media transfers of all accounts share one worker pool
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+#[cfg(feature = "qr")]
+use qrcode::QrCode;
+
+use connection;
+use connection::{WhatsappWebConnection, WhatsappWebHandler, State, UserData, PersistentSession, DisconnectReason, ConnectionOptions};
+use worker_pool::WorkerPool;
+use message::ChatMessage;
+use errors::*;
+
+pub type AccountConnection<M> = WhatsappWebConnection<AccountHandler<M>>;
+
+pub trait ManagerHandler: Send + Sync + Sized + 'static {
+    fn on_state_changed(&self, account: &str, connection: &AccountConnection<Self>, state: State);
+
+    fn on_user_data_changed(&self, account: &str, connection: &AccountConnection<Self>, user_data: UserData);
+
+    /// The session of the account changed and should be stored, so the account can be restored with ```ConnectionManager::add_account```
+    fn on_persistent_session_data_changed(&self, account: &str, persistent_session: PersistentSession);
+
+    fn on_disconnect(&self, account: &str, reason: DisconnectReason);
+
+    fn on_message(&self, account: &str, connection: &AccountConnection<Self>, message_new: bool, message: Box<ChatMessage>);
+
+    /// Error which occurred in the background of the account's connection
+    fn on_error(&self, _account: &str, _connection: &AccountConnection<Self>, _error: Error) {}
+
+    /// Error which occurred while logging in the account with a new session, e.g. ```ErrorKind::QrCode```
+    fn on_login_error(&self, _account: &str, _connection: &AccountConnection<Self>, _error: Error) {}
+}
+
+/// Forwards the events of one account to the ```ManagerHandler```
+pub struct AccountHandler<M: ManagerHandler> {
+    account: String,
+    handler: Arc<M>
+}
+
+impl<M: ManagerHandler> AccountHandler<M> {
+    pub fn account(&self) -> &str {
+        &self.account
+    }
+}
+
+impl<M: ManagerHandler> WhatsappWebHandler<AccountHandler<M>> for AccountHandler<M> {
+    fn on_state_changed(&self, connection: &AccountConnection<M>, state: State) {
+        self.handler.on_state_changed(&self.account, connection, state);
+    }
+
+    fn on_user_data_changed(&self, connection: &AccountConnection<M>, user_data: UserData) {
+        self.handler.on_user_data_changed(&self.account, connection, user_data);
+    }
+
+    fn on_persistent_session_data_changed(&self, persistent_session: PersistentSession) {
+        self.handler.on_persistent_session_data_changed(&self.account, persistent_session);
+    }
+
+    fn on_disconnect(&self, reason: DisconnectReason) {
+        self.handler.on_disconnect(&self.account, reason);
+    }
+
+    fn on_message(&self, connection: &AccountConnection<M>, message_new: bool, message: Box<ChatMessage>) {
+        self.handler.on_message(&self.account, connection, message_new, message);
+    }
+
+    fn on_error(&self, connection: &AccountConnection<M>, error: Error) {
+        self.handler.on_error(&self.account, connection, error);
+    }
+
+    fn on_login_error(&self, connection: &AccountConnection<M>, error: Error) {
+        self.handler.on_login_error(&self.account, connection, error);
+    }
+}
+
+pub struct ConnectionManager<M: ManagerHandler> {
+    handler: Arc<M>,
+    accounts: Mutex<HashMap<String, (AccountConnection<M>, JoinHandle<()>)>>,
+    media_workers: Arc<WorkerPool>
+}
+
+impl<M: ManagerHandler> ConnectionManager<M> {
+    pub fn new(handler: M) -> ConnectionManager<M> {
+        ConnectionManager {
+            handler: Arc::new(handler),
+            accounts: Mutex::new(HashMap::new()),
+            media_workers: connection::default_media_workers()
+        }
+    }
+
+    /// Restore ```account``` with the stored ```persistent_session```
+    pub fn add_account(&self, account: &str, persistent_session: PersistentSession) -> Result<AccountConnection<M>> {
+        self.insert_account(account, |handler, media_workers| {
+            connection::with_persistent_session_and_workers(persistent_session, ConnectionOptions::default(), handler, media_workers)
+        })
+    }
+
+    /// Create a new session for ```account```.
+    /// Will eventual call ```qr_cb``` with the generated qr-code.
+    #[cfg(feature = "qr")]
+    pub fn add_new_account<Q: Fn(QrCode) + Send + 'static>(&self, account: &str, qr_cb: Q) -> Result<AccountConnection<M>> {
+        self.insert_account(account, |handler, media_workers| {
+            connection::new_with_qr_string_callback(connection::qr_code_callback(qr_cb), ConnectionOptions::default(), handler, media_workers)
+        })
+    }
+
+    /// Create a new session for ```account```.
+    /// Will eventual call ```qr_cb``` with the content of the qr-code, which has to be rendered by the caller.
+    pub fn add_new_account_with_qr_string<Q: Fn(String) + Send + 'static>(&self, account: &str, qr_cb: Q) -> Result<AccountConnection<M>> {
+        self.insert_account(account, |handler, media_workers| {
+            connection::new_with_qr_string_callback(connection::qr_string_callback(qr_cb), ConnectionOptions::default(), handler, media_workers)
+        })
+    }
+
+    fn insert_account<C>(&self, account: &str, connect: C) -> Result<AccountConnection<M>>
+        where C: FnOnce(AccountHandler<M>, Arc<WorkerPool>) -> (AccountConnection<M>, JoinHandle<()>) {
+        let mut accounts = self.accounts.lock().unwrap();
+        if accounts.contains_key(account) {
+            bail! {"account {} already exists", account}
+        }
+        let (connection, join_handle) = connect(AccountHandler { account: account.to_string(), handler: self.handler.clone() }, self.media_workers.clone());
+        accounts.insert(account.to_string(), (connection.clone(), join_handle));
+        Ok(connection)
+    }
+
+    pub fn connection(&self, account: &str) -> Option<AccountConnection<M>> {
+        self.accounts.lock().unwrap().get(account).map(|&(ref connection, _)| connection.clone())
+    }
+
+    pub fn accounts(&self) -> Vec<String> {
+        self.accounts.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Set the maximum number of threads transferring media files concurrently for all accounts,
+    /// defaults to ```DEFAULT_MEDIA_WORKERS```
+    pub fn set_media_workers(&self, workers: usize) {
+        WorkerPool::set_max_workers(&self.media_workers, workers);
+    }
+
+    /// Disconnect ```account``` and wait until its connection thread finished.
+    /// Must not be called from within a handler callback.
+    pub fn remove_account(&self, account: &str) -> bool {
+        let entry = self.accounts.lock().unwrap().remove(account);
+        if let Some((connection, join_handle)) = entry {
+            connection.ws_disconnect();
+            join_handle.join().ok();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Disconnect all accounts and wait until their connection threads and the queued media transfers finished.
+    /// Must not be called from within a handler callback.
+    pub fn shutdown(&self) {
+        let accounts: Vec<_> = self.accounts.lock().unwrap().drain().map(|(_, account)| account).collect();
+        for &(ref connection, _) in &accounts {
+            connection.ws_disconnect();
+        }
+        for (_, join_handle) in accounts {
+            join_handle.join().ok();
+        }
+        self.media_workers.close_and_join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct RecordingHandler {
+        states: Mutex<Vec<(String, State)>>
+    }
+
+    impl ManagerHandler for RecordingHandler {
+        fn on_state_changed(&self, account: &str, _: &AccountConnection<Self>, state: State) {
+            self.states.lock().unwrap().push((account.to_string(), state));
+        }
+
+        fn on_user_data_changed(&self, _: &str, _: &AccountConnection<Self>, _: UserData) {}
+
+        fn on_persistent_session_data_changed(&self, _: &str, _: PersistentSession) {}
+
+        fn on_disconnect(&self, _: &str, _: DisconnectReason) {}
+
+        fn on_message(&self, _: &str, _: &AccountConnection<Self>, _: bool, _: Box<ChatMessage>) {}
+    }
+
+    fn add_account(manager: &ConnectionManager<RecordingHandler>, account: &str) -> Result<AccountConnection<RecordingHandler>> {
+        manager.insert_account(account, connection::new_unconnected)
+    }
+
+    #[test]
+    fn test_accounts() {
+        let manager = ConnectionManager::new(RecordingHandler::default());
+        add_account(&manager, "first").unwrap();
+        add_account(&manager, "second").unwrap();
+        assert!(add_account(&manager, "first").is_err());
+
+        let mut accounts = manager.accounts();
+        accounts.sort();
+        assert_eq!(accounts, vec!["first".to_string(), "second".to_string()]);
+        assert!(manager.connection("second").is_some());
+
+        assert!(manager.remove_account("first"));
+        assert!(!manager.remove_account("first"));
+        assert_eq!(manager.accounts(), vec!["second".to_string()]);
+        assert_eq!(*manager.handler.states.lock().unwrap(), vec![("first".to_string(), State::Disconnecting)]);
+    }
+
+    #[test]
+    fn test_shared_media_workers() {
+        let manager = ConnectionManager::new(RecordingHandler::default());
+        manager.set_media_workers(1);
+        let first = add_account(&manager, "first").unwrap();
+        let second = add_account(&manager, "second").unwrap();
+
+        let finished = Arc::new(AtomicUsize::new(0));
+        for connection in &[first, second] {
+            let finished = finished.clone();
+            connection.execute_media_job(move || {
+                thread::sleep(Duration::from_millis(20));
+                finished.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        manager.shutdown();
+        assert_eq!(finished.load(Ordering::SeqCst), 2);
+        assert!(manager.accounts().is_empty());
+    }
+}
@@ -2,16 +2,21 @@ extern crate base64;
 extern crate json;
 extern crate image;
 
-use std::io::Cursor;
-use std::thread;
-use std::sync::Arc;
+use std::io::{Read, Write, Cursor, BufWriter};
+use std::io;
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::{mpsc, Arc};
 
 use json_protocol::JsonNonNull;
-use image::{GenericImage, RGB};
+use image::{GenericImage, RGB, FilterType};
 use image::jpeg::JPEGEncoder;
 use reqwest;
+use reqwest::header::{ContentLength, ContentType};
+use reqwest::StatusCode;
+use url::form_urlencoded;
 
-use MediaType;
+use {MediaType, MediaConn};
 use crypto;
 use message::FileInfo;
 use connection::{WhatsappWebConnection, WhatsappWebHandler};
@@ -31,79 +36,506 @@ pub fn generate_thumbnail_and_get_size(image: &[u8]) -> (Vec<u8>, (u32, u32)) {
     (thumbnail_writter.into_inner(), size)
 }
 
-/// Download file from servers and decrypt it
-pub fn download_file(file_info: FileInfo, media_type: MediaType, callback: Box<Fn(Result<Vec<u8>>) + Send + Sync>) {
-    thread::spawn(move || {
-        let mut file_enc = Cursor::new(Vec::with_capacity(file_info.size));
+lazy_static! {
+    static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::new();
+}
+
+/// Client used by connections without a client configured in ```ConnectionOptions``` and by ```download_file_sync```
+pub(crate) fn shared_http_client() -> reqwest::Client {
+    HTTP_CLIENT.clone()
+}
+
+/// Images exceeding this width or height are downscaled if ```UploadOptions::downscale_images``` is set
+pub const IMAGE_MAX_DIMENSION: u32 = 1600;
+const IMAGE_QUALITY: u8 = 70;
+
+/// Options applied to a file before it is encrypted and uploaded
+#[derive(Debug, Copy, Clone, Default)]
+pub struct UploadOptions {
+    /// Re-encode images larger than ```IMAGE_MAX_DIMENSION```, like the official clients do.
+    /// Upload the image as ```MediaType::Document``` to send it unchanged
+    pub downscale_images: bool,
+    /// Remove EXIF metadata like the location or the camera model from jpeg images,
+    /// uploads fail with an error if the ```strip-exif``` feature isn't enabled
+    pub strip_exif: bool,
+}
+
+/// Downscale the image to ```IMAGE_MAX_DIMENSION``` and re-encode it as jpeg, returns None if the image is small enough
+pub fn downscale_image(image: &[u8]) -> Result<Option<Vec<u8>>> {
+    let image = image::load_from_memory(image).chain_err(|| "invalid image")?;
+
+    if image.width() <= IMAGE_MAX_DIMENSION && image.height() <= IMAGE_MAX_DIMENSION {
+        return Ok(None);
+    }
+
+    let image = image.resize(IMAGE_MAX_DIMENSION, IMAGE_MAX_DIMENSION, FilterType::Triangle).to_rgb();
+
+    let mut image_writter = Cursor::new(Vec::new());
+
+    JPEGEncoder::new_with_quality(&mut image_writter, IMAGE_QUALITY).encode(&image, image.width(), image.height(), RGB(8))?;
+
+    Ok(Some(image_writter.into_inner()))
+}
+
+/// Remove all APP1 segments, which contain the EXIF and XMP metadata, from a jpeg image.
+/// Files which are no valid jpeg images are returned unchanged
+#[cfg(feature = "strip-exif")]
+pub fn strip_exif(jpeg: &[u8]) -> Vec<u8> {
+    if !jpeg.starts_with(b"\xFF\xD8") {
+        return jpeg.to_vec();
+    }
+
+    let mut stripped = Vec::with_capacity(jpeg.len());
+    stripped.extend_from_slice(&jpeg[..2]);
+
+    let mut position = 2;
+    while position + 4 <= jpeg.len() {
+        if jpeg[position] != 0xFF {
+            return jpeg.to_vec();
+        }
+        let marker = jpeg[position + 1];
+        match marker {
+            // Start of scan, the entropy coded image data follows
+            0xDA => break,
+            0x01 | 0xD0...0xD7 => {
+                stripped.extend_from_slice(&jpeg[position..position + 2]);
+                position += 2;
+                continue;
+            }
+            _ => {}
+        }
+
+        let segment_end = position + 2 + ((jpeg[position + 2] as usize) << 8 | jpeg[position + 3] as usize);
+        if segment_end > jpeg.len() {
+            return jpeg.to_vec();
+        }
+        if marker != 0xE1 {
+            stripped.extend_from_slice(&jpeg[position..segment_end]);
+        }
+        position = segment_end;
+    }
+
+    stripped.extend_from_slice(&jpeg[position..]);
+    stripped
+}
+
+/// Path segment of the media type in upload urls
+fn mms_type(media_type: MediaType) -> &'static str {
+    match media_type {
+        MediaType::Image => "image",
+        MediaType::Video => "video",
+        MediaType::Audio => "audio",
+        MediaType::Document => "document",
+    }
+}
+
+/// Guess the mime type of a file by its magic bytes, falls back to a default of the media type
+pub fn guess_mime(file: &[u8], media_type: MediaType) -> &'static str {
+    if file.starts_with(b"\xFF\xD8\xFF") {
+        "image/jpeg"
+    } else if file.starts_with(b"\x89PNG\r\n\x1A\n") {
+        "image/png"
+    } else if file.starts_with(b"GIF87a") || file.starts_with(b"GIF89a") {
+        "image/gif"
+    } else if file.starts_with(b"RIFF") && file.get(8..12) == Some(&b"WEBP"[..]) {
+        "image/webp"
+    } else if file.starts_with(b"%PDF") {
+        "application/pdf"
+    } else if file.get(4..8) == Some(b"ftyp") {
+        // ISO base media files, told apart by their major brand
+        match file.get(8..12) {
+            Some(b"heic") | Some(b"heix") | Some(b"heim") | Some(b"heis") => "image/heic",
+            Some(b"mif1") | Some(b"msf1") => "image/heif",
+            Some(b"avif") | Some(b"avis") => "image/avif",
+            Some(b"M4A ") | Some(b"M4B ") => "audio/mp4",
+            _ => match media_type {
+                MediaType::Audio => "audio/mp4",
+                _ => "video/mp4"
+            }
+        }
+    } else if file.starts_with(b"OggS") {
+        "audio/ogg; codecs=opus"
+    } else if file.starts_with(b"ID3") || file.starts_with(b"\xFF\xFB") {
+        "audio/mpeg"
+    } else if file.starts_with(b"PK\x03\x04") {
+        "application/zip"
+    } else {
+        match media_type {
+            MediaType::Image => "image/jpeg",
+            MediaType::Video => "video/mp4",
+            MediaType::Audio => "audio/ogg; codecs=opus",
+            MediaType::Document => "application/octet-stream",
+        }
+    }
+}
+
+struct ProgressReader<R: Read> {
+    inner: R,
+    transferred: usize,
+    total: usize,
+    progress: Arc<Fn(usize, usize) + Send + Sync>,
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read > 0 {
+            self.transferred += read;
+            (self.progress)(self.transferred, self.total);
+        }
+        Ok(read)
+    }
+}
+
+fn copy_with_progress<R: Read, W: Write>(reader: &mut R, writer: &mut W, total: usize, progress: &Fn(usize, usize)) -> Result<usize> {
+    let mut buffer = [0u8; 16 * 1024];
+    let mut transferred = 0;
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(transferred);
+        }
+        writer.write_all(&buffer[..read])?;
+        transferred += read;
+        progress(transferred, total);
+    }
+}
+
+/// Download file from servers and decrypt it, the download is queued on the media worker pool of ```connection```
+pub fn download_file<H>(file_info: FileInfo, media_type: MediaType, connection: &WhatsappWebConnection<H>, callback: Box<Fn(Result<Vec<u8>>) + Send + Sync>)
+    where H: WhatsappWebHandler + Send + Sync + 'static {
+    download_file_with_progress(file_info, media_type, connection, Box::new(|_, _| {}), callback)
+}
+
+/// Download file from servers and decrypt it, ```progress``` is called with the transferred and total bytes
+pub fn download_file_with_progress<H>(file_info: FileInfo, media_type: MediaType, connection: &WhatsappWebConnection<H>,
+                                      progress: Box<Fn(usize, usize) + Send + Sync>, callback: Box<Fn(Result<Vec<u8>>) + Send + Sync>)
+    where H: WhatsappWebHandler + Send + Sync + 'static {
+    let client = connection.http_client();
+    let worker_connection = connection.clone();
+    connection.execute_media_job(move || {
+        callback(get_with_reauth(&client, &file_info, &worker_connection)
+            .and_then(|response| read_response(response, &file_info, media_type, &*progress)));
+    })
+}
+
+/// Download file from servers and decrypt it on the calling thread.
+/// Files referenced only by their ```direct_path``` have to be downloaded with ```download_file```
+pub fn download_file_sync(file_info: &FileInfo, media_type: MediaType) -> Result<Vec<u8>> {
+    if file_info.url.is_empty() {
+        bail! {"file without url, download it with download_file"}
+    }
+    let response = HTTP_CLIENT.get(&file_info.url).send().chain_err(|| "could not load file")?;
+    read_response(response, file_info, media_type, &|_, _| {})
+}
+
+/// Url of the file, built from the first media host and the ```direct_path``` if the message doesn't contain one.
+/// Must not be called on the websocket thread
+fn download_url<H>(file_info: &FileInfo, connection: &WhatsappWebConnection<H>) -> Result<String>
+    where H: WhatsappWebHandler + Send + Sync + 'static {
+    if !file_info.url.is_empty() {
+        return Ok(file_info.url.clone());
+    }
+    let direct_path = file_info.direct_path.as_ref().ok_or("file has neither an url nor a direct path")?;
+    let media_conn = get_media_conn_sync(connection)?;
+    let host = media_conn.hosts.first().ok_or("media connection without hosts")?;
+    Ok(format!("https://{}{}", host, direct_path))
+}
+
+/// Request the file, if it is referenced by its ```direct_path``` and the media host rejects the auth,
+/// the media connection is queried again and the request retried once.
+/// Must not be called on the websocket thread
+fn get_with_reauth<H>(client: &reqwest::Client, file_info: &FileInfo, connection: &WhatsappWebConnection<H>) -> Result<reqwest::Response>
+    where H: WhatsappWebHandler + Send + Sync + 'static {
+    let response = client.get(&download_url(file_info, connection)?).send().chain_err(|| "could not load file")?;
+    if response.status() == StatusCode::Unauthorized && file_info.url.is_empty() {
+        connection.invalidate_media_conn();
+        return client.get(&download_url(file_info, connection)?).send().chain_err(|| "could not load file");
+    }
+    Ok(response)
+}
+
+/// Read the encrypted file from ```response``` and decrypt it
+fn read_response(mut response: reqwest::Response, file_info: &FileInfo, media_type: MediaType, progress: &Fn(usize, usize)) -> Result<Vec<u8>> {
+    let mut file_enc = Cursor::new(Vec::with_capacity(file_info.size));
 
-        callback(reqwest::get(&file_info.url)
-            .map_err(|e| Error::with_chain(e, "could not load file"))
+    let status = response.status();
+    if !status.is_success() {
+        bail!{"received http status code {}", status.as_u16()}
+    }
+
+    let total = response.headers().get::<ContentLength>().map_or(file_info.size, |length| length.0 as usize);
+    copy_with_progress(&mut response, &mut file_enc, total, progress).chain_err(|| "could not load file")?;
+
+    crypto::decrypt_media_message(&file_info.key, media_type, &file_enc.into_inner())
+}
+
+/// Download file from servers and decrypt it directly into a newly created file at ```path```,
+/// without buffering the whole file in memory. The file is removed again if the download fails
+pub fn download_file_to_path<H>(file_info: FileInfo, media_type: MediaType, path: &Path, connection: &WhatsappWebConnection<H>,
+                                callback: Box<Fn(Result<()>) + Send + Sync>)
+    where H: WhatsappWebHandler + Send + Sync + 'static {
+    let path = path.to_path_buf();
+    let client = connection.http_client();
+    let worker_connection = connection.clone();
+    connection.execute_media_job(move || {
+        callback(get_with_reauth(&client, &file_info, &worker_connection)
             .and_then(|mut response| {
                 let status = response.status();
-                if status.is_success() {
-                    response.copy_to(&mut file_enc)
-                        .map_err(|e| Error::with_chain(e, "could not load file"))
-                } else {
+                if !status.is_success() {
                     bail!{"received http status code {}", status.as_u16()}
                 }
-            })
-            .and_then(|_| crypto::decrypt_media_message(&file_info.key, media_type, &file_enc.into_inner())));
-    });
+
+                let mut file = BufWriter::new(File::create(&path).chain_err(|| "could not create file")?);
+                crypto::decrypt_media_stream(&file_info.key, media_type, &mut response, &mut file)
+                    .and_then(|_| file.flush().chain_err(|| "could not write file"))
+                    .map_err(|err| {
+                        fs::remove_file(&path).ok();
+                        err
+                    })
+            }));
+    })
 }
 
 /// Upload file to servers and encrypt it
 pub fn upload_file<H>(file: &[u8], media_type: MediaType, connection: &WhatsappWebConnection<H>, callback: Box<Fn(Result<FileInfo>) + Send + Sync>)
     where H: WhatsappWebHandler + Send + Sync + 'static {
-    let file_hash = crypto::sha256(file);
-
-    let file_hash = Arc::new(file_hash);
-    let callback = Arc::new(callback);
-
-    let (file_encrypted, media_key) = crypto::encrypt_media_message(media_type, file);
-    let file_encrypted_hash = crypto::sha256(&file_encrypted);
-
-
-    //Todo refactoring, remove arc -> request_file_upload fnonce
-    let file_encrypted_hash = Arc::new(file_encrypted_hash);
-    let file_encrypted = Arc::new(file_encrypted);
-    let media_key = Arc::new(media_key);
-    let file_len = file.len();
-
-    connection.request_file_upload(&file_hash.clone(), media_type, Box::new(move |url: Result<&str>| {
-        match url {
-            Ok(url) => {
-                let url = url.to_string();
-                let file_hash = file_hash.clone();
-                let file_encrypted_hash = file_encrypted_hash.clone();
-                let file_encrypted = file_encrypted.clone();
-                let media_key = media_key.clone();
-                let callback = callback.clone();
-
-                thread::spawn(move || {
-                    let form = reqwest::multipart::Form::new()
-                        .text("hash", base64::encode(&file_encrypted_hash.to_vec()))
-                        .part("file", reqwest::multipart::Part::reader(Cursor::new(file_encrypted.to_vec()))
-                            .mime(reqwest::mime::APPLICATION_OCTET_STREAM));
-
-                    let file_info = reqwest::Client::new().post(url.as_str())
-                        .multipart(form)
-                        .send()
-                        .and_then(|mut response| response.text())
-                        .map_err(|e| Error::with_chain(e, "could not upload file"))
-                        .and_then(|response| json::parse(&response).map_err(|e| (Error::with_chain(e, "invalid response"))))
-                        .and_then(|json| json.get_str("url").map(|url| url.to_string()))
-                        .map(|url| FileInfo {
-                            mime: "image/jpeg".to_string(),
-                            sha256: file_hash.to_vec(),
-                            enc_sha256: file_encrypted_hash.to_vec(),
-                            key: media_key.to_vec(),
-                            url,
-                            size: file_len, //Or encrypted file size ??
-                        });
-                    callback(file_info);
-                });
+    upload_file_with_progress(file, media_type, connection, Box::new(|_, _| {}), callback)
+}
+
+/// Upload file to servers and encrypt it, ```progress``` is called with the transferred and total bytes
+pub fn upload_file_with_progress<H>(file: &[u8], media_type: MediaType, connection: &WhatsappWebConnection<H>,
+                                    progress: Box<Fn(usize, usize) + Send + Sync>, callback: Box<Fn(Result<FileInfo>) + Send + Sync>)
+    where H: WhatsappWebHandler + Send + Sync + 'static {
+    upload_file_with_options(file, media_type, UploadOptions::default(), connection, progress, callback)
+}
+
+/// Upload file to servers and encrypt it, after applying the given ```options```.
+/// To get the dimensions of a downscaled image, downscale it with ```downscale_image``` beforehand
+pub fn upload_file_with_options<H>(file: &[u8], media_type: MediaType, options: UploadOptions, connection: &WhatsappWebConnection<H>,
+                                   progress: Box<Fn(usize, usize) + Send + Sync>, callback: Box<Fn(Result<FileInfo>) + Send + Sync>)
+    where H: WhatsappWebHandler + Send + Sync + 'static {
+    let file = file.to_vec();
+    let worker_connection = connection.clone();
+    let client = connection.http_client();
+    let progress: Arc<Fn(usize, usize) + Send + Sync> = Arc::from(progress);
+    // Encrypting, downscaling and stripping the metadata run on the media worker pool as well,
+    // as this function is usually called from a handler on the websocket thread
+    connection.execute_media_job(move || {
+        callback(EncryptedUpload::new(&file, media_type, options).and_then(|upload| {
+            let media_conn = get_media_conn_sync(&worker_connection)?;
+            upload.post_with_reauth(&client, &media_conn, media_type, &worker_connection, progress)
+        }));
+    })
+}
+
+/// Upload file to servers and encrypt it on the calling thread.
+/// Must not be called from within a ```WhatsappWebHandler``` callback, as it waits for the response of the connection
+pub fn upload_file_sync<H>(file: &[u8], media_type: MediaType, connection: &WhatsappWebConnection<H>) -> Result<FileInfo>
+    where H: WhatsappWebHandler + Send + Sync + 'static {
+    let upload = EncryptedUpload::new(file, media_type, UploadOptions::default())?;
+
+    let media_conn = get_media_conn_sync(connection)?;
+    upload.post_with_reauth(&connection.http_client(), &media_conn, media_type, connection, Arc::new(|_, _| {}))
+}
+
+/// Must not be called on the websocket thread, as it waits for the response of the connection
+fn get_media_conn_sync<H>(connection: &WhatsappWebConnection<H>) -> Result<MediaConn>
+    where H: WhatsappWebHandler + Send + Sync + 'static {
+    let (sender, receiver) = mpsc::channel();
+    connection.get_media_conn(Box::new(move |media_conn: Result<MediaConn>| {
+        sender.send(media_conn).ok();
+    }));
+
+    receiver.recv().chain_err(|| "connection closed")?.chain_err(|| "could not query media connection")
+}
+
+struct EncryptedUpload {
+    file_hash: Vec<u8>,
+    file_encrypted_hash: Vec<u8>,
+    file_encrypted: SharedBuffer,
+    media_key: Vec<u8>,
+    sidecar: Option<Vec<u8>>,
+    file_len: usize,
+    mime: &'static str,
+}
+
+impl EncryptedUpload {
+    fn new(file: &[u8], media_type: MediaType, options: UploadOptions) -> Result<EncryptedUpload> {
+        let downscaled_image;
+        let file = match media_type {
+            MediaType::Image if options.downscale_images => match downscale_image(file)? {
+                Some(image) => {
+                    downscaled_image = image;
+                    &downscaled_image[..]
+                }
+                None => file
+            },
+            _ => file
+        };
+
+        #[cfg(feature = "strip-exif")]
+        let stripped_image;
+        #[cfg(feature = "strip-exif")]
+        let file = match media_type {
+            MediaType::Image if options.strip_exif => {
+                stripped_image = strip_exif(file);
+                &stripped_image[..]
+            }
+            _ => file
+        };
+        #[cfg(not(feature = "strip-exif"))]
+        {
+            if media_type == MediaType::Image && options.strip_exif {
+                bail! {"stripping EXIF metadata requires the strip-exif feature"}
             }
-            Err(err) => callback(Err(err).chain_err(|| "could not request file upload"))
         }
-    }))
-}
\ No newline at end of file
+
+        let (file_encrypted, media_key) = crypto::encrypt_media_message(media_type, file);
+        let sidecar = match media_type {
+            MediaType::Video | MediaType::Audio => Some(crypto::generate_media_sidecar(&media_key, media_type, &file_encrypted)),
+            _ => None
+        };
+
+        Ok(EncryptedUpload {
+            file_hash: crypto::sha256(file),
+            file_encrypted_hash: crypto::sha256(&file_encrypted),
+            file_encrypted: SharedBuffer(Arc::new(file_encrypted)),
+            media_key,
+            sidecar,
+            file_len: file.len(),
+            mime: guess_mime(file, media_type),
+        })
+    }
+
+    /// Post the file, if the media host rejects the auth of ```media_conn``` it is queried again and the upload retried once.
+    /// Must not be called on the websocket thread
+    fn post_with_reauth<H>(&self, client: &reqwest::Client, media_conn: &MediaConn, media_type: MediaType, connection: &WhatsappWebConnection<H>,
+                           progress: Arc<Fn(usize, usize) + Send + Sync>) -> Result<FileInfo>
+        where H: WhatsappWebHandler + Send + Sync + 'static {
+        match self.post(client, media_conn, media_type, progress.clone()) {
+            Err(Error(ErrorKind::MediaUnauthorized, _)) => {
+                connection.invalidate_media_conn();
+                let media_conn = get_media_conn_sync(connection)?;
+                self.post(client, &media_conn, media_type, progress)
+            }
+            result => result
+        }
+    }
+
+    /// Post the encrypted file to the first media host of ```media_conn```
+    fn post(&self, client: &reqwest::Client, media_conn: &MediaConn, media_type: MediaType, progress: Arc<Fn(usize, usize) + Send + Sync>) -> Result<FileInfo> {
+        let host = media_conn.hosts.first().ok_or("media connection without hosts")?;
+        let token = base64::encode_config(&self.file_encrypted_hash, base64::URL_SAFE_NO_PAD);
+        let url = format!("https://{}/mms/{}/{}?auth={}&token={}", host, mms_type(media_type), token,
+                          form_urlencoded::byte_serialize(media_conn.auth.as_bytes()).collect::<String>(), token);
+
+        let total = self.file_encrypted.0.len();
+        let body = reqwest::Body::sized(ProgressReader {
+            inner: Cursor::new(self.file_encrypted.clone()),
+            transferred: 0,
+            total,
+            progress
+        }, total as u64);
+
+        let mut response = client.post(&url)
+            .header(ContentType::octet_stream())
+            .body(body)
+            .send()
+            .chain_err(|| "could not upload file")?;
+        if response.status() == StatusCode::Unauthorized {
+            bail!{ErrorKind::MediaUnauthorized}
+        }
+
+        let response = response.text().chain_err(|| "could not upload file")?;
+        let response = UploadResponse::parse(&json::parse(&response).chain_err(|| "invalid response")?)?;
+        Ok(FileInfo {
+            mime: self.mime.to_string(),
+            sha256: self.file_hash.clone(),
+            enc_sha256: self.file_encrypted_hash.clone(),
+            key: self.media_key.clone(),
+            url: response.url,
+            size: self.file_len, //Or encrypted file size ??
+            sidecar: self.sidecar.clone(),
+            direct_path: response.direct_path,
+        })
+    }
+}
+
+/// Response of the media host to an upload
+#[derive(Debug, Clone, PartialEq)]
+struct UploadResponse {
+    url: String,
+    direct_path: Option<String>,
+}
+
+impl UploadResponse {
+    fn parse(json: &json::JsonValue) -> Result<UploadResponse> {
+        Ok(UploadResponse {
+            url: json.get_str("url")?.to_string(),
+            direct_path: json["direct_path"].as_str().map(|direct_path| direct_path.to_string())
+        })
+    }
+}
+
+/// Encrypted file shared between upload attempts
+#[derive(Clone)]
+struct SharedBuffer(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for SharedBuffer {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_upload_response() {
+        let response = json::parse(r#"{"url": "https://mmg.whatsapp.net/d/f/abc.enc", "direct_path": "/v/t62/abc.enc"}"#).unwrap();
+        assert_eq!(UploadResponse::parse(&response).unwrap(), UploadResponse {
+            url: "https://mmg.whatsapp.net/d/f/abc.enc".to_string(),
+            direct_path: Some("/v/t62/abc.enc".to_string())
+        });
+
+        let response = json::parse(r#"{"url": "https://mmg.whatsapp.net/d/f/abc.enc"}"#).unwrap();
+        assert_eq!(UploadResponse::parse(&response).unwrap().direct_path, None);
+
+        assert!(UploadResponse::parse(&json::parse(r#"{"direct_path": "/v/t62/abc.enc"}"#).unwrap()).is_err());
+    }
+
+    fn ftyp(brand: &[u8]) -> Vec<u8> {
+        [&b"\x00\x00\x00\x18ftyp"[..], brand, b"\x00\x00\x00\x00"].concat()
+    }
+
+    #[test]
+    fn test_guess_mime_iso_base_media() {
+        assert_eq!(guess_mime(&ftyp(b"heic"), MediaType::Image), "image/heic");
+        assert_eq!(guess_mime(&ftyp(b"heix"), MediaType::Image), "image/heic");
+        assert_eq!(guess_mime(&ftyp(b"mif1"), MediaType::Image), "image/heif");
+        assert_eq!(guess_mime(&ftyp(b"avif"), MediaType::Image), "image/avif");
+        assert_eq!(guess_mime(&ftyp(b"M4A "), MediaType::Audio), "audio/mp4");
+        assert_eq!(guess_mime(&ftyp(b"isom"), MediaType::Video), "video/mp4");
+        assert_eq!(guess_mime(&ftyp(b"mp42"), MediaType::Audio), "audio/mp4");
+        assert_eq!(guess_mime(&ftyp(b"isom"), MediaType::Document), "video/mp4");
+    }
+
+    #[test]
+    #[cfg(feature = "strip-exif")]
+    fn test_strip_exif() {
+        let jpeg = [
+            &b"\xFF\xD8"[..],
+            b"\xFF\xE0\x00\x04\x4A\x46",
+            b"\xFF\xE1\x00\x06Exif",
+            b"\xFF\xDA\x00\x02\x12\x34\xFF\xD9"
+        ].concat();
+
+        assert_eq!(strip_exif(&jpeg), [
+            &b"\xFF\xD8"[..],
+            b"\xFF\xE0\x00\x04\x4A\x46",
+            b"\xFF\xDA\x00\x02\x12\x34\xFF\xD9"
+        ].concat());
+    }
+}
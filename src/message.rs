@@ -1,18 +1,29 @@
 use std::time::Duration;
 use std::str::FromStr;
+use std::fmt;
 
 use protobuf;
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, DateTime, Utc, TimeZone};
 use protobuf::Message;
 use ring::rand::{SystemRandom, SecureRandom};
+#[cfg(feature = "media")]
+use image;
 
 use super::message_wire;
-use super::Jid;
+use crypto;
+use super::{Jid, JidKind};
 use errors::*;
 
-#[derive(Debug, Clone, PartialOrd, PartialEq)]
+#[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub struct MessageId(pub String);
 
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
 impl MessageId {
     pub fn generate() -> MessageId {
         let mut message_id_binary = vec![0u8; 12];
@@ -24,59 +35,129 @@ impl MessageId {
 }
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub enum Peer {
     Individual(Jid),
     Group { group: Jid, participant: Jid },
+    /// Status update or broadcast message of ```sender```
+    Broadcast { broadcast: Jid, sender: Jid },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub enum PeerAck {
     Individual(Jid),
     GroupIndividual { group: Jid, participant: Jid },
     GroupAll(Jid),
+    /// Recipient of a broadcast message or viewer of a status update
+    BroadcastIndividual { broadcast: Jid, recipient: Jid },
+    BroadcastAll(Jid),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub enum Direction {
     Sending(Jid),
     Receiving(Peer),
 }
 
+impl Peer {
+    /// Peer of a message in the chat ```chat```, ```participant``` is the sender within a group or broadcast
+    fn new(chat: Jid, participant: Option<Jid>) -> Peer {
+        match participant {
+            Some(sender) if chat.is_broadcast() => Peer::Broadcast { broadcast: chat, sender },
+            Some(participant) => Peer::Group { group: chat, participant },
+            None => Peer::Individual(chat)
+        }
+    }
+
+    /// Jid of the chat, the group for messages within a group and the broadcast for status updates
+    pub fn chat_jid(&self) -> &Jid {
+        match *self {
+            Peer::Individual(ref jid) | Peer::Group { group: ref jid, .. } | Peer::Broadcast { broadcast: ref jid, .. } => jid
+        }
+    }
+}
+
+impl PeerAck {
+    /// Acknowledging side of a message sent by the own user to ```receiver```
+    fn new(receiver: Jid, participant: Option<Jid>) -> PeerAck {
+        match participant {
+            Some(recipient) if receiver.is_broadcast() => PeerAck::BroadcastIndividual { broadcast: receiver, recipient },
+            Some(participant) => PeerAck::GroupIndividual { group: receiver, participant },
+            None if receiver.is_group() => PeerAck::GroupAll(receiver),
+            None if receiver.is_broadcast() => PeerAck::BroadcastAll(receiver),
+            None => PeerAck::Individual(receiver)
+        }
+    }
+}
+
 impl Direction {
+    /// Jid to send a reply to, the group for messages within a group and the sender for individual messages and status updates
+    pub fn reply_jid(&self) -> &Jid {
+        match *self {
+            Direction::Sending(ref jid) => jid,
+            Direction::Receiving(Peer::Broadcast { ref sender, .. }) => sender,
+            Direction::Receiving(ref peer) => peer.chat_jid()
+        }
+    }
+
     fn parse(mut key: message_wire::MessageKey) -> Result<Direction> {
         let remote_jid = Jid::from_str(&key.take_remoteJid())?;
         Ok(if key.get_fromMe() {
             Direction::Sending(remote_jid)
         } else {
-            Direction::Receiving(if key.has_participant() {
-                Peer::Group { group: remote_jid, participant: Jid::from_str(&key.take_participant())? }
-            } else {
-                Peer::Individual(remote_jid)
-            })
+            let participant = if key.has_participant() { Some(Jid::from_str(&key.take_participant())?) } else { None };
+            Direction::Receiving(Peer::new(remote_jid, participant))
         })
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub enum MessageAckLevel {
-    PendingSend = 0,
-    Send = 1,
-    Received = 2,
-    Read = 3,
-    Played = 4,
+    PendingSend,
+    Send,
+    Received,
+    Read,
+    Played,
+    /// Message couldn't be delivered, e.g. because the recipient blocked the own user
+    Error(u8),
+}
+
+impl MessageAckLevel {
+    /// Whether this level is a progression of ```other```, errors supersede every other level
+    pub fn supersedes(&self, other: &MessageAckLevel) -> bool {
+        fn rank(level: &MessageAckLevel) -> u8 {
+            match *level {
+                MessageAckLevel::PendingSend => 0,
+                MessageAckLevel::Send => 1,
+                MessageAckLevel::Received => 2,
+                MessageAckLevel::Read => 3,
+                MessageAckLevel::Played => 4,
+                MessageAckLevel::Error(_) => 5
+            }
+        }
+        self != other && rank(self) >= rank(other)
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub enum MessageAckSide {
+    /// Ack of a message received by the own user, sent by ```Peer```
     Here(Peer),
+    /// Ack of a message sent by the own user, acknowledged by ```PeerAck```
     There(PeerAck),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub struct MessageAck {
     pub level: MessageAckLevel,
-    pub time: Option<i64>,
+    /// Time the ack was issued, if it was sent along
+    pub time: Option<DateTime<Utc>>,
     pub id: MessageId,
     pub side: MessageAckSide,
 }
@@ -85,47 +166,40 @@ impl MessageAck {
     pub fn from_server_message(message_id: &str, level: MessageAckLevel, sender: Jid, receiver: Jid, participant: Option<Jid>, time: i64, own_jid: &Jid) -> MessageAck {
         MessageAck {
             level,
-            time: Some(time),
+            time: Utc.timestamp_opt(time, 0).single(),
             id: MessageId(message_id.to_string()),
             side: if own_jid == &sender {
-                MessageAckSide::There(if let Some(participant) = participant {
-                    PeerAck::GroupIndividual { group: receiver, participant }
-                } else {
-                    PeerAck::Individual(receiver)
-                })
+                MessageAckSide::There(PeerAck::new(receiver, participant))
             } else {
-                MessageAckSide::Here(if let Some(participant) = participant {
-                    Peer::Group { group: sender, participant }
-                } else {
-                    Peer::Individual(sender)
-                })
+                MessageAckSide::Here(Peer::new(sender, participant))
             },
         }
     }
 
-    pub fn from_app_message(message_id: MessageId, level: MessageAckLevel, jid: Jid, participant: Option<Jid>, owner: bool) -> MessageAck {
+    /// Whether the acknowledged message was sent by the own user
+    pub fn is_for_own_message(&self) -> bool {
+        match self.side {
+            MessageAckSide::There(_) => true,
+            MessageAckSide::Here(_) => false
+        }
+    }
+
+    pub fn from_app_message(message_id: MessageId, level: MessageAckLevel, jid: Jid, participant: Option<Jid>, owner: bool, time: Option<i64>) -> MessageAck {
         MessageAck {
             level,
-            time: None,
+            time: time.and_then(|time| Utc.timestamp_opt(time, 0).single()),
             id: message_id,
             side: if owner {
-                MessageAckSide::There(if jid.is_group {
-                    PeerAck::GroupAll(jid)
-                } else {
-                    PeerAck::Individual(jid)
-                })
+                MessageAckSide::There(PeerAck::new(jid, None))
             } else {
-                MessageAckSide::Here(if let Some(participant) = participant {
-                    Peer::Group { group: jid, participant }
-                } else {
-                    Peer::Individual(jid)
-                })
+                MessageAckSide::Here(Peer::new(jid, participant))
             },
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub struct FileInfo {
     pub url: String,
     pub mime: String,
@@ -133,20 +207,292 @@ pub struct FileInfo {
     pub enc_sha256: Vec<u8>,
     pub size: usize,
     pub key: Vec<u8>,
+    /// Streaming sidecar of videos and audios
+    pub sidecar: Option<Vec<u8>>,
+    /// Path of the file on the media hosts, newer messages may reference the file only by it
+    pub direct_path: Option<String>,
+}
+
+impl FileInfo {
+    /// Check the size and hash of the downloaded ```plaintext```, the decryption only checks the truncated mac of the encrypted file
+    pub fn verify(&self, plaintext: &[u8]) -> Result<()> {
+        if plaintext.len() != self.size {
+            bail! {"file has {} bytes instead of {}", plaintext.len(), self.size}
+        }
+        if crypto::sha256(plaintext) != self.sha256 {
+            bail! {"file hash mismatch"}
+        }
+        Ok(())
+    }
+}
+
+/// Invitation to join a group, which can be accepted with ```WhatsappWebConnection::accept_group_invite```
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
+pub struct GroupInvite {
+    pub group: Jid,
+    pub code: String,
+    /// The invite can't be accepted afterwards, ```None``` if it doesn't expire
+    pub expiration: Option<NaiveDateTime>,
+    pub name: String,
+    pub caption: String,
+    pub thumbnail: Vec<u8>,
+}
+
+/// Key of a message as referenced by protocol messages, jids are kept as received
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
+pub struct MessageKey {
+    pub remote_jid: String,
+    pub from_me: bool,
+    pub id: String,
+    pub participant: Option<String>,
+}
+
+impl MessageKey {
+    /// Key of the message with ```id``` sent in ```direction```
+    pub fn new(id: MessageId, direction: &Direction) -> MessageKey {
+        match *direction {
+            Direction::Sending(ref jid) => MessageKey { remote_jid: jid.to_message_jid(), from_me: true, id: id.0, participant: None },
+            Direction::Receiving(ref peer) => MessageKey::from((id, peer.clone()))
+        }
+    }
+
+    pub fn message_id(&self) -> MessageId {
+        MessageId(self.id.clone())
+    }
+
+    /// Direction of the referenced message with parsed jids
+    pub fn direction(&self) -> Result<Direction> {
+        let remote_jid = Jid::from_str(&self.remote_jid)?;
+        Ok(if self.from_me {
+            Direction::Sending(remote_jid)
+        } else {
+            let participant = match self.participant {
+                Some(ref participant) => Some(Jid::from_str(participant)?),
+                None => None
+            };
+            Direction::Receiving(Peer::new(remote_jid, participant))
+        })
+    }
+
+    /// Id and sender of a received message, fails if the key references a message sent by the own user
+    pub fn into_peer(self) -> Result<(MessageId, Peer)> {
+        match self.direction()? {
+            Direction::Receiving(peer) => Ok((MessageId(self.id), peer)),
+            Direction::Sending(_) => bail! {"key references an own message"}
+        }
+    }
+
+    fn from_proto(mut key: message_wire::MessageKey) -> MessageKey {
+        MessageKey {
+            remote_jid: key.take_remoteJid(),
+            from_me: key.get_fromMe(),
+            id: key.take_id(),
+            participant: if key.has_participant() { Some(key.take_participant()) } else { None },
+        }
+    }
+
+    fn into_proto(self) -> message_wire::MessageKey {
+        let mut key = message_wire::MessageKey::new();
+        key.set_remoteJid(self.remote_jid);
+        key.set_fromMe(self.from_me);
+        key.set_id(self.id);
+        if let Some(participant) = self.participant {
+            key.set_participant(participant);
+        }
+        key
+    }
+}
+
+/// Key of a received message
+impl From<(MessageId, Peer)> for MessageKey {
+    fn from((id, peer): (MessageId, Peer)) -> MessageKey {
+        match peer {
+            Peer::Individual(jid) => MessageKey { remote_jid: jid.to_message_jid(), from_me: false, id: id.0, participant: None },
+            Peer::Group { group, participant } | Peer::Broadcast { broadcast: group, sender: participant } => MessageKey {
+                remote_jid: group.to_message_jid(),
+                from_me: false,
+                id: id.0,
+                participant: Some(participant.to_message_jid())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
+pub enum ProtocolMessageKind {
+    Revoke,
+    /// Disappearing messages timer of the chat changed, zero disables it
+    EphemeralSetting(Duration),
+    EphemeralSyncResponse,
+    HistorySyncNotification,
+    /// Replaces the content of the referenced message
+    MessageEdit(Box<ChatMessageContent>),
+    /// Type not known by this crate
+    Other(i32),
 }
 
-#[derive(Debug)]
+impl ProtocolMessageKind {
+    fn from_proto(protocol_message: &mut message_wire::ProtocolMessage) -> Result<ProtocolMessageKind> {
+        if !protocol_message.has_field_type() {
+            // Values missing in the enum definition end up in the unknown fields
+            return Ok(protocol_message.get_unknown_fields().get(2)
+                .and_then(|values| values.varint.first().cloned())
+                .map_or(ProtocolMessageKind::Revoke, |value| ProtocolMessageKind::Other(value as i32)));
+        }
+
+        Ok(match protocol_message.get_field_type() {
+            message_wire::ProtocolMessage_TYPE::REVOKE => ProtocolMessageKind::Revoke,
+            message_wire::ProtocolMessage_TYPE::EPHEMERAL_SETTING =>
+                ProtocolMessageKind::EphemeralSetting(Duration::new(u64::from(protocol_message.get_ephemeralExpiration()), 0)),
+            message_wire::ProtocolMessage_TYPE::EPHEMERAL_SYNC_RESPONSE => ProtocolMessageKind::EphemeralSyncResponse,
+            message_wire::ProtocolMessage_TYPE::HISTORY_SYNC_NOTIFICATION => ProtocolMessageKind::HistorySyncNotification,
+            message_wire::ProtocolMessage_TYPE::MESSAGE_EDIT =>
+                ProtocolMessageKind::MessageEdit(Box::new(ChatMessageContent::from_proto(protocol_message.take_editedMessage())?)),
+        })
+    }
+
+    fn into_proto(self, protocol_message: &mut message_wire::ProtocolMessage) {
+        match self {
+            ProtocolMessageKind::Revoke => protocol_message.set_field_type(message_wire::ProtocolMessage_TYPE::REVOKE),
+            ProtocolMessageKind::EphemeralSetting(duration) => {
+                protocol_message.set_field_type(message_wire::ProtocolMessage_TYPE::EPHEMERAL_SETTING);
+                protocol_message.set_ephemeralExpiration(duration.as_secs() as u32);
+            }
+            ProtocolMessageKind::EphemeralSyncResponse => protocol_message.set_field_type(message_wire::ProtocolMessage_TYPE::EPHEMERAL_SYNC_RESPONSE),
+            ProtocolMessageKind::HistorySyncNotification => protocol_message.set_field_type(message_wire::ProtocolMessage_TYPE::HISTORY_SYNC_NOTIFICATION),
+            ProtocolMessageKind::MessageEdit(content) => {
+                protocol_message.set_field_type(message_wire::ProtocolMessage_TYPE::MESSAGE_EDIT);
+                protocol_message.set_editedMessage(content.into_proto());
+            }
+            ProtocolMessageKind::Other(value) => protocol_message.mut_unknown_fields().add_varint(2, value as u64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub enum ChatMessageContent {
     Text(String),
-    Image(FileInfo, (u32, u32), Vec<u8>),
+    /// Image with its height and width, JPEG thumbnail and caption
+    Image(FileInfo, (u32, u32), Vec<u8>, Option<String>),
     Audio(FileInfo, Duration),
-    Document(FileInfo, String),
+    /// Video with its duration, JPEG thumbnail and caption
+    Video(FileInfo, Duration, Vec<u8>, Option<String>),
+    /// Document with file name, optional JPEG thumbnail used as preview, e.g. of the first page of a PDF, and caption
+    Document(FileInfo, String, Option<Vec<u8>>, Option<String>),
+    ProtocolMessage(MessageKey, ProtocolMessageKind),
+    GroupInvite(GroupInvite),
+    /// Text or media which quotes another message or mentions users
+    WithContext(Box<ChatMessageContent>, ContextInfo),
+    /// Message of a kind which isn't modeled yet, containing the raw protobuf encoded ```Message```
+    Unknown(Vec<u8>),
+}
+
+/// Message quoted by a reply and the users mentioned by a message
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
+pub struct ContextInfo {
+    pub quoted: Option<QuotedMessage>,
+    pub mentions: Vec<Jid>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
+pub struct QuotedMessage {
+    pub id: MessageId,
+    /// Sender of the quoted message, ```None``` if it was sent by this account
+    pub sender: Option<Jid>,
+    pub content: Box<ChatMessageContent>,
+}
+
+impl ContextInfo {
+    /// Parse the context, ```None``` if it neither quotes a message nor mentions users
+    fn from_proto(mut context_info: message_wire::ContextInfo) -> Result<Option<ContextInfo>> {
+        let quoted = match context_info.take_quotedMessage().into_iter().next() {
+            Some(quoted_message) => Some(QuotedMessage {
+                id: MessageId(context_info.take_stanzaId()),
+                sender: Jid::from_str(context_info.get_participant()).ok(),
+                content: Box::new(ChatMessageContent::from_proto(quoted_message)?)
+            }),
+            None => None
+        };
+        let mentions: Vec<_> = context_info.take_mentionedJid().into_iter().filter_map(|jid| Jid::from_str(&jid).ok()).collect();
+        if quoted.is_none() && mentions.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(ContextInfo { quoted, mentions }))
+    }
+
+    fn into_proto(self) -> message_wire::ContextInfo {
+        let mut context_info = message_wire::ContextInfo::new();
+        if let Some(quoted) = self.quoted {
+            context_info.set_stanzaId(quoted.id.0);
+            if let Some(sender) = quoted.sender {
+                context_info.set_participant(sender.to_message_jid());
+            }
+            context_info.mut_quotedMessage().push(quoted.content.into_proto());
+        }
+        for mention in self.mentions {
+            context_info.mut_mentionedJid().push(mention.to_message_jid());
+        }
+        context_info
+    }
+}
+
+/// Take the context of text or media, extended texts with a link preview aren't modeled and keep theirs
+fn take_context_info(message: &mut message_wire::Message) -> Option<message_wire::ContextInfo> {
+    if message.has_extendedTextMessage() && message.get_extendedTextMessage().get_matchedText().is_empty() {
+        Some(message.mut_extendedTextMessage().take_contextInfo())
+    } else if message.has_imageMessage() {
+        Some(message.mut_imageMessage().take_contextInfo())
+    } else if message.has_videoMessage() {
+        Some(message.mut_videoMessage().take_contextInfo())
+    } else if message.has_audioMessage() {
+        Some(message.mut_audioMessage().take_contextInfo())
+    } else if message.has_documentMessage() {
+        Some(message.mut_documentMessage().take_contextInfo())
+    } else {
+        None
+    }
 }
 
 impl ChatMessageContent {
+    /// Caption of an image, video or document, audios can't have one
+    pub fn caption(&self) -> Option<&str> {
+        match *self {
+            ChatMessageContent::Image(_, _, _, ref caption) |
+            ChatMessageContent::Video(_, _, _, ref caption) |
+            ChatMessageContent::Document(_, _, _, ref caption) => caption.as_ref().map(|caption| caption.as_str()),
+            ChatMessageContent::WithContext(ref content, _) => content.caption(),
+            _ => None
+        }
+    }
+
+    /// Embedded JPEG thumbnail of media or a group invite, if it has one
+    pub fn thumbnail(&self) -> Option<&[u8]> {
+        let thumbnail = match *self {
+            ChatMessageContent::Image(_, _, ref thumbnail, _) |
+            ChatMessageContent::Video(_, _, ref thumbnail, _) => &thumbnail[..],
+            ChatMessageContent::Document(_, _, Some(ref thumbnail), _) => &thumbnail[..],
+            ChatMessageContent::GroupInvite(ref invite) => &invite.thumbnail[..],
+            ChatMessageContent::WithContext(ref content, _) => return content.thumbnail(),
+            _ => return None
+        };
+        if thumbnail.is_empty() { None } else { Some(thumbnail) }
+    }
+
     fn from_proto(mut message: message_wire::Message) -> Result<ChatMessageContent> {
-        Ok(if message.has_conversation() {
+        let context_info = match take_context_info(&mut message) {
+            Some(context_info) => ContextInfo::from_proto(context_info)?,
+            None => None
+        };
+        let content = if message.has_conversation() {
             ChatMessageContent::Text(message.take_conversation())
+        } else if message.has_extendedTextMessage() && message.get_extendedTextMessage().get_matchedText().is_empty() {
+            ChatMessageContent::Text(message.take_extendedTextMessage().take_text())
         } else if message.has_imageMessage() {
             let mut image_message = message.take_imageMessage();
             ChatMessageContent::Image(FileInfo {
@@ -156,7 +502,9 @@ impl ChatMessageContent {
                 enc_sha256: image_message.take_fileEncSha256(),
                 size: image_message.get_fileLength() as usize,
                 key: image_message.take_mediaKey(),
-            }, (image_message.get_height(), image_message.get_width()), image_message.take_jpegThumbnail())
+                direct_path: non_empty(image_message.take_directPath()),
+                sidecar: None,
+            }, (image_message.get_height(), image_message.get_width()), image_message.take_jpegThumbnail(), non_empty(image_message.take_caption()))
         } else if message.has_audioMessage() {
             let mut audio_message = message.take_audioMessage();
             ChatMessageContent::Audio(FileInfo {
@@ -166,7 +514,21 @@ impl ChatMessageContent {
                 enc_sha256: audio_message.take_fileEncSha256(),
                 size: audio_message.get_fileLength() as usize,
                 key: audio_message.take_mediaKey(),
+                direct_path: non_empty(audio_message.take_directPath()),
+                sidecar: if audio_message.has_streamingSidecar() { Some(audio_message.take_streamingSidecar()) } else { None },
             }, Duration::new(u64::from(audio_message.get_seconds()), 0))
+        } else if message.has_videoMessage() {
+            let mut video_message = message.take_videoMessage();
+            ChatMessageContent::Video(FileInfo {
+                url: video_message.take_url(),
+                mime: video_message.take_mimetype(),
+                sha256: video_message.take_fileSha256(),
+                enc_sha256: video_message.take_fileEncSha256(),
+                size: video_message.get_fileLength() as usize,
+                key: video_message.take_mediaKey(),
+                direct_path: non_empty(video_message.take_directPath()),
+                sidecar: if video_message.has_streamingSidecar() { Some(video_message.take_streamingSidecar()) } else { None },
+            }, Duration::new(u64::from(video_message.get_seconds()), 0), video_message.take_jpegThumbnail(), non_empty(video_message.take_caption()))
         } else if message.has_documentMessage() {
             let mut document_message = message.take_documentMessage();
             ChatMessageContent::Document(FileInfo {
@@ -176,17 +538,60 @@ impl ChatMessageContent {
                 enc_sha256: document_message.take_fileEncSha256(),
                 size: document_message.get_fileLength() as usize,
                 key: document_message.take_mediaKey(),
-            }, document_message.take_fileName())
+                direct_path: non_empty(document_message.take_directPath()),
+                sidecar: None,
+            }, document_message.take_fileName(), if document_message.has_jpegThumbnail() { Some(document_message.take_jpegThumbnail()) } else { None },
+                                           non_empty(document_message.take_caption()))
+        } else if message.has_protocolMessage() {
+            let mut protocol_message = message.take_protocolMessage();
+            let kind = ProtocolMessageKind::from_proto(&mut protocol_message)?;
+            ChatMessageContent::ProtocolMessage(MessageKey::from_proto(protocol_message.take_key()), kind)
+        } else if message.has_groupInviteMessage() && Jid::from_str(message.get_groupInviteMessage().get_groupJid()).is_ok() {
+            // Invites with an invalid group jid are kept as ```Unknown```
+            let mut group_invite_message = message.take_groupInviteMessage();
+            ChatMessageContent::GroupInvite(GroupInvite {
+                group: Jid::from_str(group_invite_message.get_groupJid())?,
+                code: group_invite_message.take_inviteCode(),
+                // An expiration of 0 means the invite doesn't expire
+                expiration: match group_invite_message.get_inviteExpiration() {
+                    0 => None,
+                    expiration => NaiveDateTime::from_timestamp_opt(expiration, 0)
+                },
+                name: group_invite_message.take_groupName(),
+                caption: group_invite_message.take_caption(),
+                thumbnail: group_invite_message.take_jpegThumbnail(),
+            })
         } else {
-            ChatMessageContent::Text("TODO".to_string())
+            ChatMessageContent::Unknown(message.write_to_bytes().chain_err(|| "Invalid Protobuf message")?)
+        };
+        Ok(match context_info {
+            Some(context_info) => ChatMessageContent::WithContext(Box::new(content), context_info),
+            None => content
         })
     }
 
+    /// Check that raw ```Unknown``` content is a valid protobuf message, it couldn't be sent otherwise
+    pub(crate) fn validate(&self) -> Result<()> {
+        match *self {
+            ChatMessageContent::Unknown(ref raw) => {
+                protobuf::parse_from_bytes::<message_wire::Message>(raw).chain_err(|| "invalid raw message")?;
+            }
+            ChatMessageContent::WithContext(ref content, ref context_info) => {
+                content.validate()?;
+                if let Some(ref quoted) = context_info.quoted {
+                    quoted.content.validate()?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
     pub fn into_proto(self) -> message_wire::Message {
         let mut message = message_wire::Message::new();
         match self {
             ChatMessageContent::Text(text) => message.set_conversation(text),
-            ChatMessageContent::Image(info, size, thumbnail) => {
+            ChatMessageContent::Image(info, size, thumbnail, caption) => {
                 let mut image_message = message_wire::ImageMessage::new();
                 image_message.set_url(info.url);
                 image_message.set_mimetype(info.mime);
@@ -194,12 +599,56 @@ impl ChatMessageContent {
                 image_message.set_fileSha256(info.sha256);
                 image_message.set_fileLength(info.size as u64);
                 image_message.set_mediaKey(info.key);
+                if let Some(direct_path) = info.direct_path {
+                    image_message.set_directPath(direct_path);
+                }
                 image_message.set_height(size.0);
                 image_message.set_width(size.1);
                 image_message.set_jpegThumbnail(thumbnail);
+                if let Some(caption) = caption {
+                    image_message.set_caption(caption);
+                }
                 message.set_imageMessage(image_message);
             }
-            ChatMessageContent::Document(info, filename) => {
+            ChatMessageContent::Audio(info, duration) => {
+                let mut audio_message = message_wire::AudioMessage::new();
+                audio_message.set_url(info.url);
+                audio_message.set_mimetype(info.mime);
+                audio_message.set_fileEncSha256(info.enc_sha256);
+                audio_message.set_fileSha256(info.sha256);
+                audio_message.set_fileLength(info.size as u64);
+                audio_message.set_mediaKey(info.key);
+                if let Some(direct_path) = info.direct_path {
+                    audio_message.set_directPath(direct_path);
+                }
+                audio_message.set_seconds(duration.as_secs() as u32);
+                if let Some(sidecar) = info.sidecar {
+                    audio_message.set_streamingSidecar(sidecar);
+                }
+                message.set_audioMessage(audio_message);
+            }
+            ChatMessageContent::Video(info, duration, thumbnail, caption) => {
+                let mut video_message = message_wire::VideoMessage::new();
+                video_message.set_url(info.url);
+                video_message.set_mimetype(info.mime);
+                video_message.set_fileEncSha256(info.enc_sha256);
+                video_message.set_fileSha256(info.sha256);
+                video_message.set_fileLength(info.size as u64);
+                video_message.set_mediaKey(info.key);
+                if let Some(direct_path) = info.direct_path {
+                    video_message.set_directPath(direct_path);
+                }
+                video_message.set_seconds(duration.as_secs() as u32);
+                video_message.set_jpegThumbnail(thumbnail);
+                if let Some(caption) = caption {
+                    video_message.set_caption(caption);
+                }
+                if let Some(sidecar) = info.sidecar {
+                    video_message.set_streamingSidecar(sidecar);
+                }
+                message.set_videoMessage(video_message);
+            }
+            ChatMessageContent::Document(info, filename, thumbnail, caption) => {
                 let mut document_message = message_wire::DocumentMessage::new();
                 document_message.set_url(info.url);
                 document_message.set_mimetype(info.mime);
@@ -207,22 +656,217 @@ impl ChatMessageContent {
                 document_message.set_fileSha256(info.sha256);
                 document_message.set_fileLength(info.size as u64);
                 document_message.set_mediaKey(info.key);
+                if let Some(direct_path) = info.direct_path {
+                    document_message.set_directPath(direct_path);
+                }
                 document_message.set_fileName(filename);
+                if let Some(thumbnail) = thumbnail {
+                    document_message.set_jpegThumbnail(thumbnail);
+                }
+                if let Some(caption) = caption {
+                    document_message.set_caption(caption);
+                }
                 message.set_documentMessage(document_message);
             }
-            _ => unimplemented!()
+            ChatMessageContent::ProtocolMessage(key, kind) => {
+                let mut protocol_message = message_wire::ProtocolMessage::new();
+                protocol_message.set_key(key.into_proto());
+                kind.into_proto(&mut protocol_message);
+                message.set_protocolMessage(protocol_message);
+            }
+            ChatMessageContent::GroupInvite(invite) => {
+                let mut group_invite_message = message_wire::GroupInviteMessage::new();
+                group_invite_message.set_groupJid(invite.group.to_message_jid());
+                group_invite_message.set_inviteCode(invite.code);
+                if let Some(expiration) = invite.expiration {
+                    group_invite_message.set_inviteExpiration(expiration.timestamp());
+                }
+                group_invite_message.set_groupName(invite.name);
+                group_invite_message.set_caption(invite.caption);
+                group_invite_message.set_jpegThumbnail(invite.thumbnail);
+                message.set_groupInviteMessage(group_invite_message);
+            }
+            ChatMessageContent::WithContext(content, context_info) => {
+                message = content.into_proto();
+                if message.has_conversation() {
+                    let mut extended_text_message = message_wire::ExtendedTextMessage::new();
+                    extended_text_message.set_text(message.take_conversation());
+                    message.set_extendedTextMessage(extended_text_message);
+                }
+
+                let context_info = context_info.into_proto();
+                if message.has_extendedTextMessage() {
+                    message.mut_extendedTextMessage().set_contextInfo(context_info);
+                } else if message.has_imageMessage() {
+                    message.mut_imageMessage().set_contextInfo(context_info);
+                } else if message.has_videoMessage() {
+                    message.mut_videoMessage().set_contextInfo(context_info);
+                } else if message.has_audioMessage() {
+                    message.mut_audioMessage().set_contextInfo(context_info);
+                } else if message.has_documentMessage() {
+                    message.mut_documentMessage().set_contextInfo(context_info);
+                }
+            }
+            ChatMessageContent::Unknown(raw) => {
+                if let Ok(raw_message) = protobuf::parse_from_bytes::<message_wire::Message>(&raw) {
+                    message = raw_message;
+                }
+            }
         }
 
         message
     }
 }
 
-#[derive(Debug)]
+/// Message sent by this connection, retained until it has been read by the recipient
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
+pub struct SentMessage {
+    pub id: MessageId,
+    pub jid: Jid,
+    pub content: ChatMessageContent,
+}
+
+/// Builder of the content of an outgoing message with the optional parts named,
+/// e.g. ```OutgoingMessageBuilder::text(text).quoted(&message).build()``` to reply to ```message```
+pub struct OutgoingMessageBuilder {
+    content: ChatMessageContent,
+    caption: Option<String>,
+    quoted: Option<QuotedMessage>,
+    mentions: Vec<Jid>,
+}
+
+impl OutgoingMessageBuilder {
+    pub fn text(text: String) -> OutgoingMessageBuilder {
+        OutgoingMessageBuilder::new(ChatMessageContent::Text(text))
+    }
+
+    /// Image with its height and width
+    pub fn image(info: FileInfo, size: (u32, u32)) -> OutgoingMessageBuilder {
+        OutgoingMessageBuilder::new(ChatMessageContent::Image(info, size, Vec::new(), None))
+    }
+
+    pub fn audio(info: FileInfo, duration: Duration) -> OutgoingMessageBuilder {
+        OutgoingMessageBuilder::new(ChatMessageContent::Audio(info, duration))
+    }
+
+    pub fn video(info: FileInfo, duration: Duration) -> OutgoingMessageBuilder {
+        OutgoingMessageBuilder::new(ChatMessageContent::Video(info, duration, Vec::new(), None))
+    }
+
+    pub fn document(info: FileInfo, filename: String) -> OutgoingMessageBuilder {
+        OutgoingMessageBuilder::new(ChatMessageContent::Document(info, filename, None, None))
+    }
+
+    fn new(content: ChatMessageContent) -> OutgoingMessageBuilder {
+        OutgoingMessageBuilder { content, caption: None, quoted: None, mentions: Vec::new() }
+    }
+
+    /// Caption of an image, video or document
+    pub fn caption(mut self, caption: String) -> OutgoingMessageBuilder {
+        self.caption = Some(caption);
+        self
+    }
+
+    /// JPEG thumbnail of an image, video or document
+    pub fn thumbnail(mut self, thumbnail: Vec<u8>) -> OutgoingMessageBuilder {
+        match self.content {
+            ChatMessageContent::Image(_, _, ref mut media_thumbnail, _) |
+            ChatMessageContent::Video(_, _, ref mut media_thumbnail, _) => *media_thumbnail = thumbnail,
+            ChatMessageContent::Document(_, _, ref mut document_thumbnail, _) => *document_thumbnail = Some(thumbnail),
+            _ => {}
+        }
+        self
+    }
+
+    /// Reply to ```message```
+    pub fn quoted(mut self, message: &ChatMessage) -> OutgoingMessageBuilder {
+        let sender = match message.direction {
+            Direction::Sending(_) => None,
+            Direction::Receiving(Peer::Individual(ref jid)) | Direction::Receiving(Peer::Group { participant: ref jid, .. }) |
+            Direction::Receiving(Peer::Broadcast { sender: ref jid, .. }) => Some(jid.clone())
+        };
+        self.quoted = Some(QuotedMessage { id: message.id.clone(), sender, content: Box::new(message.content.clone()) });
+        self
+    }
+
+    /// Users which are notified as mentioned, the text should reference them like ```@<phonenumber>```
+    pub fn mentions(mut self, mentions: Vec<Jid>) -> OutgoingMessageBuilder {
+        self.mentions = mentions;
+        self
+    }
+
+    /// Build the content to send with ```WhatsappWebConnection::send_message```
+    pub fn build(mut self) -> Result<ChatMessageContent> {
+        if let Some(caption) = self.caption.take() {
+            match self.content {
+                ChatMessageContent::Image(_, _, _, ref mut media_caption) |
+                ChatMessageContent::Video(_, _, _, ref mut media_caption) |
+                ChatMessageContent::Document(_, _, _, ref mut media_caption) => *media_caption = Some(caption),
+                _ => bail! {"only images, videos and documents have a caption"}
+            }
+        }
+        if self.quoted.is_none() && self.mentions.is_empty() {
+            return Ok(self.content);
+        }
+        Ok(ChatMessageContent::WithContext(Box::new(self.content), ContextInfo { quoted: self.quoted, mentions: self.mentions }))
+    }
+}
+
+/// Optional string fields like captions are received empty if they are unset
+fn non_empty(value: String) -> Option<String> {
+    if value.is_empty() { None } else { Some(value) }
+}
+
+/// Limits of outgoing media, the server drops messages exceeding them without notice.
+/// The defaults are replaced by the ```Props``` sent by the server after login
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MediaLimits {
+    /// Maximum size of images in bytes
+    pub max_image_size: usize,
+    /// Maximum size of videos, audios and documents in bytes
+    pub max_file_size: usize,
+}
+
+impl Default for MediaLimits {
+    fn default() -> MediaLimits {
+        MediaLimits {
+            max_image_size: 16 * 1024 * 1024,
+            max_file_size: 100 * 1024 * 1024
+        }
+    }
+}
+
+impl MediaLimits {
+    /// Check the file size of ```content```, other content is always valid
+    pub fn check(&self, content: &ChatMessageContent) -> Result<()> {
+        let (kind, size, max_size) = match *content {
+            ChatMessageContent::WithContext(ref content, _) => return self.check(content),
+            ChatMessageContent::Image(ref info, ..) => ("image", info.size, self.max_image_size),
+            ChatMessageContent::Video(ref info, ..) => ("video", info.size, self.max_file_size),
+            ChatMessageContent::Audio(ref info, ..) => ("audio", info.size, self.max_file_size),
+            ChatMessageContent::Document(ref info, ..) => ("document", info.size, self.max_file_size),
+            _ => return Ok(())
+        };
+
+        if size > max_size {
+            bail! {"{} of {} bytes exceeds the limit of {} bytes", kind, size, max_size}
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "bridge", derive(Serialize))]
 pub struct ChatMessage {
     pub direction: Direction,
     pub time: NaiveDateTime,
     pub id: MessageId,
     pub content: ChatMessageContent,
+    /// The underlying protobuf message of received messages, for fields not modeled by ```ChatMessageContent```
+    #[cfg(feature = "raw-proto")]
+    #[cfg_attr(feature = "bridge", serde(skip))]
+    pub raw: Option<message_wire::WebMessageInfo>,
 }
 
 impl ChatMessage {
@@ -234,16 +878,41 @@ impl ChatMessage {
 
     pub fn from_proto(mut webmessage: message_wire::WebMessageInfo) -> Result<ChatMessage> {
         debug!("Processing WebMessageInfo: {:?}", &webmessage);
+        #[cfg(feature = "raw-proto")]
+        let raw = Some(webmessage.clone());
         let mut key = webmessage.take_key();
 
         Ok(ChatMessage {
             id: MessageId(key.take_id()),
             direction: Direction::parse(key)?,
-            time: NaiveDateTime::from_timestamp(webmessage.get_messageTimestamp() as i64, 0),
+            time: NaiveDateTime::from_timestamp_opt(webmessage.get_messageTimestamp() as i64, 0).ok_or("message timestamp out of range")?,
             content: ChatMessageContent::from_proto(webmessage.take_message())?,
+            #[cfg(feature = "raw-proto")]
+            raw,
         })
     }
 
+    /// Embedded JPEG thumbnail of the content, see ```ChatMessageContent::thumbnail```
+    pub fn thumbnail(&self) -> Option<&[u8]> {
+        self.content.thumbnail()
+    }
+
+    /// Decode the embedded thumbnail, e.g. to show a preview without downloading the media
+    #[cfg(feature = "media")]
+    pub fn decode_thumbnail(&self) -> Result<Option<image::DynamicImage>> {
+        match self.thumbnail() {
+            Some(thumbnail) => Ok(Some(image::load_from_memory(thumbnail).chain_err(|| "invalid thumbnail")?)),
+            None => Ok(None)
+        }
+    }
+
+    /// The underlying protobuf message, for fields not modeled by ```ChatMessageContent```.
+    /// Only set for messages parsed with ```from_proto```
+    #[cfg(feature = "raw-proto")]
+    pub fn raw(&self) -> Option<&message_wire::WebMessageInfo> {
+        self.raw.as_ref()
+    }
+
     pub fn into_proto_binary(self) -> Vec<u8> {
         let webmessage = self.into_proto();
         webmessage.write_to_bytes().unwrap()
@@ -276,6 +945,48 @@ impl ChatMessage {
 
 impl Jid {
     pub fn to_message_jid(&self) -> String {
-        self.id.to_string() + if self.is_group { "@g.us" } else { "@s.whatsapp.net" }
+        self.id.to_string() + match self.kind {
+            JidKind::Individual => "@s.whatsapp.net",
+            JidKind::Group => "@g.us",
+            JidKind::Broadcast => "@broadcast"
+        }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reply_roundtrip() {
+        let sender = Jid::from_str("491234567890@s.whatsapp.net").unwrap();
+        let quoted = ChatMessage {
+            direction: Direction::Receiving(Peer::Individual(sender.clone())),
+            time: NaiveDateTime::from_timestamp_opt(1_500_000_000, 0).unwrap(),
+            id: MessageId("3EB0C5F8A1B2C3D4E5F6".to_string()),
+            content: ChatMessageContent::Text("question".to_string()),
+            #[cfg(feature = "raw-proto")]
+            raw: None,
+        };
+        let reply = OutgoingMessageBuilder::text("answer @491234567890".to_string())
+            .quoted(&quoted)
+            .mentions(vec![sender.clone()])
+            .build()
+            .unwrap();
+
+        assert_eq!(reply, ChatMessageContent::WithContext(Box::new(ChatMessageContent::Text("answer @491234567890".to_string())), ContextInfo {
+            quoted: Some(QuotedMessage {
+                id: quoted.id.clone(),
+                sender: Some(sender.clone()),
+                content: Box::new(quoted.content.clone())
+            }),
+            mentions: vec![sender]
+        }));
+        assert_eq!(ChatMessageContent::from_proto(reply.clone().into_proto()).unwrap(), reply);
+    }
+
+    #[test]
+    fn test_invalid_unknown_content() {
+        assert!(ChatMessageContent::Unknown(vec![0xff, 0xff, 0xff]).validate().is_err());
+        assert!(ChatMessageContent::Unknown(ChatMessageContent::Text("text".to_string()).into_proto().write_to_bytes().unwrap()).validate().is_ok());
+    }
+}
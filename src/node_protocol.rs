@@ -1,13 +1,19 @@
 use std::collections::HashMap;
+use std::mem;
 
 use Contact;
 use Jid;
 use Chat;
+use Muted;
 use ChatAction;
 use PresenceStatus;
 use GroupParticipantsChange;
+use UrlPreview;
+use Identity;
+use chrono::NaiveDateTime;
+
 use node_wire::{Node, NodeContent, IntoCow};
-use message::{ChatMessage, MessageAck, MessageAckLevel, Peer, MessageId};
+use message::{ChatMessage, MessageAck, MessageAckLevel, Peer, Direction, MessageId};
 use errors::*;
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -27,6 +33,14 @@ pub enum GroupCommand {
     Leave(Jid)
 }
 
+#[derive(Debug)]
+pub enum BroadcastCommand {
+    /// The jid of the created list is assigned by the server
+    Create(String),
+    Add(Jid),
+    Remove(Jid)
+}
+
 #[derive(Debug)]
 pub enum AppEvent {
     Message(Box<ChatMessage>),
@@ -36,18 +50,30 @@ pub enum AppEvent {
     ContactDelete(Jid),
     //App only
     ContactAddChange(Contact),
+    //Client only, phonebook entry pushed to the phone
+    ContactPush { jid: Jid, name: String, update: bool },
 
     ChatAction(Jid, ChatAction),
     //App only
+    ChatNameChange(Jid, String),
+    //App only
+    MessageStar { chat: Jid, id: MessageId, starred: bool },
+    //App only, mute action optionally with the notification tone of the chat
+    ChatMute { chat: Jid, muted: Muted, tone: Option<String> },
+    //App only
     Battery(u8),
+    //App only
+    RetryRequested(MessageId),
 
     //Client only
     MessageRead { id: MessageId, peer: Peer },
     //Client only
-    MessagePlayed { id: MessageId, peer: Peer },
+    MessagePlayed { id: MessageId, direction: Direction },
 
     //Client only
     GroupCommand { inducer: Jid, id: String, participants: Vec<Jid>, command: GroupCommand },
+    //Client only
+    BroadcastCommand { id: String, recipients: Vec<Jid>, command: BroadcastCommand },
 
     //Client only
     PresenceChange(PresenceStatus, Option<Jid>),
@@ -61,7 +87,15 @@ pub enum AppEvent {
 
 #[derive(Debug)]
 pub enum Query {
-    MessagesBefore { jid: Jid, id: String, count: u16 }
+    MessagesBefore { jid: Jid, id: String, count: u16 },
+    Chats,
+    Contacts,
+    /// Let the server generate the preview of an url
+    UrlPreview(String),
+    /// Recipients of a broadcast list
+    BroadcastRecipients(Jid),
+    /// Identity key of a contact
+    Identity(Jid)
 }
 
 #[derive(Debug)]
@@ -73,7 +107,10 @@ pub enum AppMessage {
     Chats(Vec<Chat>),
 
     //Client only
-    Query(Query)
+    Query(Query),
+    //Client only, nodes not modeled by this crate sent within a ```set``` action
+    #[cfg(feature = "raw-nodes")]
+    Raw(Vec<Node<'static>>)
 }
 
 
@@ -99,7 +136,8 @@ impl AppMessage {
                                                                  MessageAckLevel::from_node(node.get_attribute("type")?.as_str())?,
                                                                  node.take_attribute("jid")?.into_jid()?,
                                                                  node.take_attribute("participant").and_then(|participant| participant.into_jid()).ok(),
-                                                                 node.take_attribute("owner")?.as_str().parse().map_err(|_| "NAN")?)))
+                                                                 node.take_attribute("owner")?.as_str().parse().map_err(|_| "NAN")?,
+                                                                 node.take_attribute("t").ok().and_then(|time| time.as_str().parse().ok()))))
                             }
                             "read" => {
                                 let jid = node.take_attribute("jid")?.into_jid()?;
@@ -111,7 +149,8 @@ impl AppMessage {
                             }
                             "user" => {
                                 let contact = Contact::parse_node(&mut node)?;
-                                app_events.push(if contact.name.is_some() {
+                                // A change of the push name is sent without the phonebook name
+                                app_events.push(if contact.name.is_some() || contact.notify.is_some() {
                                     AppEvent::ContactAddChange(contact)
                                 } else {
                                     AppEvent::ContactDelete(contact.jid)
@@ -119,8 +158,36 @@ impl AppMessage {
                             }
                             "chat" => {
                                 let jid = node.take_attribute("jid")?.into_jid()?;
-                                let action = ChatAction::from_node(&mut node)?;
-                                app_events.push(AppEvent::ChatAction(jid, action));
+                                let star = node.get_attribute("type").ok().and_then(|typ| match typ.as_str() {
+                                    "star" => Some(true),
+                                    "unstar" => Some(false),
+                                    _ => None
+                                });
+                                if node.get_attribute("type").ok().map_or(false, |typ| typ.as_str() == "modify") {
+                                    app_events.push(AppEvent::ChatNameChange(jid, node.take_attribute("name")?.into_string()));
+                                } else if let Some(starred) = star {
+                                    if let NodeContent::List(items) = node.content {
+                                        for mut item in items {
+                                            app_events.push(AppEvent::MessageStar {
+                                                chat: jid.clone(),
+                                                id: MessageId(item.take_attribute("index")?.into_string()),
+                                                starred
+                                            });
+                                        }
+                                    }
+                                } else {
+                                    let tone = node.take_attribute("tone").ok().map(|tone| tone.into_string());
+                                    match ChatAction::from_node(&mut node)? {
+                                        ChatAction::Mute(muted) => app_events.push(AppEvent::ChatMute { chat: jid, muted, tone }),
+                                        ChatAction::Unmute => app_events.push(AppEvent::ChatMute { chat: jid, muted: Muted::No, tone }),
+                                        action => app_events.push(AppEvent::ChatAction(jid, action))
+                                    }
+                                }
+                            }
+                            "receipt" => {
+                                if node.get_attribute("type").ok().map_or(false, |typ| typ.as_str() == "retry") {
+                                    app_events.push(AppEvent::RetryRequested(MessageId(node.take_attribute("id")?.into_string())));
+                                }
                             }
                             "battery" => {
                                 let level = node.take_attribute("value")?.as_str().parse().map_err(|_| "NAN")?;
@@ -138,25 +205,15 @@ impl AppMessage {
             "response" => {
                 match root_node.get_attribute("type")?.as_str() {
                     "contacts" => {
-                        if let NodeContent::List(mut list) = root_node.content {
-                            let mut contacts = Vec::with_capacity(list.len());
-                            for mut node in list {
-                                contacts.push(Contact::parse_node(&mut node)?);
-                            }
-
-                            Ok(AppMessage::Contacts(contacts))
+                        if let NodeContent::List(list) = root_node.content {
+                            Ok(AppMessage::Contacts(parse_contact_nodes(list)?))
                         } else {
                             bail!{ "Invalid nodetype for contacts"}
                         }
                     }
                     "chat" => {
-                        if let NodeContent::List(mut list) = root_node.content {
-                            let mut chats = Vec::with_capacity(list.len());
-                            for mut node in list {
-                                chats.push(Chat::parse_node(&mut node)?);
-                            }
-
-                            Ok(AppMessage::Chats(chats))
+                        if let NodeContent::List(list) = root_node.content {
+                            Ok(AppMessage::Chats(parse_chat_nodes(list)?))
                         } else {
                             bail!{ "Invalid nodetype for chats"}
                         }
@@ -167,7 +224,7 @@ impl AppMessage {
             _ => bail!{ "invalid or unsupported app message type"}
         }
     }
-    pub fn serialize(self, epoch: u32) -> Node {
+    pub fn serialize(self, epoch: u32) -> Node<'static> {
         let mut attributes = HashMap::new();
         attributes.insert("epoch".cow(), NodeContent::String(epoch.to_string().cow()));
 
@@ -184,7 +241,7 @@ impl AppMessage {
                                     Peer::Individual(jid) => {
                                         attributes.insert("jid".cow(), NodeContent::Jid(jid));
                                     }
-                                    Peer::Group { group, participant } => {
+                                    Peer::Group { group, participant } | Peer::Broadcast { broadcast: group, sender: participant } => {
                                         attributes.insert("jid".cow(), NodeContent::Jid(group));
                                         attributes.insert("participant".cow(), NodeContent::Jid(participant));
                                     }
@@ -193,27 +250,37 @@ impl AppMessage {
                                 attributes.insert("count".cow(), NodeContent::String("1".cow()));
                                 Node::new("read", attributes, NodeContent::None)
                             }
-                            AppEvent::MessagePlayed { id, peer } => {
+                            AppEvent::MessagePlayed { id, direction } => {
                                 let mut attributes = HashMap::new();
 
                                 attributes.insert("type".cow(), NodeContent::Token("played"));
 
                                 attributes.insert("index".cow(), NodeContent::String(id.0.cow()));
 
-                                match peer {
-                                    Peer::Individual(jid) => { attributes.insert("from".cow(), NodeContent::Jid(jid)); }
-                                    Peer::Group { group, participant } => {
+                                // The owner of a message is the user who sent it
+                                let owner = match direction {
+                                    Direction::Sending(jid) => {
+                                        attributes.insert("from".cow(), NodeContent::Jid(jid));
+                                        true
+                                    }
+                                    Direction::Receiving(Peer::Individual(jid)) => {
+                                        attributes.insert("from".cow(), NodeContent::Jid(jid));
+                                        false
+                                    }
+                                    Direction::Receiving(Peer::Group { group, participant }) |
+                                    Direction::Receiving(Peer::Broadcast { broadcast: group, sender: participant }) => {
                                         attributes.insert("from".cow(), NodeContent::Jid(group));
                                         attributes.insert("participant".cow(), NodeContent::Jid(participant));
+                                        false
                                     }
-                                }
-                                attributes.insert("owner".cow(), NodeContent::Token("false"));
+                                };
+                                attributes.insert("owner".cow(), NodeContent::Token(if owner { "true" } else { "false" }));
                                 attributes.insert("count".cow(), NodeContent::String("1".cow()));
                                 Node::new("received", attributes, NodeContent::None)
                             }
 
                             AppEvent::Message(message) => {
-                                Node::new("message", HashMap::new(), NodeContent::Binary(message.into_proto_binary()))
+                                Node::new("message", HashMap::new(), NodeContent::Binary(message.into_proto_binary().into()))
                             }
                             AppEvent::GroupCommand { inducer, id, participants, command } => {
                                 let mut attributes = HashMap::new();
@@ -243,6 +310,40 @@ impl AppMessage {
                                     }).collect())
                                 )
                             }
+                            AppEvent::ContactPush { jid, name, update } => {
+                                let mut attributes = HashMap::new();
+                                attributes.insert("type".cow(), NodeContent::Token(if update { "update" } else { "add" }));
+                                attributes.insert("jid".cow(), NodeContent::Jid(jid));
+                                attributes.insert("name".cow(), NodeContent::String(name.cow()));
+                                Node::new("user", attributes, NodeContent::None)
+                            }
+                            AppEvent::BroadcastCommand { id, recipients, command } => {
+                                let mut attributes = HashMap::new();
+                                match command {
+                                    BroadcastCommand::Create(name) => {
+                                        attributes.insert("type".cow(), NodeContent::Token("create"));
+                                        attributes.insert("name".cow(), NodeContent::String(name.cow()));
+                                    }
+                                    BroadcastCommand::Add(jid) => {
+                                        attributes.insert("type".cow(), NodeContent::Token("add"));
+                                        attributes.insert("jid".cow(), NodeContent::Jid(jid));
+                                    }
+                                    BroadcastCommand::Remove(jid) => {
+                                        attributes.insert("type".cow(), NodeContent::Token("remove"));
+                                        attributes.insert("jid".cow(), NodeContent::Jid(jid));
+                                    }
+                                }
+                                attributes.insert("id".cow(), NodeContent::String(id.cow()));
+                                Node::new(
+                                    "broadcast",
+                                    attributes,
+                                    NodeContent::List(recipients.into_iter().map(|jid| {
+                                        let mut attributes = HashMap::new();
+                                        attributes.insert("jid".cow(), NodeContent::Jid(jid));
+                                        Node::new("recipient", attributes, NodeContent::None)
+                                    }).collect())
+                                )
+                            }
                             AppEvent::PresenceChange(status, jid) => {
                                 let mut attributes = HashMap::new();
                                 attributes.insert("type".cow(), NodeContent::Token(status.into_node()));
@@ -254,22 +355,26 @@ impl AppMessage {
                             AppEvent::ChatAction(jid, action) => {
                                 let mut attributes = HashMap::new();
                                 attributes.insert("jid".cow(), NodeContent::Jid(jid));
+                                let desc = match action {
+                                    ChatAction::Read | ChatAction::Unread => "read",
+                                    _ => "chat"
+                                };
                                 match action {
                                     ChatAction::Pin(time) => {
                                         attributes.insert("type".cow(), NodeContent::String("pin".cow()));
-                                        attributes.insert("pin".cow(), NodeContent::String(time.to_string().cow()));
+                                        attributes.insert("pin".cow(), NodeContent::String(time.timestamp().to_string().cow()));
                                     }
                                     //Fixme
                                     ChatAction::Unpin => {
                                         attributes.insert("type".cow(), NodeContent::String("pin".cow()));
                                         //attributes.insert("previous".to_string(), NodeContent::String(time.to_string()));
                                     }
-                                    ChatAction::Mute(time) => {
+                                    ChatAction::Mute(muted) if muted != Muted::No => {
                                         attributes.insert("type".cow(), NodeContent::Token("mute"));
-                                        attributes.insert("mute".cow(), NodeContent::String(time.to_string().cow()));
+                                        attributes.insert("mute".cow(), NodeContent::String(muted.into_node().cow()));
                                     }
                                     //Fixme
-                                    ChatAction::Unmute => {
+                                    ChatAction::Mute(_) | ChatAction::Unmute => {
                                         attributes.insert("type".cow(), NodeContent::Token("mute"));
                                     }
                                     ChatAction::Archive => {
@@ -278,11 +383,22 @@ impl AppMessage {
                                     ChatAction::Unarchive => {
                                         attributes.insert("type".cow(), NodeContent::Token("unarchive"));
                                     }
-
-                                    _ => unimplemented!()
+                                    ChatAction::Spam => {
+                                        attributes.insert("type".cow(), NodeContent::Token("spam"));
+                                    }
+                                    ChatAction::Delete => {
+                                        attributes.insert("type".cow(), NodeContent::Token("delete"));
+                                    }
+                                    ChatAction::Clear => {
+                                        attributes.insert("type".cow(), NodeContent::Token("clear"));
+                                    }
+                                    ChatAction::Read => {}
+                                    ChatAction::Unread => {
+                                        attributes.insert("type".cow(), NodeContent::Token("false"));
+                                    }
                                 }
 
-                                Node::new("chat", attributes, NodeContent::None)
+                                Node::new(desc, attributes, NodeContent::None)
                             }
                             AppEvent::StatusChange(status) => {
                                 Node::new("status", HashMap::new(), NodeContent::String(status.cow()))
@@ -323,8 +439,41 @@ impl AppMessage {
                         node.set_attribute("owner", NodeContent::Token("false"));
                         node
                     }
+                    Query::Chats => {
+                        let mut node = Node::new_empty("query");
+                        node.set_attribute("type", NodeContent::Token("chat"));
+                        node
+                    }
+                    Query::Contacts => {
+                        let mut node = Node::new_empty("query");
+                        node.set_attribute("type", NodeContent::Token("contacts"));
+                        node
+                    }
+                    Query::UrlPreview(url) => {
+                        let mut node = Node::new_empty("query");
+                        node.set_attribute("type", NodeContent::Token("url"));
+                        node.set_attribute("url", NodeContent::String(url.cow()));
+                        node
+                    }
+                    Query::Identity(jid) => {
+                        let mut node = Node::new_empty("query");
+                        node.set_attribute("type", NodeContent::Token("identity"));
+                        node.set_attribute("jid", NodeContent::Jid(jid));
+                        node
+                    }
+                    Query::BroadcastRecipients(jid) => {
+                        let mut node = Node::new_empty("query");
+                        node.set_attribute("type", NodeContent::Token("broadcast"));
+                        node.set_attribute("jid", NodeContent::Jid(jid));
+                        node
+                    }
                 }
             }
+            #[cfg(feature = "raw-nodes")]
+            AppMessage::Raw(nodes) => {
+                attributes.insert("type".cow(), NodeContent::Token(MessageEventType::Set.into_node()));
+                Node::new("action", attributes, NodeContent::List(nodes))
+            }
             _ => unreachable!()
         }
     }
@@ -350,6 +499,112 @@ pub fn parse_message_response(root_node: Node) -> Result<Vec<ChatMessage>> {
     }
 }
 
+/// Decoded but unconverted entries of the initial contacts or chats, so big rosters can be converted chunk by chunk
+pub enum Roster<'a> {
+    Contacts(Vec<Node<'a>>),
+    Chats(Vec<Node<'a>>)
+}
+
+/// Take the entries of ```root_node``` if it is a contacts or chats response, otherwise it is left untouched
+pub fn take_roster<'a>(root_node: &mut Node<'a>) -> Option<Roster<'a>> {
+    if root_node.desc() != "response" {
+        return None;
+    }
+    let is_contacts = match root_node.get_attribute("type").ok().map(|typ| typ.as_str()) {
+        Some("contacts") => true,
+        Some("chat") => false,
+        _ => return None
+    };
+    match mem::replace(&mut root_node.content, NodeContent::None) {
+        NodeContent::List(list) => Some(if is_contacts { Roster::Contacts(list) } else { Roster::Chats(list) }),
+        content => {
+            root_node.content = content;
+            None
+        }
+    }
+}
+
+pub fn parse_contact_nodes(nodes: Vec<Node>) -> Result<Vec<Contact>> {
+    let mut contacts = Vec::with_capacity(nodes.len());
+    for mut node in nodes {
+        contacts.push(Contact::parse_node(&mut node)?);
+    }
+    Ok(contacts)
+}
+
+pub fn parse_chat_nodes(nodes: Vec<Node>) -> Result<Vec<Chat>> {
+    let mut chats = Vec::with_capacity(nodes.len());
+    for mut node in nodes {
+        chats.push(Chat::parse_node(&mut node)?);
+    }
+    Ok(chats)
+}
+
+pub fn parse_chats_response(root_node: Node) -> Result<Vec<Chat>> {
+    match AppMessage::deserialize(root_node)? {
+        AppMessage::Chats(chats) => Ok(chats),
+        _ => bail!{ "invalid response" }
+    }
+}
+
+pub fn parse_contacts_response(root_node: Node) -> Result<Vec<Contact>> {
+    match AppMessage::deserialize(root_node)? {
+        AppMessage::Contacts(contacts) => Ok(contacts),
+        _ => bail!{ "invalid response" }
+    }
+}
+
+pub fn parse_url_preview_response(mut root_node: Node, url: String) -> Result<UrlPreview> {
+    if root_node.desc() != "response" {
+        bail!{ "invalid response" }
+    }
+    Ok(UrlPreview {
+        url: root_node.take_attribute("url").map(|url| url.into_string()).unwrap_or(url),
+        canonical_url: root_node.take_attribute("canonical-url").map(|url| url.into_string()).ok(),
+        title: root_node.take_attribute("title").map(|title| title.into_string()).ok(),
+        description: root_node.take_attribute("description").map(|description| description.into_string()).ok(),
+        thumbnail: match root_node.content {
+            NodeContent::Binary(thumbnail) => Some(thumbnail.into_owned()),
+            _ => None
+        }
+    })
+}
+
+pub fn parse_identity_response(root_node: Node, jid: Jid) -> Result<Identity> {
+    if root_node.desc() != "response" {
+        bail!{ "invalid response" }
+    }
+    // The key is either the content of the response or of its identity node
+    let key = match root_node.content {
+        NodeContent::Binary(key) => key.into_owned(),
+        NodeContent::List(list) => match list.into_iter().map(|node| node.content).find(|content| if let NodeContent::Binary(_) = *content { true } else { false }) {
+            Some(NodeContent::Binary(key)) => key.into_owned(),
+            _ => bail!{ "identity response without key" }
+        },
+        _ => bail!{ "identity response without key" }
+    };
+    Ok(Identity { jid, key })
+}
+
+/// The created broadcast list is answered with its jid like ```<broadcast jid="<id>@broadcast"/>```
+pub fn parse_broadcast_create_response(mut root_node: Node) -> Result<Jid> {
+    root_node.take_attribute("jid")?.into_jid()
+}
+
+pub fn parse_broadcast_recipients_response(root_node: Node) -> Result<Vec<Jid>> {
+    if root_node.desc() != "response" {
+        bail!{ "invalid response" }
+    }
+    match root_node.content {
+        NodeContent::List(list) => list.into_iter()
+            .filter(|node| node.desc() == "recipient")
+            .map(|mut node| node.take_attribute("jid")?.into_jid())
+            .collect(),
+        NodeContent::None => Ok(Vec::new()),
+        _ => bail!{ "invalid nodetype for broadcast recipients" }
+    }
+}
+
 impl Contact {
     fn parse_node(node: &mut Node) -> Result<Contact> {
         Ok(Contact {
@@ -362,14 +617,19 @@ impl Contact {
 
 impl Chat {
     fn parse_node(node: &mut Node) -> Result<Chat> {
+        let pin_time = match node.take_attribute("pin").ok().and_then(|t| t.as_str().parse().ok()) {
+            Some(time) => Some(NaiveDateTime::from_timestamp_opt(time, 0).ok_or("timestamp out of range")?),
+            None => None
+        };
         Ok(Chat {
             name: node.take_attribute("name").map(|name| name.into_string()).ok(),
             jid: node.take_attribute("jid")?.into_jid()?,
             last_activity: node.take_attribute("t")?.into_string().parse().map_err(|_| "NAN")?,
             spam: node.take_attribute("spam")?.into_string().parse().map_err(|_| "NAN")?,
-            mute_until: node.take_attribute("mute").ok().and_then(|t| t.into_string().parse().ok()),
-            pin_time: node.take_attribute("pin").ok().and_then(|t| t.into_string().parse().ok()),
+            muted: node.take_attribute("mute").ok().and_then(|t| Muted::from_node(t.as_str()).ok()).unwrap_or(Muted::No),
+            pin_time,
             read_only: node.take_attribute("read_only").ok().and_then(|read_only| read_only.into_string().parse().ok()).unwrap_or(false),
+            archived: node.take_attribute("archive").ok().map_or(false, |archive| archive.as_str() == "true"),
         })
     }
 }
@@ -420,23 +680,23 @@ impl MessageEventType {
 impl ChatAction {
     fn from_node(node: &mut Node) -> Result<ChatAction> {
         Ok(match node.take_attribute("type")?.as_str() {
-            "spam" => ChatAction::Add,
-            "delete" => ChatAction::Remove,
+            "spam" => ChatAction::Spam,
+            "delete" => ChatAction::Delete,
             "archive" => ChatAction::Archive,
             "unarchive" => ChatAction::Unarchive,
             "clear" => ChatAction::Clear,
             "pin" => {
                 if let Ok(time) = node.take_attribute("pin") {
-                    ChatAction::Pin(time.as_str().parse().map_err(|_| "NAN")?)
+                    ChatAction::Pin(NaiveDateTime::from_timestamp_opt(time.as_str().parse().map_err(|_| "NAN")?, 0)
+                        .ok_or("timestamp out of range")?)
                 } else {
                     ChatAction::Unpin
                 }
             }
             "mute" => {
-                if let Ok(time) = node.take_attribute("mute") {
-                    ChatAction::Mute(time.as_str().parse().map_err(|_| "NAN")?)
-                } else {
-                    ChatAction::Unmute
+                match node.take_attribute("mute").ok().map(|time| Muted::from_node(time.as_str())) {
+                    Some(Ok(Muted::No)) | None => ChatAction::Unmute,
+                    Some(muted) => ChatAction::Mute(muted?)
                 }
             }
             _ => bail!{ "invalid or unsupported chat action type"}
@@ -445,6 +705,24 @@ impl ChatAction {
 }
 
 
+impl Muted {
+    fn from_node(value: &str) -> Result<Muted> {
+        Ok(match value.parse().map_err(|_| "NAN")? {
+            -1 => Muted::Forever,
+            0 => Muted::No,
+            time => Muted::Until(NaiveDateTime::from_timestamp_opt(time, 0).ok_or("timestamp out of range")?)
+        })
+    }
+
+    fn into_node(self) -> String {
+        match self {
+            Muted::No => "0".to_string(),
+            Muted::Until(time) => time.timestamp().to_string(),
+            Muted::Forever => "-1".to_string()
+        }
+    }
+}
+
 impl GroupParticipantsChange {
     fn into_node(self) -> &'static str {
         match self {
@@ -463,6 +741,35 @@ impl PresenceStatus {
             PresenceStatus::Available => "available",
             PresenceStatus::Typing => "composing",
             PresenceStatus::Recording => "recording",
+            PresenceStatus::Paused => "paused",
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn chat_node(attributes: &[(&'static str, &'static str)]) -> Node {
+        let mut attributes: HashMap<_, _> = attributes.iter()
+            .map(|&(name, value)| (name.cow(), NodeContent::String(value.cow())))
+            .collect();
+        attributes.insert("jid".cow(), NodeContent::Jid(Jid::from_str("491791234567@s.whatsapp.net").unwrap()));
+        Node::new("chat", attributes, NodeContent::None)
+    }
+
+    #[test]
+    fn test_timestamp_out_of_range() {
+        let oversized = "9223372036854775807";
+
+        assert!(Muted::from_node(oversized).is_err());
+        assert!(ChatAction::from_node(&mut chat_node(&[("type", "pin"), ("pin", oversized)])).is_err());
+        assert!(ChatAction::from_node(&mut chat_node(&[("type", "mute"), ("mute", oversized)])).is_err());
+        assert!(Chat::parse_node(&mut chat_node(&[("t", "0"), ("spam", "false"), ("pin", oversized)])).is_err());
+
+        match ChatAction::from_node(&mut chat_node(&[("type", "pin"), ("pin", "1500000000")])).unwrap() {
+            ChatAction::Pin(time) => assert_eq!(time.timestamp(), 1_500_000_000),
+            _ => panic!("expected pin action")
         }
     }
-}
\ No newline at end of file
+}
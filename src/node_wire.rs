@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::io::{Read, Write, Cursor};
+use std::str;
 use std::char;
 use std::borrow::Cow;
 use std::ops::Deref;
@@ -7,6 +8,7 @@ use std::ops::Deref;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
 use Jid;
+use JidKind;
 use errors::*;
 
 const LIST_EMPTY: u8 = 0;
@@ -26,6 +28,8 @@ const BINARY_32: u8 = 254;
 const NIBBLE_8: u8 = 255;
 #[allow(dead_code)]
 const PACKED_MAX: u8 = 254;
+/// Maximum nesting of lists and jid pairs, real frames stay far below
+const MAX_NODE_DEPTH: usize = 64;
 
 const TOKENS: [&str; 159] = ["200", "400", "404", "500", "501", "502", "action", "add",
     "after", "archive", "author", "available", "battery", "before", "body",
@@ -53,17 +57,17 @@ const TOKENS: [&str; 159] = ["200", "400", "404", "500", "501", "502", "action",
 ];
 
 #[derive(Debug, PartialEq, Clone)]
-pub enum NodeContent {
+pub enum NodeContent<'a> {
     None,
-    List(Vec<Node>),
+    List(Vec<Node<'a>>),
     String(Cow<'static, str>),
-    Binary(Vec<u8>),
+    Binary(Cow<'a, [u8]>),
     Jid(Jid),
     Token(&'static str),
     Nibble(Cow<'static, str>),
 }
 
-impl NodeContent {
+impl<'a> NodeContent<'a> {
     pub fn into_cow(self) -> Cow<'static, str> {
         match self {
             NodeContent::None => "".cow(),
@@ -106,13 +110,26 @@ impl NodeContent {
             NodeContent::Token(ref token) => token
         }
     }
+
+    /// Copies borrowed binary content, so the content outlives the buffer it was deserialized from
+    pub fn into_owned(self) -> NodeContent<'static> {
+        match self {
+            NodeContent::None => NodeContent::None,
+            NodeContent::List(list) => NodeContent::List(list.into_iter().map(Node::into_owned).collect()),
+            NodeContent::String(string) => NodeContent::String(string),
+            NodeContent::Binary(binary) => NodeContent::Binary(Cow::Owned(binary.into_owned())),
+            NodeContent::Jid(jid) => NodeContent::Jid(jid),
+            NodeContent::Token(token) => NodeContent::Token(token),
+            NodeContent::Nibble(string) => NodeContent::Nibble(string)
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
-pub struct Node {
+pub struct Node<'a> {
     pub desc: Cow<'static, str>,
-    pub attributes: HashMap<Cow<'static, str>, NodeContent>,
-    pub content: NodeContent,
+    pub attributes: HashMap<Cow<'static, str>, NodeContent<'a>>,
+    pub content: NodeContent<'a>,
 }
 
 fn read_list_size(tag: u8, stream: &mut Read) -> Result<u16> {
@@ -139,12 +156,12 @@ fn write_list_size(size: u16, stream: &mut Write) -> Result<()> {
     Ok(())
 }
 
-fn read_list(tag: u8, stream: &mut Read) -> Result<Vec<Node>> {
+fn read_list<'a>(tag: u8, stream: &mut Cursor<&'a [u8]>, depth: usize) -> Result<Vec<Node<'a>>> {
     let size = read_list_size(tag, stream).chain_err(|| "Couldn't read list size")?;
     let mut list = Vec::<Node>::with_capacity(size as usize);
 
     for i in 0..size {
-        list.push(Node::deserialize_stream(stream).chain_err(|| format!("Couldn't read list item: {}, size: {}", i, size))?);
+        list.push(Node::deserialize_stream(stream, depth + 1).chain_err(|| format!("Couldn't read list item: {}, size: {}", i, size))?);
     }
 
     Ok(list)
@@ -202,33 +219,58 @@ fn char_to_nibble(nibble: char) -> u8 {
     }
 }
 
-fn read_node_content(tag: u8, stream: &mut Read) -> Result<NodeContent> {
+fn read_bytes<'a>(stream: &mut Cursor<&'a [u8]>, len: usize) -> Result<&'a [u8]> {
+    // Borrows from the underlying data instead of copying it
+    let data: &'a [u8] = *stream.get_ref();
+    let start = stream.position() as usize;
+    if data.len().saturating_sub(start) < len {
+        bail! {"unexpected end of data, expected {} bytes", len}
+    }
+    stream.set_position((start + len) as u64);
+    Ok(&data[start..start + len])
+}
+
+fn read_string_or_binary(buffer: &[u8]) -> NodeContent {
+    match str::from_utf8(buffer) {
+        Ok(string) => NodeContent::String(string.to_string().cow()),
+        Err(_) => NodeContent::Binary(Cow::Borrowed(buffer))
+    }
+}
+
+fn read_string(tag: u8, stream: &mut Cursor<&[u8]>, depth: usize) -> Result<Cow<'static, str>> {
+    match read_node_content(tag, stream, depth)? {
+        NodeContent::List(_) | NodeContent::Binary(_) => bail! {"expected string"},
+        content => Ok(content.into_cow())
+    }
+}
+
+/// ```depth``` counts the enclosing lists and jid pairs, deeper nesting is rejected instead of overflowing the stack
+fn read_node_content<'a>(tag: u8, stream: &mut Cursor<&'a [u8]>, depth: usize) -> Result<NodeContent<'a>> {
+    if depth > MAX_NODE_DEPTH {
+        bail! {"nodes nested deeper than {}", MAX_NODE_DEPTH}
+    }
     Ok(match tag {
         3...161 => NodeContent::Token(TOKENS[(tag - 3) as usize]),
         DICTIONARY_0 | DICTIONARY_1 | DICTIONARY_2 | DICTIONARY_3 => {
             stream.read_u8()?;
             NodeContent::List(Vec::new())
         }
-        LIST_EMPTY | LIST_8 | LIST_16 => NodeContent::List(read_list(tag, stream)?),
+        LIST_EMPTY | LIST_8 | LIST_16 => NodeContent::List(read_list(tag, stream, depth)?),
         BINARY_8 => {
-            let mut buffer = vec![0u8; stream.read_u8()? as usize];
-            stream.read_exact(&mut buffer)?;
-            String::from_utf8(buffer).map(|string| NodeContent::String(string.cow())).unwrap_or_else(|err| NodeContent::Binary(err.into_bytes()))
+            let len = stream.read_u8()? as usize;
+            read_string_or_binary(read_bytes(stream, len)?)
         }
         BINARY_20 => {
             let len: usize = ((stream.read_u8()? as usize & 0x0F) << 16) | (stream.read_u8()? as usize) << 8 | stream.read_u8()? as usize;
 
-            let mut buffer = vec![0u8; len];
-            stream.read_exact(&mut buffer)?;
-            String::from_utf8(buffer).map(|string| NodeContent::String(string.cow())).unwrap_or_else(|err| NodeContent::Binary(err.into_bytes()))
+            read_string_or_binary(read_bytes(stream, len)?)
         }
         BINARY_32 => {
-            let mut buffer = vec![0u8; stream.read_u32::<BigEndian>()? as usize];
-            stream.read_exact(&mut buffer)?;
-            String::from_utf8(buffer).map(|string| NodeContent::String(string.cow())).unwrap_or_else(|err| NodeContent::Binary(err.into_bytes()))
+            let len = stream.read_u32::<BigEndian>()? as usize;
+            read_string_or_binary(read_bytes(stream, len)?)
         }
         JID_PAIR => {
-            NodeContent::Jid(Jid::from_node_pair(read_node_content(stream.read_u8()?, stream)?.into_string(), read_node_content(stream.read_u8()?, stream)?.as_str())?)
+            NodeContent::Jid(Jid::from_node_pair(read_string(stream.read_u8()?, stream, depth + 1)?.into_owned(), &read_string(stream.read_u8()?, stream, depth + 1)?)?)
         }
         NIBBLE_8 | HEX_8 => {
             let startbyte = stream.read_u8()?;
@@ -288,6 +330,53 @@ fn write_node_binary(binary: &[u8], stream: &mut Write) -> Result<()> {
     Ok(())
 }
 
+lazy_static! {
+    static ref TOKEN_TAGS: HashMap<&'static str, u8> = TOKENS.iter().enumerate().map(|(i, token)| (*token, (i + 3) as u8)).collect();
+}
+
+fn token_tag(token: &str) -> Option<u8> {
+    TOKEN_TAGS.get(token).cloned()
+}
+
+fn is_packable(string: &str) -> bool {
+    !string.is_empty() && string.len() <= PACKED_MAX as usize
+}
+
+fn is_nibble_char(cha: char) -> bool {
+    match cha {
+        '0'...'9' | '-' | '.' => true,
+        _ => false
+    }
+}
+
+fn is_hex_char(cha: char) -> bool {
+    match cha {
+        '0'...'9' | 'A'...'F' => true,
+        _ => false
+    }
+}
+
+/// Writes ```string``` as ```NIBBLE_8``` or ```HEX_8```, two characters per byte.
+/// An odd length is marked in the startbyte and the last byte is padded with 15
+fn write_packed(string: &str, tag: u8, stream: &mut Write) -> Result<()> {
+    let len = string.len();
+    stream.write_u8(tag)?;
+    stream.write_u8(((len % 2) << 7 | (len + 1) / 2) as u8)?;
+    let mut last_nibble = None;
+    for cha in string.chars() {
+        let nibble = if tag == HEX_8 { cha.to_digit(16).unwrap() as u8 } else { char_to_nibble(cha) };
+        if let Some(last_nibble) = last_nibble.take() {
+            stream.write_u8(last_nibble << 4 | nibble)?;
+        } else {
+            last_nibble = Some(nibble);
+        }
+    }
+    if let Some(last_nibble) = last_nibble {
+        stream.write_u8((last_nibble << 4) + 15)?;
+    }
+    Ok(())
+}
+
 fn write_node_content(content: NodeContent, stream: &mut Write) -> Result<()> {
     match content {
         NodeContent::None => {
@@ -297,10 +386,14 @@ fn write_node_content(content: NodeContent, stream: &mut Write) -> Result<()> {
         NodeContent::List(list) => { write_list(list, stream)?; }
         NodeContent::String(string) => {
             let string = string.deref();
-            if let Some(token) = TOKENS.iter().position(|r| r == &string) {
-                stream.write_u8((token + 3) as u8)?
+            if let Some(tag) = token_tag(string) {
+                stream.write_u8(tag)?
+            } else if is_packable(string) && string.chars().all(is_nibble_char) {
+                write_packed(string, NIBBLE_8, stream)?;
+            } else if is_packable(string) && string.len() % 2 == 0 && string.chars().all(is_hex_char) {
+                write_packed(string, HEX_8, stream)?;
             } else {
-                write_node_binary(string.deref().as_bytes(), stream)?;
+                write_node_binary(string.as_bytes(), stream)?;
             }
         }
         NodeContent::Binary(binary) => {
@@ -313,32 +406,22 @@ fn write_node_content(content: NodeContent, stream: &mut Write) -> Result<()> {
             write_node_content(NodeContent::Token(pair.1), stream)?;
         }
         NodeContent::Token(ref token) => {
-            stream.write_u8((TOKENS.iter().position(|r| r == token).unwrap() + 3) as u8)?
+            stream.write_u8(token_tag(token).ok_or_else(|| format!("invalid token: {}", token))?)?
         }
         NodeContent::Nibble(string) => {
-            let mut len = (string.len() as u8 + 1) / 2;
-            stream.write_u8(NIBBLE_8)?;
-            stream.write_u8((string.len() as u8 % 2) << 7 | len)?;
-            let mut last_nibble = None;
-            for cha in string.chars() {
-                let nibble = char_to_nibble(cha);
-                if let Some(last_nibble) = last_nibble.take() {
-                    stream.write_u8(last_nibble << 4 | nibble)?;
-                } else {
-                    last_nibble = Some(nibble);
-                }
-            }
-            if let Some(last_nibble) = last_nibble {
-                stream.write_u8((last_nibble << 4) + 15)?;
+            if is_packable(&string) {
+                write_packed(&string, NIBBLE_8, stream)?;
+            } else {
+                write_node_binary(string.as_bytes(), stream)?;
             }
         }
     }
     Ok(())
 }
 
-impl Node {
+impl<'a> Node<'a> {
     #[inline]
-    pub fn new<D: IntoCow>(desc: D, attributes: HashMap<Cow<'static, str>, NodeContent>, content: NodeContent) -> Node {
+    pub fn new<D: IntoCow>(desc: D, attributes: HashMap<Cow<'static, str>, NodeContent<'a>>, content: NodeContent<'a>) -> Node<'a> {
         Node {
             desc: desc.cow(),
             attributes,
@@ -347,7 +430,7 @@ impl Node {
     }
 
     #[inline]
-    pub fn new_empty<D: IntoCow>(desc: D) -> Node {
+    pub fn new_empty<D: IntoCow>(desc: D) -> Node<'a> {
         Node {
             desc: desc.cow(),
             attributes: HashMap::new(),
@@ -359,32 +442,45 @@ impl Node {
         self.desc.deref()
     }
 
-    pub fn take_attribute(&mut self, key: &'static str) -> Result<NodeContent> {
+    pub fn take_attribute(&mut self, key: &'static str) -> Result<NodeContent<'a>> {
         self.attributes.remove(&key.cow()).ok_or_else(|| ErrorKind::NodeAttributeMissing(key).into())
     }
 
-    pub fn get_attribute<'a>(&'a self, key: &'static str) -> Result<&'a NodeContent> {
+    pub fn get_attribute(&self, key: &'static str) -> Result<&NodeContent<'a>> {
         self.attributes.get(&key.cow()).ok_or_else(|| ErrorKind::NodeAttributeMissing(key).into())
     }
 
-    pub fn set_attribute<K: IntoCow>(&mut self, key: K, value: NodeContent) {
+    pub fn set_attribute<K: IntoCow>(&mut self, key: K, value: NodeContent<'a>) {
         self.attributes.insert(key.cow(), value);
     }
 
 
-    pub fn deserialize(data: &[u8]) -> Result<Node> {
-        Node::deserialize_stream(&mut Cursor::new(data)).chain_err(|| "Node has invalid binary format")
+    /// Copies borrowed binary content, so the node outlives the buffer it was deserialized from
+    pub fn into_owned(self) -> Node<'static> {
+        Node {
+            desc: self.desc,
+            attributes: self.attributes.into_iter().map(|(name, content)| (name, content.into_owned())).collect(),
+            content: self.content.into_owned()
+        }
     }
 
-    fn deserialize_stream(stream: &mut Read) -> Result<Node> {
+    /// Binary content of the returned node borrows from ```data```
+    pub fn deserialize(data: &'a [u8]) -> Result<Node<'a>> {
+        Node::deserialize_stream(&mut Cursor::new(data), 0).chain_err(|| "Node has invalid binary format")
+    }
+
+    fn deserialize_stream(stream: &mut Cursor<&'a [u8]>, depth: usize) -> Result<Node<'a>> {
         let list_size = read_list_size(stream.read_u8()?, stream).chain_err(|| "Couldn't read attribute count")?;
-        let desc = read_node_content(stream.read_u8()?, stream).chain_err(|| "Couldn't read description")?.into_cow();
+        if list_size == 0 {
+            bail! {"Node without description"}
+        }
+        let desc = read_string(stream.read_u8()?, stream, depth).chain_err(|| "Couldn't read description")?;
 
         let mut attributes = HashMap::new();
 
         for _ in 0..((list_size - 1) >> 1) {
-            let attribute_name = read_node_content(stream.read_u8()?, stream).chain_err(|| format!("Couldn't read attribute name, node decription: {}", desc))?.into_cow();
-            let attribute_content = read_node_content(stream.read_u8()?, stream).chain_err(|| format!("Couldn't read attribute :{}, node decription: {}", attribute_name, desc))?;
+            let attribute_name = read_string(stream.read_u8()?, stream, depth).chain_err(|| format!("Couldn't read attribute name, node decription: {}", desc))?;
+            let attribute_content = read_node_content(stream.read_u8()?, stream, depth).chain_err(|| format!("Couldn't read attribute :{}, node decription: {}", attribute_name, desc))?;
 
             attributes.insert(attribute_name, attribute_content);
         }
@@ -395,23 +491,18 @@ impl Node {
             let tag = stream.read_u8()?;
             match tag {
                 BINARY_8 => {
-                    let mut buffer = vec![0u8; stream.read_u8()? as usize];
-                    stream.read_exact(&mut buffer)?;
-                    NodeContent::Binary(buffer)
+                    let len = stream.read_u8()? as usize;
+                    NodeContent::Binary(Cow::Borrowed(read_bytes(stream, len)?))
                 }
                 BINARY_20 => {
                     let len: usize = ((stream.read_u8()? as usize & 0x0F) << 16) | (stream.read_u8()? as usize) << 8 | stream.read_u8()? as usize;
-
-                    let mut buffer = vec![0u8; len];
-                    stream.read_exact(&mut buffer)?;
-                    NodeContent::Binary(buffer)
+                    NodeContent::Binary(Cow::Borrowed(read_bytes(stream, len)?))
                 }
                 BINARY_32 => {
-                    let mut buffer = vec![0u8; stream.read_u32::<BigEndian>()? as usize];
-                    stream.read_exact(&mut buffer)?;
-                    NodeContent::Binary(buffer)
+                    let len = stream.read_u32::<BigEndian>()? as usize;
+                    NodeContent::Binary(Cow::Borrowed(read_bytes(stream, len)?))
                 }
-                _ => read_node_content(tag, stream).chain_err(|| format!("Couldn't read node content (list), node decription: {}", desc))?
+                _ => read_node_content(tag, stream, depth).chain_err(|| format!("Couldn't read node content (list), node decription: {}", desc))?
             }
         };
 
@@ -419,9 +510,14 @@ impl Node {
     }
 
     pub fn serialize(self) -> Vec<u8> {
-        let mut cursor = Cursor::new(Vec::new());
-        self.serialize_stream(&mut cursor).unwrap();
-        cursor.into_inner()
+        let mut buffer = Vec::new();
+        self.serialize_into(&mut buffer);
+        buffer
+    }
+
+    /// Appends the serialized node to ```buffer```, so the allocation can be reused
+    pub fn serialize_into(self, buffer: &mut Vec<u8>) {
+        self.serialize_stream(buffer).unwrap();
     }
 
     fn serialize_stream(self, stream: &mut Write) -> Result<()> {
@@ -451,21 +547,21 @@ impl Jid {
     fn from_node_pair(id: String, surfix: &str) -> Result<Jid> {
         Ok(Jid {
             id,
-            is_group: match surfix {
-                "c.us" => false,
-                "g.us" => true,
-                "s.whatsapp.net" => false,
-                "broadcast" => false, //Todo
+            kind: match surfix {
+                "c.us" => JidKind::Individual,
+                "g.us" => JidKind::Group,
+                "s.whatsapp.net" => JidKind::Individual,
+                "broadcast" => JidKind::Broadcast,
                 _ => bail! {"invalid jid surfix {}", surfix}
             },
         })
     }
 
     fn into_node_pair(self) -> (String, &'static str) {
-        (self.id, if self.is_group {
-            "g.us"
-        } else {
-            "c.us"
+        (self.id, match self.kind {
+            JidKind::Individual => "c.us",
+            JidKind::Group => "g.us",
+            JidKind::Broadcast => "broadcast"
         })
     }
 }
@@ -489,8 +585,9 @@ impl IntoCow for String {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use super::Jid;
+    use super::{Jid, JidKind};
     use std::str::FromStr;
+    use proptest::prelude::*;
 
     #[test]
     fn test_ser_de() {
@@ -501,8 +598,89 @@ mod tests {
 
         let node = Node::new("action", HashMap::new(), NodeContent::List(vec![Node::new("chat", attributes, NodeContent::None)]));
 
-        let node_ser_de = Node::deserialize(&node.clone().serialize()).unwrap();
+        let serialized = node.clone().serialize();
+        let node_ser_de = Node::deserialize(&serialized).unwrap();
 
         assert_eq!(node_ser_de, node);
     }
+
+    #[test]
+    fn test_deserialize_deeply_nested() {
+        // Each level is a node with a token as description and a list with one child node as content
+        let data: Vec<u8> = (0..100_000).flat_map(|_| vec![LIST_8, 2, 10, LIST_8, 1]).collect();
+        assert!(Node::deserialize(&data).is_err());
+    }
+
+    #[test]
+    fn test_packed_strings() {
+        for &(string, tag) in &[("491791234567", NIBBLE_8), ("4917912345678", NIBBLE_8), ("3EB0A1B2C3D4", HEX_8), ("3eb0", BINARY_8)] {
+            let mut attributes = HashMap::new();
+            attributes.insert("id".cow(), NodeContent::String(string.cow()));
+
+            let serialized = Node::new("receipt", attributes, NodeContent::None).serialize();
+            assert!(serialized.contains(&tag));
+
+            let node_ser_de = Node::deserialize(&serialized).unwrap();
+            assert_eq!(node_ser_de.attributes["id"].as_str(), string);
+        }
+    }
+
+    // Generators only produce values in the form the reader yields them, e.g. strings which
+    // are tokens come back as ```NodeContent::Token``` and even-length nibbles as strings
+    fn arb_name() -> BoxedStrategy<String> {
+        prop_oneof![
+            prop::sample::select(TOKENS.to_vec()).prop_map(|token| token.to_string()),
+            arb_non_token()
+        ].boxed()
+    }
+
+    fn arb_non_token() -> BoxedStrategy<String> {
+        "[a-zA-Z_:]{1,16}".prop_filter("string is a token", |string| !TOKENS.contains(&string.as_str())).boxed()
+    }
+
+    fn arb_jid() -> BoxedStrategy<Jid> {
+        ("[1-9][0-9]{5,14}", prop_oneof![Just(JidKind::Individual), Just(JidKind::Group), Just(JidKind::Broadcast)]).prop_map(|(id, kind)| Jid {
+            id: if kind == JidKind::Group { format!("{}-1500000000", id) } else { id },
+            kind
+        }).boxed()
+    }
+
+    fn arb_attribute_value() -> BoxedStrategy<NodeContent<'static>> {
+        prop_oneof![
+            prop::sample::select(TOKENS.to_vec()).prop_map(NodeContent::Token),
+            arb_non_token().prop_map(|string| NodeContent::String(string.cow())),
+            "[0-9.\\-]([0-9.\\-]{2}){0,40}".prop_map(|nibble| NodeContent::Nibble(nibble.cow())),
+            arb_jid().prop_map(NodeContent::Jid)
+        ].boxed()
+    }
+
+    fn arb_attributes() -> BoxedStrategy<HashMap<Cow<'static, str>, NodeContent<'static>>> {
+        prop::collection::hash_map(arb_name().prop_map(|name| name.cow()), arb_attribute_value(), 0..5).boxed()
+    }
+
+    fn arb_node() -> BoxedStrategy<Node<'static>> {
+        let leaf = (arb_name(), arb_attributes(), prop_oneof![
+            Just(NodeContent::None),
+            prop::collection::vec(any::<u8>(), 0..300).prop_map(|binary| NodeContent::Binary(binary.into()))
+        ]).prop_map(|(desc, attributes, content)| Node::new(desc, attributes, content));
+
+        leaf.prop_recursive(4, 64, 8, |inner| {
+            (arb_name(), arb_attributes(), prop::collection::vec(inner, 0..8))
+                .prop_map(|(desc, attributes, children)| Node::new(desc, attributes, NodeContent::List(children)))
+        }).boxed()
+    }
+
+    proptest! {
+        #[test]
+        fn test_ser_de_roundtrip(ref node in arb_node()) {
+            let serialized = node.clone().serialize();
+            let node_ser_de = Node::deserialize(&serialized).unwrap();
+            prop_assert_eq!(&node_ser_de, node);
+        }
+
+        #[test]
+        fn test_deserialize_arbitrary_bytes(ref data in prop::collection::vec(any::<u8>(), 0..512)) {
+            let _ = Node::deserialize(data);
+        }
+    }
 }
@@ -5,6 +5,15 @@ use ws::util::{Token, Timeout};
 
 pub const RESPONSE_TIMEOUT: TimeoutWindow = TimeoutWindow { min: 3000, max: 5500 };
 pub const PING_TIMEOUT: TimeoutWindow = TimeoutWindow { min: 12000, max: 16000 };
+pub const PRESENCE_KEEPALIVE_INTERVAL: u64 = 60000;
+/// Interval between two batches of presence subscriptions
+pub const PRESENCE_SUBSCRIBE_INTERVAL: u64 = 1000;
+/// The ```TimeoutManager``` tokens start at 5 and only count upwards
+pub const PRESENCE_KEEPALIVE_TOKEN: Token = Token(1);
+pub const ACK_RESEND_TOKEN: Token = Token(0);
+pub const CHAT_STATE_TOKEN: Token = Token(2);
+pub const MEDIA_CONN_REFRESH_TOKEN: Token = Token(3);
+pub const PRESENCE_SUBSCRIBE_TOKEN: Token = Token(4);
 
 #[derive(Copy, Clone)]
 pub struct TimeoutWindow {
@@ -44,7 +53,7 @@ pub struct TimeoutManager {
 impl TimeoutManager {
     pub fn new(sender: &Sender, window: TimeoutWindow, state: TimeoutState) -> TimeoutManager {
         let absolute_window = AbsoluteTimeoutWindow::new(&window);
-        let token = Token(2);
+        let token = Token(5);
 
         sender.timeout(window.max, token).unwrap();
         TimeoutManager {
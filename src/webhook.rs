@@ -0,0 +1,187 @@
+//! Forwards new messages, acks and presence changes of a connection as json to an http endpoint,
+//! so the connection can be used as a gateway without writing a custom handler
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::fmt::Write;
+
+use reqwest;
+use reqwest::header::{Headers, ContentType};
+use ring::{hmac, digest};
+
+use bridge::Event;
+use connection::{WhatsappWebConnection, WhatsappWebHandler, State, UserData, PersistentSession, DisconnectReason};
+use message::ChatMessage;
+use errors::*;
+
+/// Header containing the hex encoded HMAC-SHA256 signature of the body, prefixed with ```sha256=```
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+pub struct WebhookConfig {
+    pub url: String,
+    /// Key used to sign the body of each request
+    pub secret: Option<Vec<u8>>,
+    /// Attempts per event until it is dropped
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled with each further retry
+    pub retry_delay: Duration,
+}
+
+impl WebhookConfig {
+    pub fn new(url: String) -> WebhookConfig {
+        WebhookConfig {
+            url,
+            secret: None,
+            max_attempts: 5,
+            retry_delay: Duration::from_secs(1)
+        }
+    }
+}
+
+/// Handler posting the events as ```bridge::Event``` to the configured endpoint.
+/// Events are delivered in order by a background thread, so a slow endpoint doesn't block the connection
+pub struct WebhookHandler {
+    events: Mutex<mpsc::Sender<Event>>,
+    session_callback: Option<Box<Fn(PersistentSession) + Send + Sync>>
+}
+
+impl WebhookHandler {
+    pub fn new(config: WebhookConfig) -> Result<WebhookHandler> {
+        let (sender, receiver) = mpsc::channel::<Event>();
+        thread::Builder::new().name("whatsappweb-webhook".to_string()).spawn(move || {
+            let client = reqwest::Client::new();
+            for event in receiver {
+                if let Err(err) = deliver(&client, &config, &event) {
+                    warn!("dropped webhook event: {}", err);
+                }
+            }
+        }).chain_err(|| "could not spawn webhook thread")?;
+
+        Ok(WebhookHandler {
+            events: Mutex::new(sender),
+            session_callback: None
+        })
+    }
+
+    /// The session isn't posted to the endpoint as it contains the keys of the account,
+    /// instead it is handed to ```callback``` to be stored
+    pub fn with_session_callback(mut self, callback: Box<Fn(PersistentSession) + Send + Sync>) -> WebhookHandler {
+        self.session_callback = Some(callback);
+        self
+    }
+
+    fn forward(&self, event: Event) {
+        self.events.lock().unwrap().send(event).ok();
+    }
+}
+
+impl WhatsappWebHandler for WebhookHandler {
+    fn on_state_changed(&self, _: &WhatsappWebConnection<WebhookHandler>, _: State) {}
+
+    fn on_user_data_changed(&self, _: &WhatsappWebConnection<WebhookHandler>, user_data: UserData) {
+        match user_data {
+            UserData::MessageAck(_) | UserData::SentMessageAck(_, _) |
+            UserData::PresenceChange(_, _, _) | UserData::GroupPresenceChange { .. } => self.forward(Event::UserDataChanged(user_data)),
+            _ => {}
+        }
+    }
+
+    fn on_persistent_session_data_changed(&self, persistent_session: PersistentSession) {
+        if let Some(ref callback) = self.session_callback {
+            callback(persistent_session);
+        }
+    }
+
+    fn on_disconnect(&self, _: DisconnectReason) {}
+
+    fn on_message(&self, _: &WhatsappWebConnection<WebhookHandler>, message_new: bool, message: Box<ChatMessage>) {
+        self.forward(Event::Message { new: message_new, message: *message });
+    }
+}
+
+fn deliver(client: &reqwest::Client, config: &WebhookConfig, event: &Event) -> Result<()> {
+    let body = event.to_json()?;
+    let signature = config.secret.as_ref().map(|secret| sign(secret, &body));
+
+    with_retries(config.max_attempts, config.retry_delay, || {
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+        if let Some(ref signature) = signature {
+            headers.set_raw(SIGNATURE_HEADER, signature.clone());
+        }
+
+        client.post(&config.url)
+            .headers(headers)
+            .body(body.clone())
+            .send()
+            .chain_err(|| "could not post webhook")
+            .and_then(|response| if response.status().is_success() {
+                Ok(())
+            } else {
+                Err(format!("webhook endpoint responded with {}", response.status()).into())
+            })
+    })
+}
+
+/// Value of the ```SIGNATURE_HEADER``` for ```body```
+fn sign(secret: &[u8], body: &str) -> String {
+    let signature = hmac::sign(&hmac::SigningKey::new(&digest::SHA256, secret), body.as_bytes());
+    let mut signature_hex = String::from("sha256=");
+    for byte in signature.as_ref() {
+        write!(signature_hex, "{:02x}", byte).unwrap();
+    }
+    signature_hex
+}
+
+/// Run ```attempt``` until it succeeds or failed ```max_attempts``` times,
+/// waiting ```retry_delay``` before the first retry and twice as long before each further one
+fn with_retries<F: FnMut() -> Result<()>>(max_attempts: u32, retry_delay: Duration, mut attempt: F) -> Result<()> {
+    let mut delay = retry_delay;
+    let mut attempts = 1;
+    loop {
+        match attempt() {
+            Ok(()) => return Ok(()),
+            Err(err) => if attempts >= max_attempts {
+                return Err(err);
+            }
+        }
+
+        thread::sleep(delay);
+        delay *= 2;
+        attempts += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test case 2
+        assert_eq!(sign(b"Jefe", "what do ya want for nothing?"),
+                   "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+
+    #[test]
+    fn test_with_retries() {
+        let mut attempts = 0;
+        let started = Instant::now();
+        with_retries(5, Duration::from_millis(5), || {
+            attempts += 1;
+            if attempts < 3 { bail!{"endpoint unavailable"} } else { Ok(()) }
+        }).unwrap();
+        assert_eq!(attempts, 3);
+        // Waited 5ms before the first and 10ms before the second retry
+        assert!(started.elapsed() >= Duration::from_millis(15));
+
+        let mut attempts = 0;
+        assert!(with_retries(3, Duration::from_millis(1), || {
+            attempts += 1;
+            bail!{"endpoint unavailable"}
+        }).is_err());
+        assert_eq!(attempts, 3);
+    }
+}
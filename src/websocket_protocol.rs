@@ -7,7 +7,8 @@ use ws::Message;
 use json;
 use json::JsonValue;
 
-#[derive(Copy, Clone, PartialEq)]
+/// Hint sent along with binary messages telling the server which kind of message it is
+#[derive(Debug, Copy, Clone, PartialEq)]
 #[allow(dead_code)]
 pub enum WebsocketMessageMetric {
     None = 0,
@@ -60,12 +61,21 @@ pub struct WebsocketMessage<'a> {
 pub enum WebsocketMessagePayload<'a> {
     Json(JsonValue),
     BinarySimple(&'a [u8]),
-    BinaryEphemeral(WebsocketMessageMetric, &'a [u8]),
     Empty,
     Pong
 }
 
 
+/// Writes the prefix of an encrypted binary message, so the encrypted message can be appended to the same buffer
+pub fn write_binary_ephemeral_prefix(tag: &str, metric: WebsocketMessageMetric, output: &mut Vec<u8>) {
+    output.extend_from_slice(tag.as_bytes());
+    if metric != WebsocketMessageMetric::None {
+        output.extend_from_slice(&[b',', metric as u8, 0x80]);
+    } else {
+        output.extend_from_slice(b",,");
+    }
+}
+
 impl<'a> WebsocketMessage<'a> {
     #[inline]
     pub fn serialize(&self) -> Message {
@@ -76,13 +86,6 @@ impl<'a> WebsocketMessage<'a> {
             WebsocketMessagePayload::BinarySimple(ref binary) => {
                 Message::Binary([self.tag.deref().as_bytes(), b",", binary].concat())
             }
-            WebsocketMessagePayload::BinaryEphemeral(metric, ref binary) => {
-                if metric != WebsocketMessageMetric::None {
-                    Message::Binary([self.tag.deref().as_bytes(), b",", &[metric as u8], b"\x80", binary].concat())
-                } else {
-                    Message::Binary([self.tag.deref().as_bytes(), b",,", binary].concat())
-                }
-            }
             WebsocketMessagePayload::Empty => {
                 Message::Text([self.tag.borrow(), ","].concat())
             }
@@ -0,0 +1,284 @@
+//! Bounded pool of named threads, workers are spawned on demand up to the configured size
+//! and exit again once they were idle for ```IDLE_WORKER_TIMEOUT``` or the pool is closed and no jobs are queued
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard, Condvar, Arc};
+use std::thread;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Duration;
+
+/// Idle workers exit after this duration, so a pool which isn't closed doesn't keep its threads parked
+const IDLE_WORKER_TIMEOUT: Duration = Duration::from_secs(30);
+
+type Job = Box<FnOnce() + Send>;
+
+pub struct WorkerPool {
+    name: String,
+    idle_timeout: Duration,
+    state: Mutex<WorkerPoolState>,
+    changed: Condvar
+}
+
+struct WorkerPoolState {
+    jobs: VecDeque<Job>,
+    max_workers: usize,
+    workers: usize,
+    idle_workers: usize,
+    /// Queued and running jobs
+    pending: usize,
+    closed: bool
+}
+
+impl WorkerPool {
+    pub fn new(name: &str, max_workers: usize) -> WorkerPool {
+        WorkerPool::with_idle_timeout(name, max_workers, IDLE_WORKER_TIMEOUT)
+    }
+
+    fn with_idle_timeout(name: &str, max_workers: usize, idle_timeout: Duration) -> WorkerPool {
+        WorkerPool {
+            name: name.to_string(),
+            idle_timeout,
+            state: Mutex::new(WorkerPoolState {
+                jobs: VecDeque::new(),
+                max_workers: max_workers.max(1),
+                workers: 0,
+                idle_workers: 0,
+                pending: 0,
+                closed: false
+            }),
+            changed: Condvar::new()
+        }
+    }
+
+    fn lock_state(&self) -> MutexGuard<WorkerPoolState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Change the maximum number of workers, running workers above the limit exit after their current job
+    pub fn set_max_workers(this: &Arc<WorkerPool>, max_workers: usize) {
+        let mut state = this.lock_state();
+        state.max_workers = max_workers.max(1);
+        WorkerPool::spawn_workers(this, &mut state);
+        this.changed.notify_all();
+    }
+
+    /// Queue ```job```, it is run as soon as a worker is available
+    pub fn execute<F: FnOnce() + Send + 'static>(this: &Arc<WorkerPool>, job: F) {
+        let mut state = this.lock_state();
+        state.jobs.push_back(Box::new(job));
+        state.pending += 1;
+        state.closed = false;
+        WorkerPool::spawn_workers(this, &mut state);
+        this.changed.notify_one();
+    }
+
+    fn spawn_workers(this: &Arc<WorkerPool>, state: &mut WorkerPoolState) {
+        while state.workers < state.max_workers && state.jobs.len() > state.idle_workers {
+            let pool = this.clone();
+            match thread::Builder::new().name(this.name.clone()).spawn(move || pool.work()) {
+                Ok(_) => state.workers += 1,
+                Err(err) => {
+                    error!("could not spawn thread {}: {:?}", this.name, err);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn work(&self) {
+        let mut state = self.lock_state();
+        loop {
+            if state.workers > state.max_workers {
+                break;
+            }
+            if let Some(job) = state.jobs.pop_front() {
+                drop(state);
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    error!("job of {} panicked", self.name);
+                }
+                state = self.lock_state();
+                state.pending -= 1;
+                self.changed.notify_all();
+            } else if state.closed {
+                break;
+            } else {
+                state.idle_workers += 1;
+                let (new_state, wait_result) = self.changed.wait_timeout(state, self.idle_timeout)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                state = new_state;
+                state.idle_workers -= 1;
+                if wait_result.timed_out() && state.jobs.is_empty() {
+                    break;
+                }
+            }
+        }
+        state.workers -= 1;
+        self.changed.notify_all();
+    }
+
+    /// Queue ```job``` like ```execute``` and count it in ```pending_jobs``` until it finished
+    pub fn execute_counted<F: FnOnce() + Send + 'static>(this: &Arc<WorkerPool>, pending_jobs: &Arc<PendingJobs>, job: F) {
+        *pending_jobs.lock_count() += 1;
+        let guard = PendingJobGuard(pending_jobs.clone());
+        WorkerPool::execute(this, move || {
+            let _guard = guard;
+            job();
+        });
+    }
+
+    /// Wait until all queued jobs are finished and the workers exited
+    pub fn close_and_join(&self) {
+        let mut state = self.lock_state();
+        state.closed = true;
+        self.changed.notify_all();
+        while state.pending > 0 || state.workers > 0 {
+            state = self.changed.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+}
+
+/// Queued and running jobs of one user of a pool, which may be shared with others
+#[derive(Default)]
+pub struct PendingJobs {
+    count: Mutex<usize>,
+    finished: Condvar
+}
+
+impl PendingJobs {
+    fn lock_count(&self) -> MutexGuard<usize> {
+        self.count.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Wait until all jobs queued with ```execute_counted``` are finished
+    pub fn wait(&self) {
+        let mut count = self.lock_count();
+        while *count > 0 {
+            count = self.finished.wait(count).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+}
+
+/// Uncounts the job once it is dropped, also if the job panicked or was never run
+struct PendingJobGuard(Arc<PendingJobs>);
+
+impl Drop for PendingJobGuard {
+    fn drop(&mut self) {
+        *self.0.lock_count() -= 1;
+        self.0.finished.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_bounded_workers() {
+        let pool = Arc::new(WorkerPool::new("test", 2));
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(Mutex::new(0));
+        for _ in 0..8 {
+            let running = running.clone();
+            let max_running = max_running.clone();
+            WorkerPool::execute(&pool, move || {
+                let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                {
+                    let mut max_running = max_running.lock().unwrap();
+                    *max_running = (*max_running).max(now_running);
+                }
+                thread::sleep(Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+        assert!(pool.lock_state().workers <= 2);
+        pool.close_and_join();
+        assert!(*max_running.lock().unwrap() <= 2);
+        assert_eq!(pool.lock_state().workers, 0);
+    }
+
+    #[test]
+    fn test_job_queued_behind_busy_worker() {
+        let pool = Arc::new(WorkerPool::new("test", 1));
+        let (release_sender, release_receiver) = mpsc::channel::<()>();
+        let (done_sender, done_receiver) = mpsc::channel();
+        WorkerPool::execute(&pool, move || { release_receiver.recv().ok(); });
+        WorkerPool::execute(&pool, move || done_sender.send(()).unwrap());
+
+        assert!(done_receiver.recv_timeout(Duration::from_millis(50)).is_err());
+        assert_eq!(pool.lock_state().pending, 2);
+        release_sender.send(()).unwrap();
+        done_receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        pool.close_and_join();
+    }
+
+    #[test]
+    fn test_panicking_job() {
+        let pool = Arc::new(WorkerPool::new("test", 1));
+        let (done_sender, done_receiver) = mpsc::channel();
+        WorkerPool::execute(&pool, || panic!("job panicked"));
+        WorkerPool::execute(&pool, move || done_sender.send(()).unwrap());
+
+        done_receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        pool.close_and_join();
+        assert_eq!(pool.lock_state().pending, 0);
+    }
+
+    #[test]
+    fn test_pending_jobs() {
+        let pool = Arc::new(WorkerPool::new("test", 2));
+        let (first, second) = (Arc::new(PendingJobs::default()), Arc::new(PendingJobs::default()));
+        let (release_sender, release_receiver) = mpsc::channel::<()>();
+        let finished = Arc::new(AtomicUsize::new(0));
+
+        WorkerPool::execute_counted(&pool, &second, move || { release_receiver.recv().ok(); });
+        let first_finished = finished.clone();
+        WorkerPool::execute_counted(&pool, &first, move || { first_finished.fetch_add(1, Ordering::SeqCst); });
+
+        // Waiting for the jobs of one user doesn't wait for the busy worker of the other one
+        first.wait();
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+        assert_eq!(*second.lock_count(), 1);
+
+        release_sender.send(()).unwrap();
+        second.wait();
+        pool.close_and_join();
+    }
+
+    #[test]
+    fn test_idle_workers_exit() {
+        let pool = Arc::new(WorkerPool::with_idle_timeout("test", 2, Duration::from_millis(20)));
+        let (done_sender, done_receiver) = mpsc::channel();
+        WorkerPool::execute(&pool, move || done_sender.send(()).unwrap());
+        done_receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        for _ in 0..250 {
+            if pool.lock_state().workers == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(pool.lock_state().workers, 0);
+
+        // Workers are spawned again for new jobs
+        let (done_sender, done_receiver) = mpsc::channel();
+        WorkerPool::execute(&pool, move || done_sender.send(()).unwrap());
+        done_receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        pool.close_and_join();
+    }
+
+    #[test]
+    fn test_close_and_join() {
+        let pool = Arc::new(WorkerPool::new("test", 4));
+        pool.close_and_join();
+
+        let finished = Arc::new(AtomicUsize::new(0));
+        for _ in 0..4 {
+            let finished = finished.clone();
+            WorkerPool::execute(&pool, move || { finished.fetch_add(1, Ordering::SeqCst); });
+        }
+        pool.close_and_join();
+        assert_eq!(finished.load(Ordering::SeqCst), 4);
+        assert_eq!(pool.lock_state().workers, 0);
+    }
+}